@@ -18,18 +18,41 @@ use r2d2::Pool;
 
 use dotenv::dotenv;
 
+use webdev_lib::auth::provider::provider_from_env;
+use webdev_lib::auth::provider::AuthProvider;
+
+use webdev_lib::audit::models::AuditLogRequest;
+use webdev_lib::audit::requests::{
+    begin_audit, finish_audit, handle_audit_log,
+};
+
 use webdev_lib::errors::Error;
 use webdev_lib::errors::ErrorKind;
 
 use webdev_lib::users::models::UserRequest;
 use webdev_lib::users::requests::handle_user;
 
+use webdev_lib::shape::Shape;
+
+use webdev_lib::service_accounts::models::ServiceAccountRequest;
+use webdev_lib::service_accounts::requests::handle_service_account;
+use webdev_lib::service_accounts::requests::validate_api_key;
+
+use webdev_lib::sessions::models::SessionRequest;
+use webdev_lib::sessions::requests::handle_session;
+use webdev_lib::sessions::requests::validate_session;
+
 use webdev_lib::permissions::models::{
-    PermissionRequest, UserPermissionRequest,
+    Credential, PermissionRequest, Principal, RoleRequest,
+    UserPermissionRequest, UserRoleRequest,
 };
+use webdev_lib::permissions::requests::reconcile_permissions;
 use webdev_lib::permissions::requests::validate_token;
 use webdev_lib::permissions::requests::{
-    handle_permission, handle_user_permission,
+    allowed_while_impersonating, impersonate, load_principal,
+};
+use webdev_lib::permissions::requests::{
+    handle_permission, handle_role, handle_user_permission, handle_user_role,
 };
 
 use webdev_lib::chemicals::models::{
@@ -70,6 +93,11 @@ pub fn init_database(
         warn!("Could not run migrations: {}", e);
     }
 
+    info!("Checking permissions");
+    if let Err(e) = reconcile_permissions(&pool.get()?) {
+        warn!("Could not check permissions: {}", e);
+    }
+
     Ok(pool)
 }
 
@@ -112,6 +140,17 @@ fn main() {
 
     info!("Connected to database");
 
+    let auth_provider = match provider_from_env() {
+        Ok(p) => p,
+        Err(e) => {
+            error!(
+                "Could not set up authentication: {}",
+                e.to_string_with_source()
+            );
+            return;
+        }
+    };
+
     info!("Starting server on 0.0.0.0:8000");
 
     rouille::start_server("0.0.0.0:8000", move |request| {
@@ -131,7 +170,8 @@ fn main() {
                 .with_additional_header("Access-Control-Allow-Origin", "*")
                 .with_additional_header(
                     "Access-Control-Allow-Headers",
-                    "X-PINGOTHER, Content-Type, id_token",
+                    "X-PINGOTHER, Content-Type, Authorization, id_token, session_token, \
+                     act_as",
                 )
                 .with_additional_header("Access-Control-Max-Age", "86400")
         } else {
@@ -145,22 +185,79 @@ fn main() {
                 }
             };
 
-            let response = handle_request(request, &current_connection);
+            let response = handle_request(
+                request,
+                auth_provider.as_ref(),
+                &current_connection,
+            );
 
-            response.with_additional_header("Access-Control-Allow-Origin", "*")
+            response
+                .with_additional_header("Access-Control-Allow-Origin", "*")
+                .with_additional_header(
+                    "Access-Control-Expose-Headers",
+                    ACTING_AS_HEADER,
+                )
         }
     });
 }
 
+/// Response header holding the id of the user being impersonated
+const ACTING_AS_HEADER: &str = "X-Acting-As";
+
 fn handle_request(
     request: &rouille::Request,
+    auth_provider: &dyn AuthProvider,
     database_connection: &MysqlConnection,
 ) -> rouille::Response {
-    let requested_user = if let Some(id_token) = request.header("id_token") {
+    let requested_user = if let Some(session_token) =
+        request.header("session_token")
+    {
+        match validate_session(session_token, database_connection) {
+            Ok(user) => Some((user, Credential::Session)),
+            Err(e) => {
+                warn!(
+                    "Failed to verify session: {}",
+                    e.to_string_with_source()
+                );
+                return rouille::Response::from(e);
+            }
+        }
+    } else if let Some(authorization) = request.header("Authorization") {
+        let api_key = if authorization.starts_with("Bearer ") {
+            &authorization["Bearer ".len()..]
+        } else {
+            warn!("Authorization header is not a bearer token");
+            return rouille::Response::from(Error::new(
+                ErrorKind::InvalidApiKey,
+            ));
+        };
+
+        match validate_api_key(api_key, database_connection) {
+            Ok(user) => Some((user, Credential::ApiKey)),
+            Err(e) => {
+                warn!(
+                    "Failed to verify API key: {}",
+                    e.to_string_with_source()
+                );
+                return rouille::Response::from(e);
+            }
+        }
+    } else if let Some(id_token) = request.header("id_token") {
         if request.url() == "/permission/first" {
+            let identity = match auth_provider.verify(id_token) {
+                Ok(identity) => identity,
+                Err(e) => {
+                    warn!(
+                        "Failed to verify user: {}",
+                        e.to_string_with_source()
+                    );
+                    return rouille::Response::from(e);
+                }
+            };
+
             return match handle_permission(
-                PermissionRequest::FirstPermission(id_token.to_string()),
-                None,
+                PermissionRequest::FirstPermission(identity),
+                &Principal::anonymous(),
                 database_connection,
             ) {
                 Ok(permission_response) => permission_response.to_rouille(),
@@ -168,8 +265,8 @@ fn handle_request(
             };
         }
 
-        match validate_token(id_token, database_connection) {
-            Ok(user) => Some(user),
+        match validate_token(id_token, auth_provider, database_connection) {
+            Ok(user) => Some((user, Credential::IdToken)),
             Err(e) => {
                 warn!("Failed to verify user: {}", e.to_string_with_source());
                 return rouille::Response::from(e);
@@ -179,15 +276,143 @@ fn handle_request(
         None
     };
 
-    if let Some(user_request) = request.remove_prefix("/users") {
-        match UserRequest::from_rouille(&user_request) {
+    let principal = match requested_user {
+        Some((user_id, credential)) => {
+            match load_principal(user_id, database_connection) {
+                Ok(principal) => Principal {
+                    credential: Some(credential),
+                    ..principal
+                },
+                Err(e) => {
+                    warn!(
+                        "Failed to load permissions: {}",
+                        e.to_string_with_source()
+                    );
+                    return rouille::Response::from(e);
+                }
+            }
+        }
+        None => Principal::anonymous(),
+    };
+
+    let principal = if let Some(act_as) = request.header("act_as") {
+        if !allowed_while_impersonating(request.method(), &request.url()) {
+            warn!(
+                "User {:?} tried to impersonate on {}",
+                principal.user_id,
+                request.url()
+            );
+            return rouille::Response::from(Error::new(
+                ErrorKind::PermissionDenied,
+            ));
+        }
+
+        let target_user_id = match act_as.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => {
+                warn!("act_as header is not a user id");
+                return rouille::Response::from(Error::new(ErrorKind::Url));
+            }
+        };
+
+        match impersonate(&principal, target_user_id, database_connection) {
+            Ok(principal) => principal,
+            Err(e) => {
+                warn!(
+                    "Failed to impersonate user: {}",
+                    e.to_string_with_source()
+                );
+                return rouille::Response::from(e);
+            }
+        }
+    } else {
+        principal
+    };
+
+    let shape = match Shape::from_query(request.raw_query_string()) {
+        Ok(shape) => shape,
+        Err(e) => {
+            warn!("Failed to read expand or fields: {}", e);
+            return rouille::Response::from(e);
+        }
+    };
+
+    let pending_audit = begin_audit(request, &principal, database_connection);
+
+    let response = route_request(request, &principal, database_connection);
+
+    let response = match pending_audit {
+        Some(pending_audit) => {
+            finish_audit(pending_audit, response, database_connection)
+        }
+        None => response,
+    };
+
+    let response = match shape {
+        Some(shape) => shape.apply(response, &principal, database_connection),
+        None => response,
+    };
+
+    match (principal.impersonator_id, principal.user_id) {
+        (Some(_), Some(user_id)) => response
+            .with_additional_header(ACTING_AS_HEADER, user_id.to_string()),
+        _ => response,
+    }
+}
+
+fn route_request(
+    request: &rouille::Request,
+    principal: &Principal,
+    database_connection: &MysqlConnection,
+) -> rouille::Response {
+    if let Some(audit_log_request) = request.remove_prefix("/audit_log") {
+        match AuditLogRequest::from_rouille(&audit_log_request) {
             Err(err) => rouille::Response::from(err),
-            Ok(user_request) => {
-                match handle_user(
-                    user_request,
-                    requested_user,
+            Ok(audit_log_request) => match handle_audit_log(
+                audit_log_request,
+                principal,
+                database_connection,
+            ) {
+                Ok(audit_log_response) => audit_log_response.to_rouille(),
+                Err(err) => rouille::Response::from(err),
+            },
+        }
+    } else if let Some(session_request) = request.remove_prefix("/session") {
+        match SessionRequest::from_rouille(&session_request) {
+            Err(err) => rouille::Response::from(err),
+            Ok(session_request) => {
+                match handle_session(
+                    session_request,
+                    principal,
                     database_connection,
                 ) {
+                    Ok(session_response) => session_response.to_rouille(),
+                    Err(err) => rouille::Response::from(err),
+                }
+            }
+        }
+    } else if let Some(service_account_request) =
+        request.remove_prefix("/service_accounts")
+    {
+        match ServiceAccountRequest::from_rouille(&service_account_request) {
+            Err(err) => rouille::Response::from(err),
+            Ok(service_account_request) => match handle_service_account(
+                service_account_request,
+                principal,
+                database_connection,
+            ) {
+                Ok(service_account_response) => {
+                    service_account_response.to_rouille()
+                }
+                Err(err) => rouille::Response::from(err),
+            },
+        }
+    } else if let Some(user_request) = request.remove_prefix("/users") {
+        match UserRequest::from_rouille(&user_request) {
+            Err(err) => rouille::Response::from(err),
+            Ok(user_request) => {
+                match handle_user(user_request, principal, database_connection)
+                {
                     Ok(user_response) => user_response.to_rouille(),
                     Err(err) => rouille::Response::from(err),
                 }
@@ -201,7 +426,7 @@ fn handle_request(
             Ok(permission_request) => {
                 match handle_permission(
                     permission_request,
-                    requested_user,
+                    principal,
                     database_connection,
                 ) {
                     Ok(permission_response) => permission_response.to_rouille(),
@@ -216,7 +441,7 @@ fn handle_request(
             Err(err) => rouille::Response::from(err),
             Ok(user_permission_request) => match handle_user_permission(
                 user_permission_request,
-                requested_user,
+                principal,
                 database_connection,
             ) {
                 Ok(user_permission_response) => {
@@ -225,6 +450,33 @@ fn handle_request(
                 Err(err) => rouille::Response::from(err),
             },
         }
+    } else if let Some(role_request_url) = request.remove_prefix("/role") {
+        match RoleRequest::from_rouille(&role_request_url) {
+            Err(err) => rouille::Response::from(err),
+            Ok(role_request) => {
+                match handle_role(role_request, principal, database_connection)
+                {
+                    Ok(role_response) => role_response.to_rouille(),
+                    Err(err) => rouille::Response::from(err),
+                }
+            }
+        }
+    } else if let Some(user_role_request_url) =
+        request.remove_prefix("/user_role")
+    {
+        match UserRoleRequest::from_rouille(&user_role_request_url) {
+            Err(err) => rouille::Response::from(err),
+            Ok(user_role_request) => {
+                match handle_user_role(
+                    user_role_request,
+                    principal,
+                    database_connection,
+                ) {
+                    Ok(user_role_response) => user_role_response.to_rouille(),
+                    Err(err) => rouille::Response::from(err),
+                }
+            }
+        }
     } else if let Some(chem_inventory_request_url) =
         request.remove_prefix("/chemical_inventory")
     {
@@ -234,7 +486,7 @@ fn handle_request(
             Err(err) => rouille::Response::from(err),
             Ok(chem_inventory_request) => match handle_chemical_inventory(
                 chem_inventory_request,
-                requested_user,
+                principal,
                 database_connection,
             ) {
                 Ok(chem_inventory_response) => {
@@ -251,7 +503,7 @@ fn handle_request(
             Ok(chemical_request) => {
                 match handle_chemical(
                     chemical_request,
-                    requested_user,
+                    principal,
                     database_connection,
                 ) {
                     Ok(chemical_response) => chemical_response.to_rouille(),
@@ -267,7 +519,7 @@ fn handle_request(
             Ok(question_request) => {
                 match handle_question(
                     question_request,
-                    requested_user,
+                    principal,
                     database_connection,
                 ) {
                     Ok(question_response) => question_response.to_rouille(),
@@ -285,7 +537,7 @@ fn handle_request(
             Ok(question_category_request) => {
                 match handle_question_category(
                     question_category_request,
-                    requested_user,
+                    principal,
                     database_connection,
                 ) {
                     Ok(question_category_response) => {
@@ -299,11 +551,8 @@ fn handle_request(
         match TestRequest::from_rouille(&test_request_url) {
             Err(err) => rouille::Response::from(err),
             Ok(test_request) => {
-                match handle_test(
-                    test_request,
-                    requested_user,
-                    database_connection,
-                ) {
+                match handle_test(test_request, principal, database_connection)
+                {
                     Ok(test_response) => test_response.to_rouille(),
                     Err(err) => rouille::Response::from(err),
                 }
@@ -317,7 +566,7 @@ fn handle_request(
             Ok(test_session_request) => {
                 match handle_test_session(
                     test_session_request,
-                    requested_user,
+                    principal,
                     database_connection,
                 ) {
                     Ok(test_session_response) => {
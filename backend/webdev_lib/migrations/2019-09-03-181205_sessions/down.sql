@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE permission_name = "RevokeSessions";
+DROP TABLE sessions;
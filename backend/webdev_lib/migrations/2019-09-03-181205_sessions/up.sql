@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+  id SERIAL PRIMARY KEY,
+  user_id BIGINT UNSIGNED NOT NULL,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  created TIMESTAMP NOT NULL,
+  expires TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id)
+    REFERENCES users(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE
+);
+
+INSERT INTO permissions (permission_name) VALUES
+  ("RevokeSessions");
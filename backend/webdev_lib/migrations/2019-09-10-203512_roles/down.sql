@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE user_roles;
+DROP TABLE role_permissions;
+DROP TABLE roles;
+
+DELETE FROM permissions WHERE permission_name IN (
+  "GetRoles",
+  "CreateRoles",
+  "UpdateRoles",
+  "DeleteRoles",
+  "GetUserRoles",
+  "CreateUserRoles",
+  "DeleteUserRoles"
+);
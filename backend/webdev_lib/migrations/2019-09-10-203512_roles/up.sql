@@ -0,0 +1,105 @@
+-- Your SQL goes here
+CREATE TABLE roles (
+  id SERIAL PRIMARY KEY,
+  role_name VARCHAR(255) NOT NULL UNIQUE
+);
+
+CREATE TABLE role_permissions (
+  role_id BIGINT UNSIGNED NOT NULL,
+  permission_id BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (role_id, permission_id),
+  FOREIGN KEY (role_id)
+    REFERENCES roles(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE,
+  FOREIGN KEY (permission_id)
+    REFERENCES permissions(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE
+);
+
+CREATE TABLE user_roles (
+  user_id BIGINT UNSIGNED NOT NULL,
+  role_id BIGINT UNSIGNED NOT NULL,
+  PRIMARY KEY (user_id, role_id),
+  FOREIGN KEY (user_id)
+    REFERENCES users(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE,
+  FOREIGN KEY (role_id)
+    REFERENCES roles(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE
+);
+
+INSERT INTO permissions (permission_name) VALUES
+  ("GetRoles"),
+  ("CreateRoles"),
+  ("UpdateRoles"),
+  ("DeleteRoles"),
+
+  ("GetUserRoles"),
+  ("CreateUserRoles"),
+  ("DeleteUserRoles");
+
+INSERT INTO roles (role_name) VALUES
+  ("Student"),
+  ("Lab Manager"),
+  ("Test Admin");
+
+INSERT INTO role_permissions (role_id, permission_id)
+  SELECT roles.id, permissions.id FROM roles, permissions
+  WHERE roles.role_name = "Student"
+  AND permissions.permission_name IN (
+    "GetTests",
+    "GetTestSessions"
+  );
+
+INSERT INTO role_permissions (role_id, permission_id)
+  SELECT roles.id, permissions.id FROM roles, permissions
+  WHERE roles.role_name = "Lab Manager"
+  AND permissions.permission_name IN (
+    "GetUsers",
+
+    "GetChemical",
+    "CreateChemical",
+    "UpdateChemical",
+    "DeleteChemical",
+
+    "GetChemicalInventory",
+    "CreateChemicalInventory",
+    "UpdateChemicalInventory",
+    "DeleteChemicalInventory"
+  );
+
+INSERT INTO role_permissions (role_id, permission_id)
+  SELECT roles.id, permissions.id FROM roles, permissions
+  WHERE roles.role_name = "Test Admin"
+  AND permissions.permission_name IN (
+    "GetUsers",
+
+    "GetQuestions",
+    "CreateQuestions",
+    "UpdateQuestions",
+    "DeleteQuestions",
+
+    "GetQuestionCategories",
+    "CreateQuestionCategories",
+    "UpdateQuestionCategories",
+    "DeleteQuestionCategories",
+
+    "GetTests",
+    "CreateTests",
+    "UpdateTests",
+    "DeleteTests",
+
+    "GetTestSessions",
+    "CreateTestSessions",
+    "UpdateTestSessions",
+    "DeleteTestSessions",
+
+    "GetTestSessionRegistrations",
+    "CreateTestSessionRegistrations",
+    "UpdateTestSessionRegistrations",
+    "DeleteTestSessionRegistrations"
+  );
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE permission_name IN (
+  "UpdateOwnChemicalInventory",
+  "DeleteOwnChemicalInventory",
+  "DeleteOwnTests",
+  "DeleteOwnTestSessionRegistrations"
+);
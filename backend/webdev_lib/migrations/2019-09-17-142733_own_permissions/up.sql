@@ -0,0 +1,8 @@
+-- Your SQL goes here
+INSERT INTO permissions (permission_name) VALUES
+  ("UpdateOwnChemicalInventory"),
+  ("DeleteOwnChemicalInventory"),
+
+  ("DeleteOwnTests"),
+
+  ("DeleteOwnTestSessionRegistrations");
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE service_accounts (
+  id SERIAL PRIMARY KEY,
+  user_id BIGINT UNSIGNED NOT NULL,
+  name VARCHAR(255) NOT NULL UNIQUE,
+  key_hash VARCHAR(64) NOT NULL UNIQUE,
+  created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id)
+    REFERENCES users(id)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE
+);
+
+INSERT INTO permissions (permission_name) VALUES
+  ("GetServiceAccounts"),
+  ("CreateServiceAccounts"),
+  ("DeleteServiceAccounts");
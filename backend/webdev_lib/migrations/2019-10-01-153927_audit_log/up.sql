@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE audit_log (
+  id SERIAL PRIMARY KEY,
+  actor_id BIGINT UNSIGNED,
+  entity_type VARCHAR(64) NOT NULL,
+  entity_id BIGINT UNSIGNED,
+  action VARCHAR(16) NOT NULL,
+  request_path VARCHAR(255) NOT NULL,
+  before_state TEXT,
+  after_state TEXT,
+  created TIMESTAMP NOT NULL,
+  FOREIGN KEY (actor_id)
+    REFERENCES users(id)
+    ON DELETE SET NULL
+    ON UPDATE CASCADE,
+  INDEX (entity_type, entity_id)
+);
+
+INSERT INTO permissions (permission_name) VALUES
+  ("GetAuditLog");
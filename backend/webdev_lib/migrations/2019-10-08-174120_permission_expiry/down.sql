@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE user_permissions
+  DROP COLUMN granted_at,
+  DROP COLUMN expires_at;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE user_permissions
+  ADD COLUMN granted_at TIMESTAMP NULL DEFAULT NULL,
+  ADD COLUMN expires_at TIMESTAMP NULL DEFAULT NULL;
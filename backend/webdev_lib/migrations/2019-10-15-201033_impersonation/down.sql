@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE permission_name = "ImpersonateUsers";
+ALTER TABLE audit_log
+  DROP FOREIGN KEY audit_log_ibfk_2,
+  DROP COLUMN impersonator_id;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE audit_log
+  ADD COLUMN impersonator_id BIGINT UNSIGNED,
+  ADD FOREIGN KEY (impersonator_id)
+    REFERENCES users(id)
+    ON DELETE SET NULL
+    ON UPDATE CASCADE;
+
+INSERT INTO permissions (permission_name) VALUES
+  ("ImpersonateUsers");
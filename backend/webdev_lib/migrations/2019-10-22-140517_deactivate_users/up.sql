@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE users
+  ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE,
+  ADD COLUMN deactivated_at TIMESTAMP NULL DEFAULT NULL;
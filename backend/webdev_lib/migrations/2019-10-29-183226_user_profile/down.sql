@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+  DROP COLUMN department,
+  DROP COLUMN class_year,
+  DROP COLUMN affiliation,
+  DROP COLUMN phone,
+  DROP COLUMN emergency_contact;
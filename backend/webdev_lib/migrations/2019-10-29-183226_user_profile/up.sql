@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE users
+  ADD COLUMN department VARCHAR(255) NULL DEFAULT NULL,
+  ADD COLUMN class_year VARCHAR(16) NULL DEFAULT NULL,
+  ADD COLUMN affiliation VARCHAR(16) NULL DEFAULT NULL,
+  ADD COLUMN phone VARCHAR(32) NULL DEFAULT NULL,
+  ADD COLUMN emergency_contact VARCHAR(255) NULL DEFAULT NULL;
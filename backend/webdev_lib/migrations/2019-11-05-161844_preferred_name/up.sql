@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users
+  ADD COLUMN preferred_name VARCHAR(255) NULL DEFAULT NULL;
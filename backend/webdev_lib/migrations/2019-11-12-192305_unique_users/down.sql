@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM permissions WHERE permission_name = "MergeUsers";
+
+ALTER TABLE users
+  DROP INDEX email,
+  DROP INDEX banner_id;
+
+UPDATE users SET banner_id = 0 WHERE banner_id IS NULL;
+
+ALTER TABLE users MODIFY banner_id INT(9) UNSIGNED NOT NULL;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+ALTER TABLE users MODIFY banner_id INT(9) UNSIGNED NULL;
+
+-- Users without a banner id used to be given 0
+UPDATE users SET banner_id = NULL WHERE banner_id = 0;
+
+-- Duplicates that already exist are kept, but set aside so they can be merged by hand. The newer
+-- copy gets a marked email and loses its banner id, which the older copy still has.
+UPDATE users AS duplicate
+  INNER JOIN users AS original
+    ON duplicate.email = original.email AND duplicate.id > original.id
+  SET duplicate.email = CONCAT(duplicate.email, ".duplicate-", duplicate.id);
+
+UPDATE users AS duplicate
+  INNER JOIN users AS original
+    ON duplicate.banner_id = original.banner_id AND duplicate.id > original.id
+  SET duplicate.banner_id = NULL;
+
+ALTER TABLE users
+  ADD UNIQUE (email),
+  ADD UNIQUE (banner_id);
+
+INSERT INTO permissions (permission_name) VALUES
+  ("MergeUsers");
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE questions DROP INDEX question_text;
+
+ALTER TABLE chemical DROP INDEX chemical_text;
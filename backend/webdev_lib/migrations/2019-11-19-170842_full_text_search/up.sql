@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE chemical
+  ADD FULLTEXT chemical_text (name, purpose, ingredients, company_name);
+
+ALTER TABLE questions
+  ADD FULLTEXT question_text (
+    title, correct_answer, incorrect_answer_1, incorrect_answer_2, incorrect_answer_3
+  );
@@ -0,0 +1,141 @@
+use diesel::Queryable;
+
+use rouille::router;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use chrono::NaiveDateTime;
+
+use log::warn;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::search::{NullableSearch, Page, Search};
+
+use webdev_search_derive::Searchable;
+
+use super::schema::audit_log;
+
+#[derive(Queryable, Debug)]
+pub struct RawAuditEntry {
+    pub id: u64,
+    pub actor_id: Option<u64>,
+    pub entity_type: String,
+    pub entity_id: Option<u64>,
+    pub action: String,
+    pub request_path: String,
+    pub before_state: Option<String>,
+    pub after_state: Option<String>,
+    pub created: NaiveDateTime,
+    pub impersonator_id: Option<u64>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "audit_log"]
+pub struct NewRawAuditEntry {
+    pub actor_id: Option<u64>,
+    pub entity_type: String,
+    pub entity_id: Option<u64>,
+    pub action: String,
+    pub request_path: String,
+    pub before_state: Option<String>,
+    pub after_state: Option<String>,
+    pub created: NaiveDateTime,
+    pub impersonator_id: Option<u64>,
+}
+
+/// A single change to the database, or a request made while impersonating a user
+///
+/// `before` and `after` only hold the fields that changed. Creates have no `before`,
+/// and deletes have no `after`. When `impersonator_id` is set, that user made the
+/// request while acting as `actor_id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub actor_id: Option<u64>,
+    pub entity_type: String,
+    pub entity_id: Option<u64>,
+    pub action: String,
+    pub request_path: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub created: NaiveDateTime,
+    pub impersonator_id: Option<u64>,
+}
+
+#[derive(Debug, Searchable)]
+#[search(table = "audit_log")]
+pub struct SearchAuditLog {
+    pub actor_id: NullableSearch<u64>,
+    pub entity_type: Search<String>,
+    pub entity_id: NullableSearch<u64>,
+    pub action: Search<String>,
+    pub impersonator_id: NullableSearch<u64>,
+    pub created: Search<NaiveDateTime>,
+}
+
+/// Fields that the audit log can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AuditLogSortField {
+    Id,
+    ActorId,
+    EntityType,
+    Created,
+}
+
+impl std::str::FromStr for AuditLogSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuditLogSortField, String> {
+        match s {
+            "id" => Ok(AuditLogSortField::Id),
+            "actor_id" => Ok(AuditLogSortField::ActorId),
+            "entity_type" => Ok(AuditLogSortField::EntityType),
+            "created" => Ok(AuditLogSortField::Created),
+            _ => Err(format!("Cannot sort the audit log by {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditLogList {
+    pub entries: Vec<AuditEntry>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+pub enum AuditLogRequest {
+    SearchAuditLog(SearchAuditLog, Page<AuditLogSortField>),
+}
+
+impl AuditLogRequest {
+    pub fn from_rouille(request: &rouille::Request) -> Result<AuditLogRequest, Error> {
+        router!(request,
+            (GET) (/) => {
+                let search = SearchAuditLog::from_query(request.raw_query_string())?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(AuditLogRequest::SearchAuditLog(search, page))
+            },
+
+            _ => {
+                warn!("Could not create an audit log request for the given rouille request");
+                Err(Error::new(ErrorKind::NotFound))
+            }
+        ) //end router
+    }
+}
+
+pub enum AuditLogResponse {
+    ManyEntries(AuditLogList),
+}
+
+impl AuditLogResponse {
+    pub fn to_rouille(self) -> rouille::Response {
+        match self {
+            AuditLogResponse::ManyEntries(entries) => rouille::Response::json(&entries),
+        }
+    }
+}
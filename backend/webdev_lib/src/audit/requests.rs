@@ -0,0 +1,411 @@
+use std::io::Read;
+
+use diesel;
+use diesel::mysql::Mysql;
+use diesel::mysql::MysqlConnection;
+use diesel::query_builder::AsQuery;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::RunQueryDsl;
+
+use chrono::offset::Local;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use log::warn;
+
+use crate::errors::Error;
+
+use crate::permissions::models::{Permission, Principal};
+use crate::permissions::requests::check_to_run;
+use crate::permissions::requests::{get_permission, get_role, get_user_permission, get_user_roles};
+
+use crate::chemicals::requests::{get_chemical, get_chemical_inventory};
+use crate::tests::question_categories::requests::get_question_category;
+use crate::tests::questions::requests::get_question;
+use crate::tests::test_sessions::requests::{get_registration, get_test_session};
+use crate::tests::tests::requests::get_test;
+use crate::users::requests::get_user;
+
+use crate::search::{Page, Sort, SortOrder};
+
+use super::models::{
+    AuditEntry, AuditLogList, AuditLogRequest, AuditLogResponse, AuditLogSortField,
+    NewRawAuditEntry, RawAuditEntry, SearchAuditLog,
+};
+
+use super::schema::audit_log as audit_log_schema;
+
+pub fn handle_audit_log(
+    request: AuditLogRequest,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<AuditLogResponse, Error> {
+    match request {
+        AuditLogRequest::SearchAuditLog(audit_log, page) => {
+            check_to_run(requesting_user, Permission::GetAuditLog)?;
+            search_audit_log(audit_log, page, database_connection)
+                .map(|a| AuditLogResponse::ManyEntries(a))
+        }
+    }
+}
+
+pub(crate) fn search_audit_log(
+    audit_log_search: SearchAuditLog,
+    page: Page<AuditLogSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<AuditLogList, Error> {
+    let total = filter_audit_log(&audit_log_search)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    // Newest first, unless asked otherwise
+    let sort = page.sort.unwrap_or(Sort {
+        field: AuditLogSortField::Id,
+        order: SortOrder::Descending,
+    });
+
+    let audit_log_query = filter_audit_log(&audit_log_search);
+
+    let audit_log_query = crate::sort_query!(audit_log_query, sort, {
+        AuditLogSortField::Id => audit_log_schema::id,
+        AuditLogSortField::ActorId => audit_log_schema::actor_id,
+        AuditLogSortField::EntityType => audit_log_schema::entity_type,
+        AuditLogSortField::Created => audit_log_schema::created,
+    });
+
+    let found_entries = audit_log_query
+        .then_order_by(audit_log_schema::id.desc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<RawAuditEntry>(database_connection)?;
+
+    let entries = found_entries
+        .into_iter()
+        .map(|entry| AuditEntry {
+            id: entry.id,
+            actor_id: entry.actor_id,
+            entity_type: entry.entity_type,
+            entity_id: entry.entity_id,
+            action: entry.action,
+            request_path: entry.request_path,
+            before: entry
+                .before_state
+                .and_then(|s| serde_json::from_str(&s).ok()),
+            after: entry
+                .after_state
+                .and_then(|s| serde_json::from_str(&s).ok()),
+            created: entry.created,
+            impersonator_id: entry.impersonator_id,
+        })
+        .collect();
+
+    Ok(AuditLogList {
+        entries: entries,
+        total: total,
+        next: page.next(total),
+    })
+}
+
+/// Builds a query for the audit log entries that match the search
+fn filter_audit_log<'a>(
+    audit_log_search: &'a SearchAuditLog,
+) -> audit_log_schema::BoxedQuery<'a, Mysql> {
+    audit_log_search.filter(audit_log_schema::table.as_query().into_boxed())
+}
+
+/// A request that still needs its response before it can be logged
+#[derive(Debug)]
+pub struct PendingAuditEntry {
+    actor_id: Option<u64>,
+    impersonator_id: Option<u64>,
+    entity_type: String,
+    entity_id: Option<u64>,
+    action: &'static str,
+    request_path: String,
+    before: Option<Value>,
+}
+
+/// Starts an audit log entry for a POST, PUT or DELETE request
+///
+/// The entity is taken from the url, like `/chemicals/4`, and its current state is
+/// saved so that it can be compared once the request has been handled. Returns
+/// `None` for requests that cannot change anything, unless the request is made
+/// while impersonating another user, in which case it is logged as a `view`, and
+/// for requests whose handlers log what they change themselves.
+pub fn begin_audit(
+    request: &rouille::Request,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Option<PendingAuditEntry> {
+    let request_path = request.url();
+
+    if audited_by_handler(&request_path) {
+        return None;
+    }
+
+    let (entity_type, entity_id) = {
+        let mut segments = request_path.trim_start_matches('/').split('/');
+
+        let entity_type = segments.next().unwrap_or("").to_owned();
+
+        // `/users/current` is whoever is making the request
+        let entity_id = match segments.next() {
+            Some("current") if entity_type == "users" => requesting_user.user_id,
+            segment => segment.and_then(|s| s.parse::<u64>().ok()),
+        };
+
+        (entity_type, entity_id)
+    };
+
+    let action = match (request.method(), entity_id) {
+        ("DELETE", _) => "delete",
+        ("POST", None) => "create",
+        ("POST", Some(_)) | ("PUT", _) => "update",
+        _ if requesting_user.impersonator_id.is_some() => "view",
+        _ => return None,
+    };
+
+    let before = match entity_id {
+        Some(id) if action != "view" => snapshot(&entity_type, id, database_connection),
+        _ => None,
+    };
+
+    Some(PendingAuditEntry {
+        actor_id: requesting_user.user_id,
+        impersonator_id: requesting_user.impersonator_id,
+        entity_type: entity_type,
+        entity_id: entity_id,
+        action: action,
+        request_path: request_path,
+        before: before,
+    })
+}
+
+/// Writes the audit log entry for a request once it has succeeded
+///
+/// Requests made while impersonating are logged even if they fail. Failing to
+/// write the entry is logged, but does not fail the request.
+pub fn finish_audit(
+    pending: PendingAuditEntry,
+    mut response: rouille::Response,
+    database_connection: &MysqlConnection,
+) -> rouille::Response {
+    if !response.is_success() && pending.impersonator_id.is_none() {
+        return response;
+    }
+
+    let mut entity_id = pending.entity_id;
+
+    // Creates only know the id of what they created from the response
+    if entity_id.is_none() && pending.action == "create" && response.is_success() {
+        let data = std::mem::replace(&mut response.data, rouille::ResponseBody::empty());
+        let (mut reader, _) = data.into_reader_and_size();
+
+        let mut body = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut body) {
+            warn!("Could not read response for the audit log: {}", e);
+        }
+
+        // User roles are logged as the roles of their user
+        let id_field = if pending.entity_type == "user_role" {
+            "user_id"
+        } else {
+            "id"
+        };
+
+        entity_id = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.get(id_field).and_then(|id| id.as_u64()));
+
+        response.data = rouille::ResponseBody::from_data(body);
+    }
+
+    write_entry(pending, entity_id, database_connection);
+
+    response
+}
+
+/// Starts an audit log entry for one of the entities a request changes
+///
+/// Some requests change entities that their url does not name, like merging two
+/// users or importing many. Their handlers log every entity they change with this
+/// and `finish_change`, instead of the single entry `begin_audit` would make.
+pub(crate) fn begin_change(
+    requesting_user: &Principal,
+    request_path: String,
+    entity_type: &str,
+    entity_id: Option<u64>,
+    action: &'static str,
+    database_connection: &MysqlConnection,
+) -> PendingAuditEntry {
+    let before = entity_id.and_then(|id| snapshot(entity_type, id, database_connection));
+
+    PendingAuditEntry {
+        actor_id: requesting_user.user_id,
+        impersonator_id: requesting_user.impersonator_id,
+        entity_type: entity_type.to_owned(),
+        entity_id: entity_id,
+        action: action,
+        request_path: request_path,
+        before: before,
+    }
+}
+
+/// Writes the audit log entry for a change once it has been made
+///
+/// Like `finish_audit`, failed changes are only logged while impersonating. The id
+/// is given again, since a created entity only has one after it has been created.
+pub(crate) fn finish_change(
+    pending: PendingAuditEntry,
+    succeeded: bool,
+    entity_id: Option<u64>,
+    database_connection: &MysqlConnection,
+) {
+    if succeeded || pending.impersonator_id.is_some() {
+        let entity_id = entity_id.or(pending.entity_id);
+
+        write_entry(pending, entity_id, database_connection);
+    }
+}
+
+/// Compares the entity with its state before the change and writes the entry
+///
+/// Deleted entities have no state after, unless they are only deactivated.
+fn write_entry(
+    pending: PendingAuditEntry,
+    entity_id: Option<u64>,
+    database_connection: &MysqlConnection,
+) {
+    let after = match entity_id {
+        Some(id) if pending.action != "view" => {
+            snapshot(&pending.entity_type, id, database_connection)
+        }
+        _ => None,
+    };
+
+    let (before, after) = diff(pending.before, after);
+
+    let new_raw_audit_entry = NewRawAuditEntry {
+        actor_id: pending.actor_id,
+        entity_type: pending.entity_type,
+        entity_id: entity_id,
+        action: pending.action.to_owned(),
+        request_path: pending.request_path,
+        before_state: before.map(|b| b.to_string()),
+        after_state: after.map(|a| a.to_string()),
+        created: Local::now().naive_local(),
+        impersonator_id: pending.impersonator_id,
+    };
+
+    if let Err(e) = diesel::insert_into(audit_log_schema::table)
+        .values(&new_raw_audit_entry)
+        .execute(database_connection)
+    {
+        warn!("Could not write audit log entry: {}", e);
+    }
+}
+
+/// Whether the handler of the request logs the entities it changes itself
+///
+/// These are the requests that call `begin_change`, because their url does not name
+/// every entity they change, or names the wrong one.
+fn audited_by_handler(request_path: &str) -> bool {
+    let segments: Vec<&str> = request_path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["users", "import"] | ["users", _, "merge", _] => true,
+        ["user_permission", "bulk"] => true,
+        ["test_sessions", _, "register"]
+        | ["test_sessions", _, "unregister"]
+        | ["test_sessions", _, "unregister", _]
+        | ["test_sessions", _, "open"]
+        | ["test_sessions", _, "submit"] => true,
+        _ => false,
+    }
+}
+
+/// The current state of an entity, if it is one that can be looked up by id
+///
+/// Sessions and service accounts are never included, since they hold secrets. User
+/// roles are looked up by the id of their user, and hold all of that user's roles.
+fn snapshot(
+    entity_type: &str,
+    entity_id: u64,
+    database_connection: &MysqlConnection,
+) -> Option<Value> {
+    fn to_json<T: Serialize>(entity: Result<T, Error>) -> Option<Value> {
+        entity.ok().and_then(|e| serde_json::to_value(e).ok())
+    }
+
+    match entity_type {
+        "users" => to_json(get_user(entity_id, database_connection)),
+        "permission" => to_json(get_permission(entity_id, database_connection)),
+        "user_permission" => to_json(get_user_permission(entity_id, database_connection)),
+        "role" => to_json(get_role(entity_id, database_connection)),
+        "user_role" => to_json(get_user_roles(entity_id, database_connection)),
+        "chemicals" => to_json(get_chemical(entity_id, database_connection)),
+        "chemical_inventory" => to_json(get_chemical_inventory(entity_id, database_connection)),
+        "questions" => to_json(get_question(entity_id, database_connection)),
+        "question_categories" => to_json(get_question_category(entity_id, database_connection)),
+        "tests" => to_json(get_test(entity_id, database_connection)),
+        "test_sessions" => to_json(get_test_session(entity_id, database_connection)),
+        "test_session_registrations" => to_json(get_registration(entity_id, database_connection)),
+        _ => None,
+    }
+}
+
+/// Removes the fields that did not change between two states of an entity
+fn diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    match (before, after) {
+        (Some(Value::Object(mut before)), Some(Value::Object(mut after))) => {
+            let unchanged: Vec<String> = before
+                .iter()
+                .filter(|(field, value)| after.get(field.as_str()) == Some(*value))
+                .map(|(field, _)| field.clone())
+                .collect();
+
+            for field in unchanged {
+                before.remove(&field);
+                after.remove(&field);
+            }
+
+            (Some(Value::Object(before)), Some(Value::Object(after)))
+        }
+        (before, after) => (before, after),
+    }
+}
+
+#[test]
+fn diff_keeps_only_changed_fields() {
+    let before = serde_json::json!({"id": 1, "name": "Acetone", "amount": "1L"});
+    let after = serde_json::json!({"id": 1, "name": "Acetone", "amount": "500mL"});
+
+    assert_eq!(
+        diff(Some(before), Some(after)),
+        (
+            Some(serde_json::json!({"amount": "1L"})),
+            Some(serde_json::json!({"amount": "500mL"}))
+        )
+    );
+}
+
+#[test]
+fn diff_keeps_whole_state_for_create_and_delete() {
+    let state = serde_json::json!({"id": 1, "name": "Acetone"});
+
+    assert_eq!(diff(None, Some(state.clone())), (None, Some(state.clone())));
+    assert_eq!(diff(Some(state.clone()), None), (Some(state), None));
+}
+
+#[test]
+fn handlers_log_changes_their_url_does_not_name() {
+    assert!(audited_by_handler("/users/import"));
+    assert!(audited_by_handler("/users/4/merge/9"));
+    assert!(audited_by_handler("/user_permission/bulk"));
+    assert!(audited_by_handler("/test_sessions/2/unregister/7"));
+    assert!(!audited_by_handler("/users/4"));
+    assert!(!audited_by_handler("/user_permission/"));
+    assert!(!audited_by_handler("/test_sessions/2"));
+}
@@ -0,0 +1,19 @@
+use crate::users::schema::users;
+
+table! {
+    audit_log (id) {
+        id -> Unsigned<Bigint>,
+        actor_id -> Nullable<Unsigned<Bigint>>,
+        entity_type -> Varchar,
+        entity_id -> Nullable<Unsigned<Bigint>>,
+        action -> Varchar,
+        request_path -> Varchar,
+        before_state -> Nullable<Text>,
+        after_state -> Nullable<Text>,
+        created -> Timestamp,
+        impersonator_id -> Nullable<Unsigned<Bigint>>,
+    }
+}
+
+joinable!(audit_log -> users (actor_id));
+allow_tables_to_appear_in_same_query!(audit_log, users);
@@ -0,0 +1,3 @@
+pub mod google;
+pub mod local_jwt;
+pub mod provider;
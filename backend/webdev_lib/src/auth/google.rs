@@ -0,0 +1,50 @@
+use std::env;
+
+use google_signin;
+
+use log::trace;
+
+use crate::errors::{Error, ErrorKind};
+
+use super::provider::{AuthIdentity, AuthProvider};
+
+static DEFAULT_GOOGLE_CLIENT_ID: &str =
+    "918184954544-jm1aufr31fi6sdjs1140p7p3rouaka14.apps.googleusercontent.com";
+
+/// Verifies Google Sign-In id tokens
+pub struct GoogleAuthProvider {
+    pub audiences: Vec<String>,
+}
+
+impl GoogleAuthProvider {
+    /// Uses `GOOGLE_CLIENT_ID` as the audience, falling back to the client id
+    /// of the production frontend
+    pub fn from_env() -> GoogleAuthProvider {
+        let client_id =
+            env::var("GOOGLE_CLIENT_ID").unwrap_or(DEFAULT_GOOGLE_CLIENT_ID.to_owned());
+
+        GoogleAuthProvider {
+            audiences: vec![client_id],
+        }
+    }
+}
+
+impl AuthProvider for GoogleAuthProvider {
+    fn verify(&self, id_token: &str) -> Result<AuthIdentity, Error> {
+        let mut client = google_signin::Client::new();
+        client.audiences = self.audiences.clone();
+
+        let id_info = client.verify(id_token)?;
+
+        trace!("Validated token: {:?}", id_info);
+
+        match id_info.email {
+            Some(email) => Ok(AuthIdentity {
+                email: email,
+                first_name: id_info.given_name,
+                last_name: id_info.family_name,
+            }),
+            None => Err(Error::new(ErrorKind::GoogleUserNoEmail)),
+        }
+    }
+}
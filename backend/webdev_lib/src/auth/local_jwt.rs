@@ -0,0 +1,135 @@
+use std::env;
+
+use chrono::Duration;
+use chrono::Utc;
+
+use jsonwebtoken::{Algorithm, Header, Validation};
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use log::trace;
+
+use crate::errors::{Error, ErrorKind};
+
+use super::provider::{AuthIdentity, AuthProvider};
+
+static DEFAULT_ISSUER: &str = "webdev_lib";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LocalJwtClaims {
+    iss: String,
+    exp: i64,
+    email: String,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+/// Verifies HS256 tokens signed with a secret shared with the server
+///
+/// This does not contact anything outside of the server, so it is meant for
+/// staging and integration environments that have to run offline.
+pub struct LocalJwtAuthProvider {
+    secret: Vec<u8>,
+    issuer: String,
+}
+
+impl LocalJwtAuthProvider {
+    pub fn new(secret: &[u8], issuer: &str) -> LocalJwtAuthProvider {
+        LocalJwtAuthProvider {
+            secret: secret.to_vec(),
+            issuer: issuer.to_owned(),
+        }
+    }
+
+    /// Reads the secret from `LOCAL_JWT_SECRET` and the issuer from `LOCAL_JWT_ISSUER`
+    pub fn from_env() -> Result<LocalJwtAuthProvider, Error> {
+        let secret = match env::var("LOCAL_JWT_SECRET") {
+            Ok(secret) => secret,
+            Err(e) => return Err(Error::with_source(ErrorKind::Config, Box::new(e))),
+        };
+
+        let issuer = env::var("LOCAL_JWT_ISSUER").unwrap_or(DEFAULT_ISSUER.to_owned());
+
+        Ok(LocalJwtAuthProvider::new(secret.as_bytes(), &issuer))
+    }
+
+    /// Signs a token for the identity that will be valid for the given amount of time
+    pub fn issue(&self, identity: &AuthIdentity, valid_for: Duration) -> Result<String, Error> {
+        let claims = LocalJwtClaims {
+            iss: self.issuer.clone(),
+            exp: (Utc::now() + valid_for).timestamp(),
+            email: identity.email.clone(),
+            given_name: identity.first_name.clone(),
+            family_name: identity.last_name.clone(),
+        };
+
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.secret)?;
+
+        Ok(token)
+    }
+}
+
+impl AuthProvider for LocalJwtAuthProvider {
+    fn verify(&self, id_token: &str) -> Result<AuthIdentity, Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.iss = Some(self.issuer.clone());
+
+        let token_data =
+            jsonwebtoken::decode::<LocalJwtClaims>(id_token, &self.secret, &validation)?;
+
+        trace!("Validated token: {:?}", token_data.claims);
+
+        Ok(AuthIdentity {
+            email: token_data.claims.email,
+            first_name: token_data.claims.given_name,
+            last_name: token_data.claims.family_name,
+        })
+    }
+}
+
+#[cfg(test)]
+fn test_identity() -> AuthIdentity {
+    AuthIdentity {
+        email: "smithj1@students.rowan.edu".to_owned(),
+        first_name: Some("John".to_owned()),
+        last_name: None,
+    }
+}
+
+#[test]
+fn local_jwt_issued_token_verifies() {
+    let provider = LocalJwtAuthProvider::new(b"secret", "test");
+    let token = provider.issue(&test_identity(), Duration::minutes(5)).unwrap();
+
+    let identity = provider.verify(&token).unwrap();
+    assert_eq!(identity.email, "smithj1@students.rowan.edu");
+    assert_eq!(identity.first_name, Some("John".to_owned()));
+    assert_eq!(identity.last_name, None);
+}
+
+#[test]
+fn local_jwt_wrong_secret_fails() {
+    let issuer = LocalJwtAuthProvider::new(b"secret", "test");
+    let verifier = LocalJwtAuthProvider::new(b"other secret", "test");
+    let token = issuer.issue(&test_identity(), Duration::minutes(5)).unwrap();
+
+    assert!(verifier.verify(&token).is_err());
+}
+
+#[test]
+fn local_jwt_wrong_issuer_fails() {
+    let issuer = LocalJwtAuthProvider::new(b"secret", "test");
+    let verifier = LocalJwtAuthProvider::new(b"secret", "production");
+    let token = issuer.issue(&test_identity(), Duration::minutes(5)).unwrap();
+
+    assert!(verifier.verify(&token).is_err());
+}
+
+#[test]
+fn local_jwt_expired_token_fails() {
+    let provider = LocalJwtAuthProvider::new(b"secret", "test");
+    let token = provider.issue(&test_identity(), Duration::minutes(-5)).unwrap();
+
+    assert!(provider.verify(&token).is_err());
+}
@@ -0,0 +1,55 @@
+use std::env;
+
+use diesel::prelude::*;
+use diesel::MysqlConnection;
+use dotenv::dotenv;
+use log::debug;
+use log::error;
+use log::info;
+use webdev_lib::permissions::requests::bootstrap_admin;
+
+const USAGE: &str = "Usage: create_admin <email> [first name] [last name]";
+
+fn main() {
+    dotenv().ok();
+
+    simplelog::TermLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default())
+        .unwrap();
+
+    //Get the admin's email, and names in case they need to be created, from args
+    let mut args = env::args().skip(1);
+    let email = match args.next() {
+        Some(email) => email,
+        None => {
+            error!("{}", USAGE);
+            return;
+        }
+    };
+    let first_name = args.next();
+    let last_name = args.next();
+
+    info!("Connecting to database");
+
+    let database_url = match env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_e) => {
+            error!("Could not read DATABASE_URL environment variable");
+            return;
+        }
+    };
+
+    debug!("Connecting to {}", database_url);
+
+    let connection = match MysqlConnection::establish(&database_url) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not connect to database: {}", e);
+            return;
+        }
+    };
+
+    match bootstrap_admin(&email, first_name, last_name, &connection) {
+        Ok(user_id) => info!("{} (user {}) is now an administrator", email, user_id),
+        Err(e) => error!("Could not create administrator: {}", e.to_string_with_source()),
+    }
+}
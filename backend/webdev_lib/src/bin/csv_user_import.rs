@@ -1,45 +1,72 @@
-use csv;
+use std::env;
+use std::fs::File;
+use std::process;
+
 use diesel::prelude::*;
 use diesel::MysqlConnection;
 use dotenv::dotenv;
 use log::debug;
 use log::error;
 use log::info;
-use serde::Deserialize;
-use serde::Serialize;
-use webdev_lib::users::models::{NewUser, UserRequest};
-use webdev_lib::users::requests;
-
-#[derive(Serialize, Deserialize, Debug)]
-//Struct to take the data from the csv
-struct CsvUser {
-    #[serde(rename = "Banner ID")]
-    banner_id: i32,
-    #[serde(rename = "Last Name")]
-    last_name: String,
-    #[serde(rename = "First Name")]
-    first_name: String,
-    #[serde(rename = "Email")]
-    email: String,
-    #[serde(rename = "Year")]
-    year: String,
-    #[serde(rename = "Department")]
-    department: String,
-}
+use log::warn;
+use webdev_lib::permissions::models::{Permission, Principal};
+use webdev_lib::users::import::import_users;
+use webdev_lib::users::models::ImportAction;
+
+const USAGE: &str = "Usage: csv_user_import [--dry-run] <file>";
+
 fn main() {
-    //Diesel things
     dotenv().ok();
 
-    simplelog::TermLogger::init(simplelog::LevelFilter::Trace, simplelog::Config::default())
+    simplelog::TermLogger::init(simplelog::LevelFilter::Info, simplelog::Config::default())
         .unwrap();
 
+    //Get the file, and whether to only show what would change, from args
+    let mut dry_run = false;
+    let mut filename = None;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-n" | "--dry-run" => dry_run = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            flag if flag.starts_with('-') => {
+                error!("Unknown option {}\n{}", flag, USAGE);
+                process::exit(2);
+            }
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => {
+                error!("Only one file can be imported at a time\n{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let filename = match filename {
+        Some(filename) => filename,
+        None => {
+            error!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let file = match File::open(&filename) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Could not open {}: {}", filename, e);
+            process::exit(1);
+        }
+    };
+
     info!("Connecting to database");
 
     let database_url = match env::var("DATABASE_URL") {
         Ok(url) => url,
         Err(_e) => {
             error!("Could not read DATABASE_URL environment variable");
-            return;
+            process::exit(1);
         }
     };
 
@@ -49,54 +76,85 @@ fn main() {
         Ok(c) => c,
         Err(e) => {
             error!("Could not connect to database: {}", e);
-            return;
+            process::exit(1);
         }
     };
 
     debug!("Connected to database");
-    //Get file name and path from args
-    use std::env;
-    let arg = env::args().next();
-    let filename = match arg {
-        Some(name) => name,
-        None => {
-            error!("Needs a filename");
-            return;
-        }
+
+    //The importer has direct database access, so it can do anything without logging in
+    let importer = Principal {
+        user_id: None,
+        permissions: Permission::ALL.iter().cloned().collect(),
+        impersonator_id: None,
+        credential: None,
     };
-    debug!("{}", filename);
-    //Import the csv into an iterator
-    let mut user_count = 0;
-    let all_users_result = csv::Reader::from_path(filename);
-    let mut all_users = match all_users_result {
-        Ok(data) => data,
+
+    let report = match import_users(file, dry_run, &importer, &connection) {
+        Ok(report) => report,
         Err(e) => {
-            error!("Bad file. Error {}", e);
-            return;
+            error!(
+                "Could not import {}: {}",
+                filename,
+                e.to_string_with_source()
+            );
+            process::exit(1);
         }
     };
-    //Go through each item in the iterator
-    for result in all_users.deserialize() {
-        //Check to see if it's valid
-        let csv_user: CsvUser = match result {
-            Ok(data) => data,
-            Err(e) => {
-                error!("Bad data, {:?}", e);
-                return;
+
+    for row in &report.rows {
+        let who = row
+            .email
+            .as_ref()
+            .map(|e| e.as_str())
+            .unwrap_or("unknown user");
+
+        match row.action {
+            ImportAction::Failed => warn!(
+                "Row {} ({}): {}",
+                row.row,
+                who,
+                row.error.as_ref().map(|e| e.as_str()).unwrap_or("")
+            ),
+            ImportAction::Unchanged => debug!("Row {} ({}): unchanged", row.row, who),
+            ImportAction::Created => info!("Row {} ({}): created", row.row, who),
+            ImportAction::Updated => {
+                for change in &row.changes {
+                    info!(
+                        "Row {} ({}): {} {} -> {}",
+                        row.row,
+                        who,
+                        change.field,
+                        change
+                            .before
+                            .as_ref()
+                            .map(|b| b.as_str())
+                            .unwrap_or("(none)"),
+                        change
+                            .after
+                            .as_ref()
+                            .map(|a| a.as_str())
+                            .unwrap_or("(none)")
+                    );
+                }
             }
-        };
-        //Convert the user data from the csv and create a New User from it
-        let new_user: NewUser = NewUser {
-            first_name: csv_user.first_name,
-            last_name: csv_user.last_name,
-            email: csv_user.email,
-            banner_id: csv_user.banner_id as u32,
-            permissions: Vec::new(),
-        };
-        //Import new user into database
-        let import_user = UserRequest::CreateUser(new_user);
-        requests::handle_user(import_user, Some(0), &connection).unwrap();
-        user_count = user_count + 1;
+        }
+    }
+
+    info!(
+        "{} {} created, {} updated, {} unchanged and {} failed",
+        if dry_run {
+            "Dry run, nothing was saved:"
+        } else {
+            "Imported users:"
+        },
+        report.created,
+        report.updated,
+        report.unchanged,
+        report.failed
+    );
+
+    if report.failed > 0 {
+        process::exit(1);
     }
-    info!("Imported {} user(s)", user_count);
 }
@@ -5,13 +5,13 @@ use rouille::router;
 use serde::Deserialize;
 use serde::Serialize;
 
-use url::form_urlencoded;
-
 use log::warn;
 
 use crate::errors::{Error, ErrorKind};
 
-use crate::search::Search;
+use crate::search::{parse_queries, Page, Search};
+
+use webdev_search_derive::Searchable;
 
 use super::schema::{chemical, chemical_inventory};
 
@@ -45,6 +45,8 @@ pub struct PartialChemical {
     pub manual_link: Option<String>,
 }
 
+#[derive(Searchable)]
+#[search(table = "chemical")]
 pub struct SearchChemical {
     pub name: Search<String>,
     pub purpose: Search<String>,
@@ -53,13 +55,39 @@ pub struct SearchChemical {
     pub manual_link: Search<String>,
 }
 
+/// Fields that a list of chemicals can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChemicalSortField {
+    Id,
+    Name,
+    CompanyName,
+}
+
+impl std::str::FromStr for ChemicalSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChemicalSortField, String> {
+        match s {
+            "id" => Ok(ChemicalSortField::Id),
+            "name" => Ok(ChemicalSortField::Name),
+            "company_name" => Ok(ChemicalSortField::CompanyName),
+            _ => Err(format!("Cannot sort chemicals by {}", s)),
+        }
+    }
+}
+
+/// A page of chemicals
+///
+/// `total` counts every chemical that matched, and `next` links to the next page.
 #[derive(Serialize, Deserialize)]
 pub struct ChemicalList {
     pub chemicals: Vec<Chemical>,
+    pub total: u64,
+    pub next: Option<String>,
 }
 
 pub enum ChemicalRequest {
-    Search(SearchChemical),
+    Search(SearchChemical, Option<String>, Page<ChemicalSortField>),
     GetChemical(u64),
     CreateChemical(NewChemical),
     UpdateChemical(u64, PartialChemical),
@@ -68,34 +96,22 @@ pub enum ChemicalRequest {
 
 impl ChemicalRequest {
     pub fn from_rouille(request: &rouille::Request) -> Result<ChemicalRequest, Error> {
-        let url_queries = form_urlencoded::parse(request.raw_query_string().as_bytes());
-
         router!(request,
             (GET) (/) => {
-                let mut name_search = Search::NoSearch;
-                let mut purpose_search = Search::NoSearch;
-                let mut company_name_search = Search::NoSearch;
-                let mut ingredients_search = Search::NoSearch;
-                let mut manual_link_search = Search::NoSearch;
-
-                for (field, query) in url_queries {
-                    match field.as_ref() as &str {
-                        "name" => name_search = Search::from_query(query.as_ref())?,
-                        "purpose" => purpose_search = Search::from_query(query.as_ref())?,
-                        "company_name" => company_name_search = Search::from_query(query.as_ref())?,
-                        "ingredients" => ingredients_search = Search::from_query(query.as_ref())?,
-                        "manual_link" => manual_link_search = Search::from_query(query.as_ref())?,
-                        _ => return Err(Error::new(ErrorKind::Url)),
+                let mut search = SearchChemical::default();
+                let mut text = None;
+
+                parse_queries(request.raw_query_string(), |field, query| match field {
+                    "q" => {
+                        text = Some(query.to_owned());
+                        Ok(())
                     }
-                }
-
-                Ok(ChemicalRequest::Search(SearchChemical {
-                    name: name_search,
-                    purpose: purpose_search,
-                    company_name: company_name_search,
-                    ingredients: ingredients_search,
-                    manual_link: manual_link_search,
-                }))
+                    field => search.parse_query(field, query),
+                })?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(ChemicalRequest::Search(search, text, page))
             },
 
             (GET) (/{id: u64}) => {
@@ -174,6 +190,8 @@ pub struct PartialChemicalInventory {
     pub amount: Option<String>,
 }
 
+#[derive(Searchable)]
+#[search(table = "chemical_inventory")]
 pub struct SearchChemicalInventory {
     pub purchaser_id: Search<u64>,
     pub custodian_id: Search<u64>,
@@ -182,13 +200,40 @@ pub struct SearchChemicalInventory {
     pub amount: Search<String>,
 }
 
+/// Fields that a list of inventory entries can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChemicalInventorySortField {
+    Id,
+    ChemicalId,
+    CustodianId,
+    PurchaserId,
+    StorageLocation,
+}
+
+impl std::str::FromStr for ChemicalInventorySortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChemicalInventorySortField, String> {
+        match s {
+            "id" => Ok(ChemicalInventorySortField::Id),
+            "chemical_id" => Ok(ChemicalInventorySortField::ChemicalId),
+            "custodian_id" => Ok(ChemicalInventorySortField::CustodianId),
+            "purchaser_id" => Ok(ChemicalInventorySortField::PurchaserId),
+            "storage_location" => Ok(ChemicalInventorySortField::StorageLocation),
+            _ => Err(format!("Cannot sort chemical inventory by {}", s)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ChemicalInventoryList {
     pub entries: Vec<ChemicalInventory>,
+    pub total: u64,
+    pub next: Option<String>,
 }
 
 pub enum ChemicalInventoryRequest {
-    SearchInventory(SearchChemicalInventory),
+    SearchInventory(SearchChemicalInventory, Page<ChemicalInventorySortField>),
     GetInventory(u64),
     CreateInventory(NewChemicalInventory),
     UpdateInventory(u64, PartialChemicalInventory),
@@ -197,38 +242,13 @@ pub enum ChemicalInventoryRequest {
 
 impl ChemicalInventoryRequest {
     pub fn from_rouille(request: &rouille::Request) -> Result<ChemicalInventoryRequest, Error> {
-        let url_queries = form_urlencoded::parse(request.raw_query_string().as_bytes());
-
         router!(request,
             (GET) (/) => {
-                let mut purchaser_id_search = Search::NoSearch;
-                let mut custodian_id_search = Search::NoSearch;
-                let mut chemical_id_search = Search::NoSearch;
-                let mut storage_location_search = Search::NoSearch;
-                let mut amount_search = Search::NoSearch;
-
-                for (field, query) in url_queries {
-                    match field.as_ref() as &str {
-                        "purchaser_id" => purchaser_id_search =
-                            Search::from_query(query.as_ref())?,
-                        "custodian_id" => custodian_id_search =
-                            Search::from_query(query.as_ref())?,
-                        "chemical_id" => chemical_id_search =
-                            Search::from_query(query.as_ref())?,
-                        "storage_location" => storage_location_search
-                            = Search::from_query(query.as_ref())?,
-                        "amount" => amount_search = Search::from_query(query.as_ref())?,
-                        _ => return Err(Error::new(ErrorKind::Url)),
-                    }
-                }
-
-                Ok(ChemicalInventoryRequest::SearchInventory(SearchChemicalInventory {
-                    purchaser_id: purchaser_id_search,
-                    custodian_id: custodian_id_search,
-                    chemical_id: chemical_id_search,
-                    storage_location: storage_location_search,
-                    amount: amount_search,
-                }))
+                let search = SearchChemicalInventory::from_query(request.raw_query_string())?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(ChemicalInventoryRequest::SearchInventory(search, page))
             },
 
             (GET) (/{permission_id: u64}) => {
@@ -1,24 +1,25 @@
 use diesel;
 use diesel::mysql::types::Unsigned;
+use diesel::mysql::Mysql;
 use diesel::mysql::MysqlConnection;
 use diesel::query_builder::AsQuery;
 use diesel::sql_types;
 use diesel::ExpressionMethods;
 use diesel::QueryDsl;
 use diesel::RunQueryDsl;
-use diesel::TextExpressionMethods;
 
 use crate::errors::{Error, ErrorKind};
 
-use crate::search::Search;
+use crate::search::{match_against, MatchAgainst, Page, Sort, SortOrder};
 
-use crate::permissions::requests::check_to_run;
+use crate::permissions::models::{OwnedResource, Permission, Principal};
+use crate::permissions::requests::{check_to_run, check_to_run_scoped};
 
 use super::models::{
     Chemical, ChemicalInventory, ChemicalInventoryList, ChemicalInventoryRequest,
-    ChemicalInventoryResponse, ChemicalList, ChemicalRequest, ChemicalResponse, NewChemical,
-    NewChemicalInventory, PartialChemical, PartialChemicalInventory, SearchChemical,
-    SearchChemicalInventory,
+    ChemicalInventoryResponse, ChemicalInventorySortField, ChemicalList, ChemicalRequest,
+    ChemicalResponse, ChemicalSortField, NewChemical, NewChemicalInventory, PartialChemical,
+    PartialChemicalInventory, SearchChemical, SearchChemicalInventory,
 };
 
 use super::schema::chemical as chemical_schema;
@@ -26,19 +27,19 @@ use super::schema::chemical_inventory as chemical_inventory_schema;
 
 pub fn handle_chemical(
     request: ChemicalRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<ChemicalResponse, Error> {
     match request {
-        ChemicalRequest::Search(chemical) => {
-            match check_to_run(requested_user, "GetChemical", database_connection) {
-                Ok(()) => search_chemical(chemical, database_connection)
+        ChemicalRequest::Search(chemical, text, page) => {
+            match check_to_run(requested_user, Permission::GetChemical) {
+                Ok(()) => search_chemical(chemical, text, page, database_connection)
                     .map(|c| ChemicalResponse::ManyChemical(c)),
                 Err(e) => Err(e),
             }
         }
         ChemicalRequest::GetChemical(id) => {
-            match check_to_run(requested_user, "GetChemical", database_connection) {
+            match check_to_run(requested_user, Permission::GetChemical) {
                 Ok(()) => {
                     get_chemical(id, database_connection).map(|c| ChemicalResponse::OneChemical(c))
                 }
@@ -46,21 +47,21 @@ pub fn handle_chemical(
             }
         }
         ChemicalRequest::CreateChemical(chemical) => {
-            match check_to_run(requested_user, "CreateChemical", database_connection) {
+            match check_to_run(requested_user, Permission::CreateChemical) {
                 Ok(()) => create_chemical(chemical, database_connection)
                     .map(|c| ChemicalResponse::OneChemical(c)),
                 Err(e) => Err(e),
             }
         }
         ChemicalRequest::UpdateChemical(id, chemical) => {
-            match check_to_run(requested_user, "UpdateChemical", database_connection) {
+            match check_to_run(requested_user, Permission::UpdateChemical) {
                 Ok(()) => update_chemical(id, chemical, database_connection)
                     .map(|_| ChemicalResponse::NoResponse),
                 Err(e) => Err(e),
             }
         }
         ChemicalRequest::DeleteChemical(id) => {
-            match check_to_run(requested_user, "DeleteChemical", database_connection) {
+            match check_to_run(requested_user, Permission::DeleteChemical) {
                 Ok(()) => {
                     delete_chemical(id, database_connection).map(|_| ChemicalResponse::NoResponse)
                 }
@@ -70,80 +71,92 @@ pub fn handle_chemical(
     }
 }
 
+/// Searches for chemicals, with an optional free text search on their name, purpose,
+/// ingredients and company
+///
+/// Free text searches are ranked by relevance, unless they are sorted some other way.
 pub(crate) fn search_chemical(
     chemical_search: SearchChemical,
+    text: Option<String>,
+    page: Page<ChemicalSortField>,
     database_connection: &MysqlConnection,
 ) -> Result<ChemicalList, Error> {
-    let mut chemical_query = chemical_schema::table.as_query().into_boxed();
+    let total = filter_chemical(&chemical_search, &text)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
 
-    match chemical_search.name {
-        Search::Partial(s) => {
-            chemical_query = chemical_query.filter(chemical_schema::name.like(format!("%%{}%", s)))
-        }
-
-        Search::Exact(s) => chemical_query = chemical_query.filter(chemical_schema::name.eq(s)),
-
-        Search::NoSearch => {}
-    }
-
-    match chemical_search.purpose {
-        Search::Partial(s) => {
-            chemical_query =
-                chemical_query.filter(chemical_schema::purpose.like(format!("%%{}%", s)))
-        }
-
-        Search::Exact(s) => chemical_query = chemical_query.filter(chemical_schema::purpose.eq(s)),
-
-        Search::NoSearch => {}
-    }
+    let rank_by_relevance = page.sort.is_none();
 
-    match chemical_search.company_name {
-        Search::Partial(s) => {
-            chemical_query =
-                chemical_query.filter(chemical_schema::company_name.like(format!("%%{}%", s)))
-        }
+    let sort = page.sort.unwrap_or(Sort {
+        field: ChemicalSortField::Id,
+        order: SortOrder::Ascending,
+    });
 
-        Search::Exact(s) => {
-            chemical_query = chemical_query.filter(chemical_schema::company_name.eq(s))
-        }
+    let chemical_query = filter_chemical(&chemical_search, &text);
 
-        Search::NoSearch => {}
-    }
-
-    match chemical_search.ingredients {
-        Search::Partial(s) => {
-            chemical_query =
-                chemical_query.filter(chemical_schema::ingredients.like(format!("%%{}%", s)))
-        }
-
-        Search::Exact(s) => {
-            chemical_query = chemical_query.filter(chemical_schema::ingredients.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
+    let chemical_query = crate::sort_query!(chemical_query, sort, {
+        ChemicalSortField::Id => chemical_schema::id,
+        ChemicalSortField::Name => chemical_schema::name,
+        ChemicalSortField::CompanyName => chemical_schema::company_name,
+    });
 
-    match chemical_search.manual_link {
-        Search::Partial(s) => {
-            chemical_query =
-                chemical_query.filter(chemical_schema::manual_link.like(format!("%{}%", s)))
-        }
-
-        Search::Exact(s) => {
-            chemical_query = chemical_query.filter(chemical_schema::manual_link.eq(s))
-        }
+    let chemical_query = match &text {
+        Some(text) if rank_by_relevance => chemical_query.order(chemical_relevance(text).desc()),
+        _ => chemical_query,
+    };
 
-        Search::NoSearch => {}
-    }
+    let found_chemicals = chemical_query
+        .then_order_by(chemical_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<Chemical>(database_connection)?;
 
-    let found_chemicals = chemical_query.load::<Chemical>(database_connection)?;
     let chemical_list = ChemicalList {
         chemicals: found_chemicals,
+        total: total,
+        next: page.next(total),
     };
 
     Ok(chemical_list)
 }
 
+/// Builds a query for the chemicals that match the search
+///
+/// It is used both to count the matching chemicals and to load a page of them.
+fn filter_chemical<'a>(
+    chemical_search: &'a SearchChemical,
+    text: &Option<String>,
+) -> chemical_schema::BoxedQuery<'a, Mysql> {
+    let chemical_query = chemical_search.filter(chemical_schema::table.as_query().into_boxed());
+
+    match text {
+        Some(text) => chemical_query.filter(chemical_relevance(text).gt(0.0)),
+        None => chemical_query,
+    }
+}
+
+/// How relevant a chemical is to a free text search
+///
+/// The columns are the ones in the `chemical_text` index.
+fn chemical_relevance(
+    text: &str,
+) -> MatchAgainst<(
+    chemical_schema::name,
+    chemical_schema::purpose,
+    chemical_schema::ingredients,
+    chemical_schema::company_name,
+)> {
+    match_against(
+        (
+            chemical_schema::name,
+            chemical_schema::purpose,
+            chemical_schema::ingredients,
+            chemical_schema::company_name,
+        ),
+        text,
+    )
+}
+
 pub(crate) fn get_chemical(
     id: u64,
     database_connection: &MysqlConnection,
@@ -158,6 +171,19 @@ pub(crate) fn get_chemical(
     }
 }
 
+/// Gets every chemical with one of the ids, in order of their ids
+pub(crate) fn get_chemicals(
+    ids: Vec<u64>,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<Chemical>, Error> {
+    let chemicals = chemical_schema::table
+        .filter(chemical_schema::id.eq_any(ids))
+        .order(chemical_schema::id.asc())
+        .load::<Chemical>(database_connection)?;
+
+    Ok(chemicals)
+}
+
 pub(crate) fn create_chemical(
     chemical: NewChemical,
     database_connection: &MysqlConnection,
@@ -200,39 +226,37 @@ pub(crate) fn delete_chemical(id: u64, database_connection: &MysqlConnection) ->
 
 pub fn handle_chemical_inventory(
     request: ChemicalInventoryRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<ChemicalInventoryResponse, Error> {
     match request {
-        ChemicalInventoryRequest::SearchInventory(inventory) => {
-            match check_to_run(requested_user, "GetChemicalInventory", database_connection) {
-                Ok(()) => search_chemical_inventory(inventory, database_connection)
+        ChemicalInventoryRequest::SearchInventory(inventory, page) => {
+            match check_to_run(requested_user, Permission::GetChemicalInventory) {
+                Ok(()) => search_chemical_inventory(inventory, page, database_connection)
                     .map(|c| ChemicalInventoryResponse::ManyInventoryEntries(c)),
                 Err(e) => Err(e),
             }
         }
         ChemicalInventoryRequest::GetInventory(id) => {
-            match check_to_run(requested_user, "GetChemicalInventory", database_connection) {
+            match check_to_run(requested_user, Permission::GetChemicalInventory) {
                 Ok(()) => get_chemical_inventory(id, database_connection)
                     .map(|c| ChemicalInventoryResponse::OneInventoryEntry(c)),
                 Err(e) => Err(e),
             }
         }
         ChemicalInventoryRequest::CreateInventory(inventory) => {
-            match check_to_run(
-                requested_user,
-                "CreateChemicalInventory",
-                database_connection,
-            ) {
+            match check_to_run(requested_user, Permission::CreateChemicalInventory) {
                 Ok(()) => create_chemical_inventory(inventory, database_connection)
                     .map(|c| ChemicalInventoryResponse::OneInventoryEntry(c)),
                 Err(e) => Err(e),
             }
         }
         ChemicalInventoryRequest::UpdateInventory(id, inventory) => {
-            match check_to_run(
+            match check_to_run_scoped(
                 requested_user,
-                "UpdateChemicalInventory",
+                Permission::UpdateChemicalInventory,
+                Permission::UpdateOwnChemicalInventory,
+                OwnedResource::ChemicalInventory(id),
                 database_connection,
             ) {
                 Ok(()) => update_chemical_inventory(id, inventory, database_connection)
@@ -241,9 +265,11 @@ pub fn handle_chemical_inventory(
             }
         }
         ChemicalInventoryRequest::DeleteInventory(id) => {
-            match check_to_run(
+            match check_to_run_scoped(
                 requested_user,
-                "DeleteChemicalInventory",
+                Permission::DeleteChemicalInventory,
+                Permission::DeleteOwnChemicalInventory,
+                OwnedResource::ChemicalInventory(id),
                 database_connection,
             ) {
                 Ok(()) => delete_chemical_inventory(id, database_connection)
@@ -256,88 +282,50 @@ pub fn handle_chemical_inventory(
 
 pub(crate) fn search_chemical_inventory(
     chemical_inventory_search: SearchChemicalInventory,
+    page: Page<ChemicalInventorySortField>,
     database_connection: &MysqlConnection,
 ) -> Result<ChemicalInventoryList, Error> {
-    let mut chemical_inventory_query = chemical_inventory_schema::table.as_query().into_boxed();
-
-    match chemical_inventory_search.purchaser_id {
-        Search::Partial(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::purchaser_id.eq(s))
-        }
-
-        Search::Exact(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::purchaser_id.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
-
-    match chemical_inventory_search.custodian_id {
-        Search::Partial(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::custodian_id.eq(s))
-        }
-
-        Search::Exact(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::custodian_id.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
-
-    match chemical_inventory_search.chemical_id {
-        Search::Partial(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::chemical_id.eq(s))
-        }
-
-        Search::Exact(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::chemical_id.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
-
-    match chemical_inventory_search.storage_location {
-        Search::Partial(s) => {
-            chemical_inventory_query = chemical_inventory_query
-                .filter(chemical_inventory_schema::storage_location.like(format!("%{}%", s)))
-        }
-
-        Search::Exact(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::storage_location.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
-
-    match chemical_inventory_search.amount {
-        Search::Partial(s) => {
-            chemical_inventory_query = chemical_inventory_query
-                .filter(chemical_inventory_schema::amount.like(format!("%{}%", s)))
-        }
-
-        Search::Exact(s) => {
-            chemical_inventory_query =
-                chemical_inventory_query.filter(chemical_inventory_schema::amount.eq(s))
-        }
-
-        Search::NoSearch => {}
-    }
+    let total = filter_chemical_inventory(&chemical_inventory_search)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: ChemicalInventorySortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let chemical_inventory_query = filter_chemical_inventory(&chemical_inventory_search);
+
+    let chemical_inventory_query = crate::sort_query!(chemical_inventory_query, sort, {
+        ChemicalInventorySortField::Id => chemical_inventory_schema::id,
+        ChemicalInventorySortField::ChemicalId => chemical_inventory_schema::chemical_id,
+        ChemicalInventorySortField::CustodianId => chemical_inventory_schema::custodian_id,
+        ChemicalInventorySortField::PurchaserId => chemical_inventory_schema::purchaser_id,
+        ChemicalInventorySortField::StorageLocation => chemical_inventory_schema::storage_location,
+    });
+
+    let found_entries = chemical_inventory_query
+        .then_order_by(chemical_inventory_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<ChemicalInventory>(database_connection)?;
 
-    let found_entries = chemical_inventory_query.load::<ChemicalInventory>(database_connection)?;
     let inventory_list = ChemicalInventoryList {
         entries: found_entries,
+        total: total,
+        next: page.next(total),
     };
 
     Ok(inventory_list)
 }
 
+/// Builds a query for the inventory entries that match the search
+fn filter_chemical_inventory<'a>(
+    chemical_inventory_search: &'a SearchChemicalInventory,
+) -> chemical_inventory_schema::BoxedQuery<'a, Mysql> {
+    chemical_inventory_search.filter(chemical_inventory_schema::table.as_query().into_boxed())
+}
+
 pub(crate) fn get_chemical_inventory(
     id: u64,
     database_connection: &MysqlConnection,
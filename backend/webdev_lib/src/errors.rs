@@ -15,6 +15,11 @@ pub enum ErrorKind {
     GoogleSignIn,
     GoogleUserNoEmail,
     GoogleUserNotFound,
+    InvalidSession,
+    InvalidApiKey,
+    UserDeactivated,
+    DuplicateUser,
+    PermissionAlreadyGranted,
     RegisteredTwiceForTest,
     RegistrationClosedForTest,
     OpenedTestNotRegistered,
@@ -26,6 +31,7 @@ pub enum ErrorKind {
     Image,
     Font,
     Io,
+    Config,
     Unimplemented,
 }
 
@@ -49,6 +55,15 @@ impl std::fmt::Display for Error {
                 f,
                 "The email provided by Google did not match any users' emails"
             ),
+            ErrorKind::InvalidSession => write!(f, "The session is invalid or has expired"),
+            ErrorKind::InvalidApiKey => write!(f, "The API key is invalid or has been revoked"),
+            ErrorKind::UserDeactivated => write!(f, "The user has been deactivated"),
+            ErrorKind::DuplicateUser => {
+                write!(f, "Another user already has that email or banner id")
+            }
+            ErrorKind::PermissionAlreadyGranted => {
+                write!(f, "The user already has that permission")
+            }
             ErrorKind::Unimplemented => write!(f, "Method not implemented"),
             ErrorKind::RegisteredTwiceForTest => write!(f, "Registered twice for a test"),
             ErrorKind::RegistrationClosedForTest => {
@@ -62,6 +77,7 @@ impl std::fmt::Display for Error {
             ErrorKind::Image => write!(f, "There was an image problem"),
             ErrorKind::Io => write!(f, "There was an io problem"),
             ErrorKind::Font => write!(f, "There was a font problem"),
+            ErrorKind::Config => write!(f, "The server is not configured correctly"),
             ErrorKind::SubmissionsClosedForTest => {
                 write!(f, "The test session is closed for submissions")
             }
@@ -133,6 +149,12 @@ impl From<std::str::ParseBoolError> for Error {
     }
 }
 
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::with_source(ErrorKind::Body, Box::new(e))
+    }
+}
+
 impl From<url::ParseError> for Error {
     fn from(s: url::ParseError) -> Error {
         Error::with_source(ErrorKind::Url, Box::new(s))
@@ -163,6 +185,12 @@ impl From<google_signin::Error> for Error {
     }
 }
 
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(e: jsonwebtoken::errors::Error) -> Error {
+        Error::with_source(ErrorKind::PermissionDenied, Box::new(e))
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Error {
         Error::with_source(ErrorKind::Network, Box::new(e))
@@ -198,6 +226,21 @@ impl From<Error> for rouille::Response {
             ErrorKind::PermissionDenied => {
                 rouille::Response::text(e.to_string()).with_status_code(401)
             }
+            ErrorKind::InvalidSession => {
+                rouille::Response::text(e.to_string()).with_status_code(401)
+            }
+            ErrorKind::InvalidApiKey => {
+                rouille::Response::text(e.to_string()).with_status_code(401)
+            }
+            ErrorKind::UserDeactivated => {
+                rouille::Response::text(e.to_string()).with_status_code(403)
+            }
+            ErrorKind::DuplicateUser => {
+                rouille::Response::text(e.to_string()).with_status_code(409)
+            }
+            ErrorKind::PermissionAlreadyGranted => {
+                rouille::Response::text(e.to_string()).with_status_code(409)
+            }
             ErrorKind::RegisteredTwiceForTest => {
                 rouille::Response::text(e.to_string()).with_status_code(409)
             }
@@ -231,6 +274,9 @@ impl From<Error> for rouille::Response {
             ErrorKind::Io => {
                 rouille::Response::text(e.to_string()).with_status_code(501)
             }
+            ErrorKind::Config => {
+                rouille::Response::text(e.to_string()).with_status_code(500)
+            }
             ErrorKind::Unimplemented => {
                 rouille::Response::text(e.to_string()).with_status_code(501)
             }
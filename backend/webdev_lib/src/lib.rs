@@ -7,9 +7,14 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate google_signin;
 
+pub mod audit;
+pub mod auth;
 pub mod chemicals;
 pub mod errors;
 pub mod permissions;
 pub mod search;
+pub mod service_accounts;
+pub mod sessions;
+pub mod shape;
 pub mod tests;
 pub mod users;
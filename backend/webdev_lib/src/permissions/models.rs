@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use diesel::Queryable;
 
 use rouille::router;
@@ -5,22 +7,155 @@ use rouille::router;
 use serde::Deserialize;
 use serde::Serialize;
 
-use url::form_urlencoded;
+use chrono::NaiveDateTime;
 
 use log::warn;
 
+use crate::auth::provider::AuthIdentity;
+
 use crate::errors::{Error, ErrorKind};
 
-use crate::search::Search;
+use crate::search::{parse_queries, unknown_query, NullableSearch, Page, Search};
 
-use super::schema::{permissions, user_permissions};
+use webdev_search_derive::Searchable;
+
+use super::schema::{permissions, role_permissions, roles, user_permissions, user_roles};
 
 #[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
-pub struct Permission {
+pub struct RawPermission {
     pub id: u64,
     pub permission_name: String,
 }
 
+macro_rules! permissions {
+    ($($name:ident,)*) => {
+        /// Every permission the server checks for
+        ///
+        /// Each one is stored by name in the `permissions` table, and
+        /// `reconcile_permissions` adds any that are missing when the server starts.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Permission {
+            $($name,)*
+        }
+
+        impl Permission {
+            /// Every permission, in the order they were added
+            pub const ALL: &'static [Permission] = &[$(Permission::$name,)*];
+
+            /// The name of the permission in the `permissions` table
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Permission::$name => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+permissions! {
+    GetUsers,
+    CreateUsers,
+    UpdateUsers,
+    DeleteUsers,
+
+    GetPermission,
+    CreatePermission,
+    UpdatePermission,
+    DeletePermission,
+
+    GetUserPermission,
+    CreateUserPermission,
+    UpdateUserPermission,
+    DeleteUserPermission,
+
+    GetChemical,
+    CreateChemical,
+    UpdateChemical,
+    DeleteChemical,
+
+    GetChemicalInventory,
+    CreateChemicalInventory,
+    UpdateChemicalInventory,
+    DeleteChemicalInventory,
+
+    GetQuestions,
+    CreateQuestions,
+    UpdateQuestions,
+    DeleteQuestions,
+
+    GetQuestionCategories,
+    CreateQuestionCategories,
+    UpdateQuestionCategories,
+    DeleteQuestionCategories,
+
+    GetTests,
+    CreateTests,
+    UpdateTests,
+    DeleteTests,
+
+    GetTestSessions,
+    CreateTestSessions,
+    UpdateTestSessions,
+    DeleteTestSessions,
+
+    GetTestSessionRegistrations,
+    CreateTestSessionRegistrations,
+    UpdateTestSessionRegistrations,
+    DeleteTestSessionRegistrations,
+
+    RevokeSessions,
+
+    GetRoles,
+    CreateRoles,
+    UpdateRoles,
+    DeleteRoles,
+
+    GetUserRoles,
+    CreateUserRoles,
+    DeleteUserRoles,
+
+    UpdateOwnChemicalInventory,
+    DeleteOwnChemicalInventory,
+
+    DeleteOwnTests,
+
+    DeleteOwnTestSessionRegistrations,
+
+    GetServiceAccounts,
+    CreateServiceAccounts,
+    DeleteServiceAccounts,
+
+    GetAuditLog,
+
+    ImpersonateUsers,
+
+    MergeUsers,
+}
+
+impl Permission {
+    pub fn from_name(name: &str) -> Option<Permission> {
+        Permission::ALL.iter().cloned().find(|p| p.name() == name)
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[test]
+fn permission_name_round_trips() {
+    for permission in Permission::ALL {
+        assert_eq!(Permission::from_name(permission.name()), Some(*permission));
+    }
+}
+
+#[test]
+fn permission_unknown_name_fails() {
+    assert_eq!(Permission::from_name("RootPermission"), None);
+}
+
 #[derive(Insertable, Serialize, Deserialize, Debug)]
 #[table_name = "permissions"]
 pub struct NewPermission {
@@ -35,7 +170,66 @@ pub struct PartialPermission {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PermissionList {
-    pub permissions: Vec<Permission>,
+    pub permissions: Vec<RawPermission>,
+}
+
+/// How a request proved who it is from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Credential {
+    /// An id token verified by the auth provider
+    IdToken,
+
+    /// A session made from an id token
+    Session,
+
+    /// A service account's API key
+    ApiKey,
+}
+
+/// Whoever is making a request, along with every permission they have
+///
+/// This is loaded once per request by `load_principal`, so permission checks
+/// do not need to go back to the database. When a user is impersonating someone
+/// else, this is the impersonated user and `impersonator_id` is the real one.
+/// `credential` is only set on the user that logged in, not on an impersonated one.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub user_id: Option<u64>,
+    pub permissions: HashSet<Permission>,
+    pub impersonator_id: Option<u64>,
+    pub credential: Option<Credential>,
+}
+
+impl Principal {
+    /// A request that was not logged in, which has no permissions
+    pub fn anonymous() -> Principal {
+        Principal {
+            user_id: None,
+            permissions: HashSet::new(),
+            impersonator_id: None,
+            credential: None,
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
+
+/// Something a single user is responsible for
+///
+/// Used with the "Own" variants of permissions, like `UpdateOwnChemicalInventory`,
+/// which only allow the action when the requesting user is the owner.
+#[derive(Debug, Clone, Copy)]
+pub enum OwnedResource {
+    /// Owned by the `custodian_id` of the inventory entry
+    ChemicalInventory(u64),
+
+    /// Owned by the `creator_id` of the test
+    Test(u64),
+
+    /// Owned by the `taker_id` of the registration
+    TestSessionRegistration(u64),
 }
 
 pub enum PermissionRequest {
@@ -43,7 +237,7 @@ pub enum PermissionRequest {
     CreatePermission(NewPermission),          //new permission type of some name to be created
     UpdatePermission(u64, PartialPermission), //Contains id to be changed to new permission_name
     DeletePermission(u64),                    //if of permission to be deleted
-    FirstPermission(String),
+    FirstPermission(AuthIdentity),            //identity verified by the auth provider
     GetPermissions // get all possible permissions from sql table
 }
 
@@ -73,11 +267,9 @@ impl PermissionRequest {
             },
 
             (GET) (/first) => {
-                if let Some(id_token) = request.header("id_token") {
-                    Ok(PermissionRequest::FirstPermission(id_token.to_string()))
-                } else {
-                    Err(Error::new(ErrorKind::PermissionDenied))
-                }
+                // The id token has to be verified by the auth provider first,
+                // so a request with one is handled before it gets routed here
+                Err(Error::new(ErrorKind::PermissionDenied))
             },
 
             (GET) (/) => {
@@ -94,7 +286,7 @@ impl PermissionRequest {
 }
 
 pub enum PermissionResponse {
-    OnePermission(Permission),
+    OnePermission(RawPermission),
     NoResponse,
     ManyPermissions(PermissionList)
 }
@@ -120,13 +312,22 @@ pub struct UserPermission {
     pub user_permission_id: u64,
     pub permission_id: u64,
     pub user_id: u64,
+    pub granted_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
 }
 
+/// A permission granted to a user
+///
+/// The grant only applies from `granted_at` until `expires_at`. Leaving `granted_at`
+/// out when creating a grant starts it right away, and leaving `expires_at` out
+/// makes it last forever.
 #[derive(Insertable, Serialize, Deserialize, Debug)]
 #[table_name = "user_permissions"]
 pub struct NewUserPermission {
     pub permission_id: u64,
     pub user_id: u64,
+    pub granted_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
 }
 
 #[derive(AsChangeset, Serialize, Deserialize, Debug)]
@@ -134,59 +335,120 @@ pub struct NewUserPermission {
 pub struct PartialUserPermission {
     pub permission_id: Option<u64>,
     pub user_id: Option<u64>,
+    pub granted_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
 }
 
+#[derive(Searchable)]
+#[search(table = "user_permissions")]
 pub struct SearchUserPermission {
     pub permission_id: Search<u64>,
     pub user_id: Search<u64>,
+    pub expires_at: NullableSearch<NaiveDateTime>,
+    pub include_inactive: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    Grant,
+    Revoke,
+}
+
+/// Grants or revokes every permission in `permission_ids` for every user in `user_ids`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkUserPermission {
+    pub action: BulkAction,
+    pub user_ids: Vec<u64>,
+    pub permission_ids: Vec<u64>,
+    pub granted_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkOutcome {
+    Granted,
+    /// An expired or not yet started grant was given the new times
+    Renewed,
+    AlreadyGranted,
+    Revoked,
+    NotGranted,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkUserPermissionResult {
+    pub user_id: u64,
+    pub permission_id: u64,
+    pub outcome: BulkOutcome,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkUserPermissionSummary {
+    pub results: Vec<BulkUserPermissionResult>,
+}
+
+/// How far ahead `GET /user_permission/expiring` looks if `days` is not given
+const DEFAULT_EXPIRING_DAYS: i64 = 14;
+
 pub enum UserPermissionRequest {
-    SearchPermission(SearchUserPermission), //list of users with permission id or (?) name
-    GetCurrentUserPermission,               // Get the permission for the logged in user
-    GetPermission(u64),                     //get individual permission entry from its id
-    CheckPermission(u64, String), //entry allowing user of user_id to perform action of action_id
+    SearchPermission(SearchUserPermission, Page<UserPermissionSortField>), //list of users with permission id or (?) name
+    GetCurrentUserPermission, // Get the permission for the logged in user
+    GetPermission(u64),       //get individual permission entry from its id
+    GetExpiringPermissions(i64, Page<UserPermissionSortField>), //entries expiring within the given number of days
+    CheckPermission(u64, Permission), //entry allowing user of user_id to perform action of action_id
     CreatePermission(NewUserPermission), //entry to add to database
+    BulkPermissions(BulkUserPermission), //grant or revoke many entries at once
     UpdatePermission(u64, PartialUserPermission), //entry to update with new information
-    DeletePermission(u64),        //entry to delete from database
+    DeletePermission(u64),            //entry to delete from database
 }
 
 impl UserPermissionRequest {
     pub fn from_rouille(request: &rouille::Request) -> Result<UserPermissionRequest, Error> {
-        let url_queries = form_urlencoded::parse(request.raw_query_string().as_bytes());
-
         router!(request,
             (GET) (/) => {
+                let search = SearchUserPermission::from_query(request.raw_query_string())?;
+                let page = Page::from_query(request.raw_query_string())?;
 
-                let mut permission_id_search = Search::NoSearch;
-                let mut user_id_search = Search::NoSearch;
-
-                for (field, query) in url_queries {
-                    match field.as_ref() as &str {
-                        "permission_id" => permission_id_search =
-                            Search::from_query(query.as_ref())?,
-                        "user_id" => user_id_search =
-                            Search::from_query(query.as_ref())?,
-                        _ => return Err(Error::new(ErrorKind::Url)),
-                    }
-                }
-
-                Ok(UserPermissionRequest::SearchPermission(SearchUserPermission {
-                    permission_id: permission_id_search,
-                    user_id: user_id_search,
-                }))
+                Ok(UserPermissionRequest::SearchPermission(search, page))
             },
 
             (GET) (/current) => {
                 Ok(UserPermissionRequest::GetCurrentUserPermission)
             },
 
+            (GET) (/expiring) => {
+                let mut days = DEFAULT_EXPIRING_DAYS;
+
+                parse_queries(request.raw_query_string(), |field, query| match field {
+                    "days" => {
+                        days = query.parse()?;
+                        Ok(())
+                    }
+                    _ => unknown_query(field, query),
+                })?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(UserPermissionRequest::GetExpiringPermissions(days, page))
+            },
+
             (GET) (/{permission_id: u64}) => {
                 Ok(UserPermissionRequest::GetPermission(permission_id))
             },
 
             (GET) (/{user_id:u64}/{permission_name: String}) => {
-                Ok(UserPermissionRequest::CheckPermission(user_id, permission_name))
+                let permission = Permission::from_name(&permission_name)
+                    .ok_or(Error::new(ErrorKind::NotFound))?;
+                Ok(UserPermissionRequest::CheckPermission(user_id, permission))
+            },
+
+            (POST) (/bulk) => {
+                let request_body = request.data()
+                    .ok_or(Error::new(ErrorKind::Body))?;
+                let bulk_user_permission: BulkUserPermission =
+                    serde_json::from_reader(request_body)?;
+                Ok(UserPermissionRequest::BulkPermissions(bulk_user_permission))
             },
 
             (POST) (/) => {
@@ -222,6 +484,7 @@ pub enum UserPermissionResponse {
     ManyUserPermission(JoinedUserPermissionList),
     ManyPermission(PermissionList),
     OneUserPermission(UserPermission),
+    BulkSummary(BulkUserPermissionSummary),
     NoResponse,
 }
 
@@ -240,6 +503,7 @@ impl UserPermissionResponse {
             UserPermissionResponse::OneUserPermission(user_permission) => {
                 rouille::Response::json(&user_permission)
             }
+            UserPermissionResponse::BulkSummary(summary) => rouille::Response::json(&summary),
             UserPermissionResponse::NoResponse => rouille::Response::empty_204(),
         }
     }
@@ -252,10 +516,276 @@ pub struct JoinedUserPermission {
     pub permission_id: u64,
     pub first_name: String,
     pub last_name: String,
-    pub banner_id: u32,
+    pub banner_id: Option<u32>,
+    pub granted_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
 }
 
+/// A page of grants
+///
+/// `total` counts every grant that matched, and `next` links to the next page.
 #[derive(Serialize, Deserialize)]
 pub struct JoinedUserPermissionList {
     pub entries: Vec<JoinedUserPermission>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+/// Fields that a list of grants can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UserPermissionSortField {
+    Id,
+    UserId,
+    PermissionId,
+    GrantedAt,
+    ExpiresAt,
+}
+
+impl std::str::FromStr for UserPermissionSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UserPermissionSortField, String> {
+        match s {
+            "id" => Ok(UserPermissionSortField::Id),
+            "user_id" => Ok(UserPermissionSortField::UserId),
+            "permission_id" => Ok(UserPermissionSortField::PermissionId),
+            "granted_at" => Ok(UserPermissionSortField::GrantedAt),
+            "expires_at" => Ok(UserPermissionSortField::ExpiresAt),
+            _ => Err(format!("Cannot sort user permissions by {}", s)),
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
+pub struct RawRole {
+    pub id: u64,
+    pub role_name: String,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "roles"]
+pub struct NewRawRole {
+    pub role_name: String,
+}
+
+#[derive(Queryable, Debug)]
+pub struct JoinedRole {
+    pub role: RawRole,
+    pub permission: Option<RawPermission>,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+#[table_name = "role_permissions"]
+pub struct RolePermission {
+    pub role_id: u64,
+    pub permission_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Role {
+    pub id: u64,
+    pub role_name: String,
+    pub permissions: Vec<RawPermission>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewRole {
+    pub role_name: String,
+    pub permissions: Vec<u64>,
+}
+
+/// Changes to a role
+///
+/// If `permissions` is given, it replaces every permission the role currently has.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartialRole {
+    pub role_name: Option<String>,
+    pub permissions: Option<Vec<u64>>,
+}
+
+/// A page of roles
+///
+/// `total` counts every role, and `next` links to the next page.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoleList {
+    pub roles: Vec<Role>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+/// Fields that a list of roles can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RoleSortField {
+    Id,
+    RoleName,
+}
+
+impl std::str::FromStr for RoleSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RoleSortField, String> {
+        match s {
+            "id" => Ok(RoleSortField::Id),
+            "role_name" => Ok(RoleSortField::RoleName),
+            _ => Err(format!("Cannot sort roles by {}", s)),
+        }
+    }
+}
+
+pub enum RoleRequest {
+    GetRoles(Page<RoleSortField>), //get every role and its permissions
+    GetRole(u64),                  //get the role with the id
+    CreateRole(NewRole),           //new role with the given permissions
+    UpdateRole(u64, PartialRole),  //rename the role or replace its permissions
+    DeleteRole(u64),               //delete the role, taking it away from all users
+}
+
+impl RoleRequest {
+    pub fn from_rouille(request: &rouille::Request) -> Result<RoleRequest, Error> {
+        router!(request,
+            (GET) (/) => {
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(RoleRequest::GetRoles(page))
+            },
+
+            (GET) (/{id: u64}) => {
+                Ok(RoleRequest::GetRole(id))
+            },
+
+            (POST) (/) => {
+                let request_body = request.data().ok_or(Error::new(ErrorKind::Body))?;
+                let new_role: NewRole = serde_json::from_reader(request_body)?;
+
+                Ok(RoleRequest::CreateRole(new_role))
+            },
+
+            (PUT) (/{id: u64}) => {
+                let request_body = request.data().ok_or(Error::new(ErrorKind::Body))?;
+                let update_role: PartialRole = serde_json::from_reader(request_body)?;
+
+                Ok(RoleRequest::UpdateRole(id, update_role))
+            },
+
+            (DELETE) (/{id: u64}) => {
+                Ok(RoleRequest::DeleteRole(id))
+            },
+
+            _ => {
+                warn!("Could not create a role request for the given rouille request");
+                Err(Error::new(ErrorKind::NotFound))
+            }
+        ) //end router
+    }
+}
+
+pub enum RoleResponse {
+    OneRole(Role),
+    ManyRoles(RoleList),
+    NoResponse,
+}
+
+impl RoleResponse {
+    pub fn to_rouille(self) -> rouille::Response {
+        match self {
+            RoleResponse::OneRole(role) => rouille::Response::json(&role),
+            RoleResponse::ManyRoles(roles) => rouille::Response::json(&roles),
+            RoleResponse::NoResponse => rouille::Response::empty_204(),
+        }
+    }
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
+#[table_name = "user_roles"]
+pub struct UserRole {
+    pub user_id: u64,
+    pub role_id: u64,
+}
+
+#[derive(Searchable)]
+#[search(table = "user_roles")]
+pub struct SearchUserRole {
+    pub user_id: Search<u64>,
+    pub role_id: Search<u64>,
+}
+
+/// A page of user roles
+///
+/// `total` counts every user role that matched, and `next` links to the next page.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserRoleList {
+    pub entries: Vec<UserRole>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+/// Fields that a list of user roles can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UserRoleSortField {
+    UserId,
+    RoleId,
+}
+
+impl std::str::FromStr for UserRoleSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UserRoleSortField, String> {
+        match s {
+            "user_id" => Ok(UserRoleSortField::UserId),
+            "role_id" => Ok(UserRoleSortField::RoleId),
+            _ => Err(format!("Cannot sort user roles by {}", s)),
+        }
+    }
+}
+
+pub enum UserRoleRequest {
+    SearchUserRoles(SearchUserRole, Page<UserRoleSortField>), //users with a role, or roles of a user
+    CreateUserRole(UserRole),                                 //give the user the role
+    DeleteUserRole(u64, u64), //take the role (second) away from the user (first)
+}
+
+impl UserRoleRequest {
+    pub fn from_rouille(request: &rouille::Request) -> Result<UserRoleRequest, Error> {
+        router!(request,
+            (GET) (/) => {
+                let search = SearchUserRole::from_query(request.raw_query_string())?;
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(UserRoleRequest::SearchUserRoles(search, page))
+            },
+
+            (POST) (/) => {
+                let request_body = request.data()
+                    .ok_or(Error::new(ErrorKind::Body))?;
+                let new_user_role: UserRole =
+                    serde_json::from_reader(request_body)?;
+                Ok(UserRoleRequest::CreateUserRole(new_user_role))
+            },
+
+            (DELETE) (/{user_id: u64}/{role_id: u64}) => {
+                Ok(UserRoleRequest::DeleteUserRole(user_id, role_id))
+            },
+
+            _ => {
+                warn!("Could not create a user role request for the given rouille request");
+                Err(Error::new(ErrorKind::NotFound))
+            }
+        ) //end router
+    }
+}
+
+pub enum UserRoleResponse {
+    OneUserRole(UserRole),
+    ManyUserRoles(UserRoleList),
+    NoResponse,
+}
+
+impl UserRoleResponse {
+    pub fn to_rouille(self) -> rouille::Response {
+        match self {
+            UserRoleResponse::OneUserRole(user_role) => rouille::Response::json(&user_role),
+            UserRoleResponse::ManyUserRoles(user_roles) => rouille::Response::json(&user_roles),
+            UserRoleResponse::NoResponse => rouille::Response::empty_204(),
+        }
+    }
 }
@@ -1,131 +1,437 @@
+use std::env;
+
 use diesel;
 use diesel::mysql::types::Unsigned;
 use diesel::mysql::Mysql;
 use diesel::mysql::MysqlConnection;
 use diesel::sql_types;
+use diesel::BoolExpressionMethods;
+use diesel::Connection;
 use diesel::ExpressionMethods;
+use diesel::JoinOnDsl;
+use diesel::NullableExpressionMethods;
 use diesel::QueryDsl;
 use diesel::RunQueryDsl;
 
-use google_signin;
+use chrono::offset::Local;
+use chrono::Duration;
+use chrono::NaiveDateTime;
 
 use log::debug;
+use log::info;
 use log::trace;
 use log::warn;
 
+use crate::auth::provider::{AuthIdentity, AuthProvider};
+
 use crate::errors::{Error, ErrorKind};
 
-use crate::search::Search;
+use crate::audit::requests::{begin_change, finish_change};
+
+use crate::search::{Page, Sort, SortOrder};
 
 use super::models::{
-    JoinedUserPermission, JoinedUserPermissionList, NewPermission,
-    NewUserPermission, PartialPermission, PartialUserPermission, Permission,
-    PermissionList, PermissionRequest, PermissionResponse,
-    SearchUserPermission, UserPermission, UserPermissionRequest,
-    UserPermissionResponse
+    BulkAction, BulkOutcome, BulkUserPermission, BulkUserPermissionResult,
+    BulkUserPermissionSummary, JoinedRole, JoinedUserPermission, JoinedUserPermissionList, NewPermission,
+    NewRawRole, NewRole, NewUserPermission, OwnedResource, PartialPermission, PartialRole,
+    PartialUserPermission, Permission, PermissionList, Principal, PermissionRequest,
+    PermissionResponse, RawPermission, RawRole, Role, RoleList, RolePermission, RoleRequest,
+    RoleResponse, RoleSortField, SearchUserPermission, SearchUserRole, UserPermission,
+    UserPermissionRequest, UserPermissionResponse, UserPermissionSortField, UserRole, UserRoleList,
+    UserRoleRequest, UserRoleResponse, UserRoleSortField,
 };
 
-use crate::users::models::{NewUser, SearchUser};
+use crate::users::models::NewUser;
 use crate::users::requests::create_user;
-use crate::users::requests::search_users;
 
 use super::schema::permissions as permissions_schema;
+use super::schema::role_permissions as role_permissions_schema;
+use super::schema::roles as roles_schema;
 use super::schema::user_permissions as user_permissions_schema;
+use super::schema::user_roles as user_roles_schema;
 use crate::users::schema::users as users_schema;
 
-pub fn validate_token(id_token: &str, database_connection: &MysqlConnection) -> Result<u64, Error> {
-    let mut client = google_signin::Client::new();
-    client.audiences.push(String::from(
-        "918184954544-jm1aufr31fi6sdjs1140p7p3rouaka14.apps.googleusercontent.com",
-    ));
+use crate::chemicals::schema::chemical_inventory as chemical_inventory_schema;
+use crate::tests::test_sessions::schema::test_session_registrations as test_session_registrations_schema;
+use crate::tests::tests::schema::tests as tests_schema;
 
-    let id_info = client.verify(id_token)?;
+pub fn validate_token(
+    id_token: &str,
+    auth_provider: &dyn AuthProvider,
+    database_connection: &MysqlConnection,
+) -> Result<u64, Error> {
+    let identity = auth_provider.verify(id_token)?;
+
+    let mut found_users = users_schema::table
+        .select((users_schema::id, users_schema::active))
+        .filter(users_schema::email.eq(&identity.email))
+        .load::<(u64, bool)>(database_connection)?;
+
+    match found_users.pop() {
+        Some((user_id, true)) => Ok(user_id),
+        Some((_, false)) => Err(Error::new(ErrorKind::UserDeactivated)),
+        None if email_domain_allowed(&identity.email, &auto_provision_domains()) => {
+            provision_user(identity, database_connection)
+        }
+        None => Err(Error::new(ErrorKind::GoogleUserNotFound)),
+    }
+}
 
-    trace!("Validated token: {:?}", id_info);
+/// Reads a comma separated list from an environment variable, ignoring empty entries
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|list| {
+            list.split(',')
+                .map(|entry| entry.trim().to_owned())
+                .filter(|entry| !entry.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    if let Some(email) = id_info.email {
-        let mut found_users = search_users(
-            SearchUser {
-                first_name: Search::NoSearch,
-                last_name: Search::NoSearch,
-                banner_id: Search::NoSearch,
-                email: Search::Exact(email),
-            },
-            database_connection,
-        )?;
+/// The names that are not among the names found, ignoring case like the database does
+fn unknown_names<'a>(names: &'a [String], found_names: &[&str]) -> Vec<&'a str> {
+    names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| {
+            !found_names
+                .iter()
+                .any(|found| found.eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
 
-        if let Some(user) = found_users.users.pop() {
-            Ok(user.id)
-        } else {
-            Err(Error::new(ErrorKind::GoogleUserNotFound))
+/// Email domains whose users are created the first time they log in
+///
+/// Set with `AUTO_PROVISION_DOMAINS`, like `rowan.edu,students.rowan.edu`. Nobody is
+/// created automatically when it is not set.
+fn auto_provision_domains() -> Vec<String> {
+    env_list("AUTO_PROVISION_DOMAINS")
+}
+
+/// Whether the part of the email after the `@` is exactly one of the domains
+///
+/// Subdomains have to be listed on their own, so `rowan.edu` does not allow
+/// `students.rowan.edu`.
+fn email_domain_allowed(email: &str, domains: &[String]) -> bool {
+    match email.rfind('@') {
+        Some(at) => {
+            let email_domain = &email[at + 1..];
+            domains
+                .iter()
+                .any(|domain| domain.eq_ignore_ascii_case(email_domain))
         }
-    } else {
-        Err(Error::new(ErrorKind::GoogleUserNoEmail))
+        None => false,
     }
 }
 
-pub fn check_to_run(
-    requesting_user_id: Option<u64>,
-    permission_name: &str,
+/// Creates a user for someone logging in from an allowed domain for the first time
+///
+/// They are given the permissions named in `AUTO_PROVISION_PERMISSIONS` and the role
+/// named in `AUTO_PROVISION_ROLE`. A name that does not exist is a configuration
+/// error, and nothing is created.
+fn provision_user(
+    identity: AuthIdentity,
     database_connection: &MysqlConnection,
-) -> Result<(), Error> {
-    trace!(
-        "Checking if user {:?} has {}",
-        requesting_user_id,
-        permission_name
-    );
-    match requesting_user_id {
-        Some(user_id) => {
-            match check_user_permission(user_id, String::from(permission_name), database_connection)
-            {
-                Ok(permission) => {
-                    if permission {
-                        debug!("Permission granted!");
-                        Ok(())
-                    } else {
-                        Err(Error::new(ErrorKind::PermissionDenied))
+) -> Result<u64, Error> {
+    let mut permission_names = env_list("AUTO_PROVISION_PERMISSIONS");
+
+    // Names are matched without case by the database, so they are deduplicated the same way
+    permission_names.sort_by_key(|name| name.to_lowercase());
+    permission_names.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+    let role_name = env::var("AUTO_PROVISION_ROLE")
+        .ok()
+        .filter(|role_name| !role_name.is_empty());
+
+    database_connection.transaction::<_, Error, _>(|| {
+        let found_permissions = permissions_schema::table
+            .select((permissions_schema::id, permissions_schema::permission_name))
+            .filter(permissions_schema::permission_name.eq_any(&permission_names))
+            .load::<(u64, String)>(database_connection)?;
+
+        let found_names: Vec<&str> = found_permissions
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect();
+
+        let unknown_names = unknown_names(&permission_names, &found_names);
+
+        if !unknown_names.is_empty() {
+            warn!(
+                "AUTO_PROVISION_PERMISSIONS has permissions that do not exist: {:?}",
+                unknown_names
+            );
+            return Err(Error::new(ErrorKind::Config));
+        }
+
+        let permission_ids = found_permissions.into_iter().map(|(id, _)| id).collect();
+
+        let role_id = match role_name {
+            Some(ref role_name) => {
+                let mut found_roles = roles_schema::table
+                    .select(roles_schema::id)
+                    .filter(roles_schema::role_name.eq(role_name))
+                    .load::<u64>(database_connection)?;
+
+                match found_roles.pop() {
+                    Some(role_id) => Some(role_id),
+                    None => {
+                        warn!("AUTO_PROVISION_ROLE {} does not exist", role_name);
+                        return Err(Error::new(ErrorKind::Config));
                     }
                 }
-                Err(e) => Err(e),
             }
+            None => None,
+        };
+
+        let new_user = NewUser {
+            first_name: identity
+                .first_name
+                .unwrap_or("Not supplied by Google".to_owned()),
+            last_name: identity
+                .last_name
+                .unwrap_or("Not supplied by Google".to_owned()),
+            email: identity.email,
+            banner_id: None,
+            department: None,
+            class_year: None,
+            affiliation: None,
+            phone: None,
+            emergency_contact: None,
+            permissions: permission_ids,
+        };
+
+        let user = create_user(new_user, database_connection)?;
+
+        if let Some(role_id) = role_id {
+            create_user_role(
+                UserRole {
+                    user_id: user.id,
+                    role_id: role_id,
+                },
+                database_connection,
+            )?;
         }
-        None => Err(Error::new(ErrorKind::PermissionDenied)),
+
+        info!("Created user {} for {} on first login", user.id, user.email);
+
+        Ok(user.id)
+    })
+}
+
+/// Loads everything needed to check the permissions of a logged in user
+///
+/// Deactivated users are rejected here as well, so their sessions and API keys stop
+/// working along with their id tokens.
+pub fn load_principal(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<Principal, Error> {
+    let mut found_users = users_schema::table
+        .select(users_schema::active)
+        .filter(users_schema::id.eq(user_id))
+        .load::<bool>(database_connection)?;
+
+    match found_users.pop() {
+        Some(true) => {}
+        Some(false) => return Err(Error::new(ErrorKind::UserDeactivated)),
+        None => return Err(Error::new(ErrorKind::NotFound)),
+    }
+
+    let permissions = get_effective_permissions(user_id, database_connection)?
+        .into_iter()
+        .filter_map(|permission| Permission::from_name(&permission.permission_name))
+        .collect();
+
+    Ok(Principal {
+        user_id: Some(user_id),
+        permissions: permissions,
+        impersonator_id: None,
+        credential: None,
+    })
+}
+
+/// Endpoints that manage permissions, sessions or the audit log, which can never be
+/// used while impersonating
+const IMPERSONATION_BLOCKED_ENDPOINTS: &[&str] = &[
+    "permission",
+    "user_permission",
+    "role",
+    "user_role",
+    "service_accounts",
+    "session",
+    "audit_log",
+];
+
+/// Whether a request can be made while impersonating another user
+///
+/// Besides the blocked endpoints, creating, importing, merging, deleting and
+/// reactivating users has to be done as yourself, since they change accounts.
+pub fn allowed_while_impersonating(method: &str, request_path: &str) -> bool {
+    let segments: Vec<&str> = request_path.trim_matches('/').split('/').collect();
+
+    if IMPERSONATION_BLOCKED_ENDPOINTS.contains(&segments[0]) {
+        return false;
+    }
+
+    match (method, segments.as_slice()) {
+        ("POST", ["users"]) | ("POST", ["users", ""]) => false,
+        ("POST", ["users", "import"]) => false,
+        ("POST", ["users", _, "merge", _]) => false,
+        ("POST", ["users", _, "reactivate"]) => false,
+        ("DELETE", ["users", _]) => false,
+        _ => true,
+    }
+}
+
+/// Lets a user with `ImpersonateUsers` act as another user
+///
+/// The returned principal only has the permissions that both users have, so
+/// impersonating can never do more than the impersonator could do themselves. It
+/// remembers who is really making the request so it can be audited.
+pub fn impersonate(
+    requesting_user: &Principal,
+    target_user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<Principal, Error> {
+    check_to_run(requesting_user, Permission::ImpersonateUsers)?;
+
+    let target = load_principal(target_user_id, database_connection)?;
+
+    info!(
+        "User {:?} is impersonating user {}",
+        requesting_user.user_id, target_user_id
+    );
+
+    Ok(impersonated(requesting_user, target))
+}
+
+fn impersonated(requesting_user: &Principal, target: Principal) -> Principal {
+    Principal {
+        user_id: target.user_id,
+        permissions: target
+            .permissions
+            .intersection(&requesting_user.permissions)
+            .cloned()
+            .collect(),
+        impersonator_id: requesting_user.user_id,
+        credential: None,
+    }
+}
+
+pub fn check_to_run(requesting_user: &Principal, permission: Permission) -> Result<(), Error> {
+    trace!(
+        "Checking if user {:?} has {}",
+        requesting_user.user_id,
+        permission
+    );
+
+    if requesting_user.has_permission(permission) {
+        debug!("Permission granted!");
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::PermissionDenied))
+    }
+}
+
+/// Like `check_to_run`, but also allows users that own the resource
+///
+/// The user needs either `permission`, which applies to every resource, or
+/// `own_permission` along with being the owner of `resource`.
+pub fn check_to_run_scoped(
+    requesting_user: &Principal,
+    permission: Permission,
+    own_permission: Permission,
+    resource: OwnedResource,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    trace!(
+        "Checking if user {:?} has {} or {} for {:?}",
+        requesting_user.user_id,
+        permission,
+        own_permission,
+        resource
+    );
+
+    if requesting_user.has_permission(permission) {
+        debug!("Permission granted!");
+        return Ok(());
+    }
+
+    let user_id = match requesting_user.user_id {
+        Some(user_id) => user_id,
+        None => return Err(Error::new(ErrorKind::PermissionDenied)),
+    };
+
+    if requesting_user.has_permission(own_permission)
+        && get_resource_owner(resource, database_connection)? == user_id
+    {
+        debug!("Permission granted to owner!");
+        return Ok(());
+    }
+
+    Err(Error::new(ErrorKind::PermissionDenied))
+}
+
+pub(crate) fn get_resource_owner(
+    resource: OwnedResource,
+    database_connection: &MysqlConnection,
+) -> Result<u64, Error> {
+    let mut owners = match resource {
+        OwnedResource::ChemicalInventory(id) => chemical_inventory_schema::table
+            .select(chemical_inventory_schema::custodian_id)
+            .filter(chemical_inventory_schema::id.eq(id))
+            .load::<u64>(database_connection)?,
+        OwnedResource::Test(id) => tests_schema::table
+            .select(tests_schema::creator_id)
+            .filter(tests_schema::id.eq(id))
+            .load::<u64>(database_connection)?,
+        OwnedResource::TestSessionRegistration(id) => test_session_registrations_schema::table
+            .select(test_session_registrations_schema::taker_id)
+            .filter(test_session_registrations_schema::id.eq(id))
+            .load::<u64>(database_connection)?,
+    };
+
+    match owners.pop() {
+        Some(owner) => Ok(owner),
+        None => Err(Error::new(ErrorKind::NotFound)),
     }
 }
 
 pub fn handle_permission(
     request: PermissionRequest,
-    requesting_user: Option<u64>,
+    requesting_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<PermissionResponse, Error> {
     match request {
-        PermissionRequest::FirstPermission(id_token) => {
-            first_permission(requesting_user, &id_token, database_connection)
+        PermissionRequest::FirstPermission(identity) => {
+            first_permission(requesting_user.user_id, identity, database_connection)
                 .map(|_| PermissionResponse::NoResponse)
         }
         PermissionRequest::GetPermission(id) => {
-            match check_to_run(requesting_user, "GetPermission", database_connection) {
+            match check_to_run(requesting_user, Permission::GetPermission) {
                 Ok(()) => get_permission(id, database_connection)
                     .map(|a| PermissionResponse::OnePermission(a)),
                 Err(e) => Err(e),
             }
         }
         PermissionRequest::CreatePermission(permission) => {
-            match check_to_run(requesting_user, "CreatePermission", database_connection) {
+            match check_to_run(requesting_user, Permission::CreatePermission) {
                 Ok(()) => create_permission(permission, database_connection)
                     .map(|a| PermissionResponse::OnePermission(a)),
                 Err(e) => Err(e),
             }
         }
         PermissionRequest::UpdatePermission(id, permission) => {
-            match check_to_run(requesting_user, "UpdatePermission", database_connection) {
+            match check_to_run(requesting_user, Permission::UpdatePermission) {
                 Ok(()) => update_permission(id, permission, database_connection)
                     .map(|_| PermissionResponse::NoResponse),
                 Err(e) => Err(e),
             }
         }
         PermissionRequest::DeletePermission(id) => {
-            match check_to_run(requesting_user, "DeletePermission", database_connection) {
+            match check_to_run(requesting_user, Permission::DeletePermission) {
                 Ok(()) => delete_permission(id, database_connection)
                     .map(|_| PermissionResponse::NoResponse),
                 Err(e) => Err(e),
@@ -138,82 +444,114 @@ pub fn handle_permission(
     }
 }
 
+/// Whether `GET /permission/first` is allowed to hand out permissions
+///
+/// It is off unless `ALLOW_FIRST_PERMISSION` is set to `true`, since anyone who can
+/// log in could use it on a fresh deployment. Use the `create_admin` command instead.
+fn first_permission_enabled() -> bool {
+    env::var("ALLOW_FIRST_PERMISSION")
+        .map(|allow| allow == "true")
+        .unwrap_or(false)
+}
+
+const FIRST_PERMISSION_PATH: &str = "/permission/first";
+
+/// Who the audit log records as making the first permission request
+fn first_admin(user_id: u64) -> Principal {
+    Principal {
+        user_id: Some(user_id),
+        ..Principal::anonymous()
+    }
+}
+
 pub(crate) fn first_permission(
     requesting_user: Option<u64>,
-    id_token: &str,
+    identity: AuthIdentity,
     database_connection: &MysqlConnection,
 ) -> Result<(), Error> {
     trace!(
-        "First permission requested for user: {:?}, id_token: {}",
+        "First permission requested for user: {:?}, identity: {:?}",
         requesting_user,
-        id_token
+        identity
     );
 
+    if !first_permission_enabled() {
+        warn!("First permission request attempted, but ALLOW_FIRST_PERMISSION is not set.");
+        return Err(Error::new(ErrorKind::PermissionDenied));
+    }
+
     let search = SearchUserPermission {
-        permission_id: Search::NoSearch,
-        user_id: Search::NoSearch,
+        include_inactive: true,
+        ..SearchUserPermission::default()
     };
 
-    let non_root_permissions = search_user_permission(search, &database_connection)?
+    let non_root_permissions = search_user_permission(search, Page::all(), &database_connection)?
         .entries
         .into_iter()
         .filter(|permission| permission.permission_id != 1)
         .count();
 
-    trace!("Found {} non-root permissions", non_root_permissions);
+    // Admins can be set up through roles without any permissions of their own
+    let assigned_roles = user_roles_schema::table
+        .count()
+        .get_result::<i64>(database_connection)?;
 
-    if non_root_permissions == 0 {
+    trace!(
+        "Found {} non-root permissions and {} assigned roles",
+        non_root_permissions,
+        assigned_roles
+    );
+
+    if non_root_permissions == 0 && assigned_roles == 0 {
         let user_id = if let Some(user_id) = requesting_user {
             user_id
         } else {
-            let mut client = google_signin::Client::new();
-            client.audiences.push(String::from(
-                "918184954544-jm1aufr31fi6sdjs1140p7p3rouaka14.apps.googleusercontent.com",
-            ));
-
-            let info = client.verify(id_token)?;
-
-            trace!("Token verified: {:?}", info);
-
-            let email = match info.email {
-                Some(email) => email,
-                None => {
-                    return Err(Error::new(ErrorKind::PermissionDenied));
-                }
-            };
-
             let new_user = NewUser {
-                first_name: info
-                    .given_name
+                first_name: identity
+                    .first_name
                     .unwrap_or("Not supplied by Google".to_owned()),
-                last_name: info
-                    .family_name
+                last_name: identity
+                    .last_name
                     .unwrap_or("Not supplied by Google".to_owned()),
-                email: email,
-                banner_id: 0,
+                email: identity.email,
+                banner_id: None,
+                department: None,
+                class_year: None,
+                affiliation: None,
+                phone: None,
+                emergency_contact: None,
                 permissions: Vec::new(),
             };
 
             trace!("New user: {:#?}", new_user);
 
-            create_user(new_user, database_connection)?.id
-        };
+            let user_id = create_user(new_user, database_connection)?.id;
 
-        let permissions = permissions_schema::table
-            .filter(permissions_schema::permission_name.ne("RootPermission"))
-            .load::<Permission>(database_connection)?;
+            let pending = begin_change(
+                &first_admin(user_id),
+                FIRST_PERMISSION_PATH.to_owned(),
+                "users",
+                None,
+                "create",
+                database_connection,
+            );
+            finish_change(pending, true, Some(user_id), database_connection);
 
-        let new_user_permissions: Vec<_> = permissions
-            .into_iter()
-            .map(|permission| NewUserPermission {
-                permission_id: permission.id,
-                user_id: user_id,
-            })
-            .collect();
+            user_id
+        };
 
-        diesel::insert_into(user_permissions_schema::table)
-            .values(new_user_permissions)
-            .execute(database_connection)?;
+        // This hands out every permission, so each grant is logged like any other
+        for user_permission_id in grant_all_permissions(user_id, database_connection)? {
+            let pending = begin_change(
+                &first_admin(user_id),
+                FIRST_PERMISSION_PATH.to_owned(),
+                "user_permission",
+                None,
+                "create",
+                database_connection,
+            );
+            finish_change(pending, true, Some(user_permission_id), database_connection);
+        }
 
         Ok(())
     } else {
@@ -222,13 +560,113 @@ pub(crate) fn first_permission(
     }
 }
 
+/// Makes the user with the given email an administrator, creating them if needed
+///
+/// This is meant to be run directly against the database when setting up a new
+/// deployment, so it does not check any permissions. Returns the id of the user.
+pub fn bootstrap_admin(
+    email: &str,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    database_connection: &MysqlConnection,
+) -> Result<u64, Error> {
+    database_connection.transaction::<_, Error, _>(|| {
+        let mut found_users = users_schema::table
+            .select(users_schema::id)
+            .filter(users_schema::email.eq(email))
+            .load::<u64>(database_connection)?;
+
+        let user_id = match found_users.pop() {
+            Some(user_id) => {
+                info!("Promoting existing user {} to administrator", user_id);
+
+                diesel::update(users_schema::table)
+                    .filter(users_schema::id.eq(user_id))
+                    .set((
+                        users_schema::active.eq(true),
+                        users_schema::deactivated_at.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(database_connection)?;
+
+                user_id
+            }
+            None => {
+                let new_user = NewUser {
+                    first_name: first_name.unwrap_or("Administrator".to_owned()),
+                    last_name: last_name.unwrap_or("".to_owned()),
+                    email: email.to_owned(),
+                    banner_id: None,
+                    department: None,
+                    class_year: None,
+                    affiliation: None,
+                    phone: None,
+                    emergency_contact: None,
+                    permissions: Vec::new(),
+                };
+
+                let user_id = create_user(new_user, database_connection)?.id;
+                info!("Created administrator as user {}", user_id);
+                user_id
+            }
+        };
+
+        grant_all_permissions(user_id, database_connection)?;
+
+        Ok(user_id)
+    })
+}
+
+/// Grants the user every permission they do not already have
+/// Gives the user every permission they do not have yet, returning the ids of the new grants
+fn grant_all_permissions(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<u64>, Error> {
+    let granted_permission_ids = user_permissions_schema::table
+        .select(user_permissions_schema::permission_id)
+        .filter(user_permissions_schema::user_id.eq(user_id))
+        .load::<u64>(database_connection)?;
+
+    let permissions = permissions_schema::table
+        .filter(permissions_schema::permission_name.ne("RootPermission"))
+        .load::<RawPermission>(database_connection)?;
+
+    let new_user_permissions: Vec<_> = permissions
+        .into_iter()
+        .filter(|permission| !granted_permission_ids.contains(&permission.id))
+        .map(|permission| NewUserPermission {
+            permission_id: permission.id,
+            user_id: user_id,
+            granted_at: None,
+            expires_at: None,
+        })
+        .collect();
+
+    let new_permission_ids: Vec<u64> = new_user_permissions
+        .iter()
+        .map(|user_permission| user_permission.permission_id)
+        .collect();
+
+    diesel::insert_into(user_permissions_schema::table)
+        .values(new_user_permissions)
+        .execute(database_connection)?;
+
+    let new_user_permission_ids = user_permissions_schema::table
+        .select(user_permissions_schema::user_permission_id)
+        .filter(user_permissions_schema::user_id.eq(user_id))
+        .filter(user_permissions_schema::permission_id.eq_any(new_permission_ids))
+        .load::<u64>(database_connection)?;
+
+    Ok(new_user_permission_ids)
+}
+
 pub(crate) fn get_permission(
     id: u64,
     database_connection: &MysqlConnection,
-) -> Result<Permission, Error> {
+) -> Result<RawPermission, Error> {
     let mut found_permission = permissions_schema::table
         .filter(permissions_schema::id.eq(id))
-        .load::<Permission>(database_connection)?;
+        .load::<RawPermission>(database_connection)?;
 
     match found_permission.pop() {
         Some(permission) => Ok(permission),
@@ -239,7 +677,7 @@ pub(crate) fn get_permission(
 pub(crate) fn create_permission(
     permission: NewPermission,
     database_connection: &MysqlConnection,
-) -> Result<Permission, Error> {
+) -> Result<RawPermission, Error> {
     diesel::insert_into(permissions_schema::table)
         .values(permission)
         .execute(database_connection)?;
@@ -249,7 +687,7 @@ pub(crate) fn create_permission(
     let mut inserted_permissions = permissions_schema::table
         .filter(permissions_schema::id.eq(last_insert_id))
         //.filter(diesel::dsl::sql("id = LAST_INSERT_ID()"))
-        .load::<Permission>(database_connection)?;
+        .load::<RawPermission>(database_connection)?;
 
     if let Some(inserted_permission) = inserted_permissions.pop() {
         Ok(inserted_permission)
@@ -282,48 +720,71 @@ pub(crate) fn delete_permission(
 
 pub fn handle_user_permission(
     request: UserPermissionRequest,
-    requesting_user: Option<u64>,
+    requesting_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<UserPermissionResponse, Error> {
     match request {
-        UserPermissionRequest::SearchPermission(user_permission) => {
-            match check_to_run(requesting_user, "GetUserPermission", database_connection) {
-                Ok(()) => search_user_permission(user_permission, database_connection)
+        UserPermissionRequest::SearchPermission(user_permission, page) => {
+            match check_to_run(requesting_user, Permission::GetUserPermission) {
+                Ok(()) => search_user_permission(user_permission, page, database_connection)
                     .map(|u| UserPermissionResponse::ManyUserPermission(u)),
                 Err(e) => Err(e),
             }
         }
         UserPermissionRequest::GetCurrentUserPermission => {
-            get_current_user_permission(requesting_user, database_connection)
+            get_current_user_permission(requesting_user.user_id, database_connection)
                 .map(|u| UserPermissionResponse::ManyPermission(u))
         }
-        UserPermissionRequest::GetPermission(permission_id) => {
-            match check_to_run(requesting_user, "GetUserPermission", database_connection) {
-                Ok(()) => get_user_permission(permission_id, database_connection)
+        UserPermissionRequest::GetPermission(user_permission_id) => {
+            match check_to_run(requesting_user, Permission::GetUserPermission) {
+                Ok(()) => get_user_permission(user_permission_id, database_connection)
                     .map(|a| UserPermissionResponse::OneUserPermission(a)),
                 Err(e) => Err(e),
             }
         }
-        UserPermissionRequest::CheckPermission(user_id, permission_name) => {
-            check_user_permission(user_id, permission_name, database_connection)
+        UserPermissionRequest::GetExpiringPermissions(days, page) => {
+            match check_to_run(requesting_user, Permission::GetUserPermission) {
+                Ok(()) => get_expiring_user_permissions(days, page, database_connection)
+                    .map(|u| UserPermissionResponse::ManyUserPermission(u)),
+                Err(e) => Err(e),
+            }
+        }
+        UserPermissionRequest::CheckPermission(user_id, permission) => {
+            check_user_permission(user_id, permission, database_connection)
                 .map(|s| UserPermissionResponse::PermissionState(s))
         }
         UserPermissionRequest::CreatePermission(user_permission) => {
-            match check_to_run(requesting_user, "CreateUserPermission", database_connection) {
+            match check_to_run(requesting_user, Permission::CreateUserPermission) {
                 Ok(()) => create_user_permission(user_permission, database_connection)
                     .map(|a| UserPermissionResponse::OneUserPermission(a)),
                 Err(e) => Err(e),
             }
         }
+        UserPermissionRequest::BulkPermissions(bulk_user_permission) => {
+            let permission = match bulk_user_permission.action {
+                BulkAction::Grant => Permission::CreateUserPermission,
+                BulkAction::Revoke => Permission::DeleteUserPermission,
+            };
+
+            match check_to_run(requesting_user, permission) {
+                Ok(()) => bulk_user_permissions(
+                    bulk_user_permission,
+                    requesting_user,
+                    database_connection,
+                )
+                .map(|s| UserPermissionResponse::BulkSummary(s)),
+                Err(e) => Err(e),
+            }
+        }
         UserPermissionRequest::UpdatePermission(id, user_permission) => {
-            match check_to_run(requesting_user, "UpdateUserPermission", database_connection) {
+            match check_to_run(requesting_user, Permission::UpdateUserPermission) {
                 Ok(()) => update_user_permission(id, user_permission, database_connection)
                     .map(|_| UserPermissionResponse::NoResponse),
                 Err(e) => Err(e),
             }
         }
         UserPermissionRequest::DeletePermission(id) => {
-            match check_to_run(requesting_user, "DeleteUserPermission", database_connection) {
+            match check_to_run(requesting_user, Permission::DeleteUserPermission) {
                 Ok(()) => delete_user_permission(id, database_connection)
                     .map(|_| UserPermissionResponse::NoResponse),
                 Err(e) => Err(e),
@@ -334,56 +795,130 @@ pub fn handle_user_permission(
 
 pub(crate) fn search_user_permission(
     user_permission_search: SearchUserPermission,
+    page: Page<UserPermissionSortField>,
     database_connection: &MysqlConnection,
 ) -> Result<JoinedUserPermissionList, Error> {
-    let mut user_permission_query = user_permissions_schema::table
-        .inner_join(permissions_schema::table)
-        .inner_join(users_schema::table)
-        .select((
-            user_permissions_schema::permission_id,
-            users_schema::id,
-            permissions_schema::id,
-            users_schema::first_name,
-            users_schema::last_name,
-            users_schema::banner_id,
-        ))
-        .into_boxed::<Mysql>();
-
-    match user_permission_search.permission_id {
-        Search::Partial(s) => {
-            user_permission_query =
-                user_permission_query.filter(user_permissions_schema::permission_id.eq(s))
+    // Used both to count the matching grants and to load a page of them
+    let filter_user_permissions = || {
+        // The searches are on the grants alone, so they are made into a subquery of the ids
+        // that match rather than filters on the join
+        let matching_ids = user_permission_search.filter(
+            user_permissions_schema::table
+                .select(user_permissions_schema::user_permission_id)
+                .into_boxed::<Mysql>(),
+        );
+
+        let mut user_permission_query = user_permissions_schema::table
+            .inner_join(permissions_schema::table)
+            .inner_join(users_schema::table)
+            .filter(user_permissions_schema::user_permission_id.eq_any(matching_ids))
+            .into_boxed::<Mysql>();
+
+        if !user_permission_search.include_inactive {
+            user_permission_query = user_permission_query.filter(users_schema::active.eq(true));
         }
 
-        Search::Exact(s) => {
-            user_permission_query =
-                user_permission_query.filter(user_permissions_schema::permission_id.eq(s))
-        }
+        user_permission_query
+    };
 
-        Search::NoSearch => {}
-    }
+    let total = filter_user_permissions()
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: UserPermissionSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let user_permission_query = filter_user_permissions().select((
+        user_permissions_schema::user_permission_id,
+        users_schema::id,
+        permissions_schema::id,
+        users_schema::first_name,
+        users_schema::last_name,
+        users_schema::banner_id,
+        user_permissions_schema::granted_at,
+        user_permissions_schema::expires_at,
+    ));
 
-    match user_permission_search.user_id {
-        Search::Partial(s) => {
-            user_permission_query =
-                user_permission_query.filter(user_permissions_schema::user_id.eq(s))
-        }
+    let user_permission_query = crate::sort_query!(user_permission_query, sort, {
+        UserPermissionSortField::Id => user_permissions_schema::user_permission_id,
+        UserPermissionSortField::UserId => user_permissions_schema::user_id,
+        UserPermissionSortField::PermissionId => user_permissions_schema::permission_id,
+        UserPermissionSortField::GrantedAt => user_permissions_schema::granted_at,
+        UserPermissionSortField::ExpiresAt => user_permissions_schema::expires_at,
+    });
+
+    let found_permission_entries = user_permission_query
+        .then_order_by(user_permissions_schema::user_permission_id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<JoinedUserPermission>(database_connection)?;
+
+    Ok(JoinedUserPermissionList {
+        entries: found_permission_entries,
+        total: total,
+        next: page.next(total),
+    })
+}
 
-        Search::Exact(s) => {
-            user_permission_query =
-                user_permission_query.filter(user_permissions_schema::user_id.eq(s))
-        }
+/// Grants that run out within the next `days` days, soonest first unless another sort is given
+pub(crate) fn get_expiring_user_permissions(
+    days: i64,
+    page: Page<UserPermissionSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<JoinedUserPermissionList, Error> {
+    let now = Local::now().naive_local();
+
+    // Used both to count the expiring grants and to load a page of them
+    let filter_user_permissions = || {
+        user_permissions_schema::table
+            .inner_join(permissions_schema::table)
+            .inner_join(users_schema::table)
+            .filter(user_permissions_schema::expires_at.gt(now))
+            .filter(user_permissions_schema::expires_at.le(now + Duration::days(days)))
+            .into_boxed::<Mysql>()
+    };
 
-        Search::NoSearch => {}
-    }
+    let total = filter_user_permissions()
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: UserPermissionSortField::ExpiresAt,
+        order: SortOrder::Ascending,
+    });
+
+    let user_permission_query = filter_user_permissions().select((
+        user_permissions_schema::user_permission_id,
+        users_schema::id,
+        permissions_schema::id,
+        users_schema::first_name,
+        users_schema::last_name,
+        users_schema::banner_id,
+        user_permissions_schema::granted_at,
+        user_permissions_schema::expires_at,
+    ));
 
-    let found_permission_entries =
-        user_permission_query.load::<JoinedUserPermission>(database_connection)?;
-    let joined_list = JoinedUserPermissionList {
+    let user_permission_query = crate::sort_query!(user_permission_query, sort, {
+        UserPermissionSortField::Id => user_permissions_schema::user_permission_id,
+        UserPermissionSortField::UserId => user_permissions_schema::user_id,
+        UserPermissionSortField::PermissionId => user_permissions_schema::permission_id,
+        UserPermissionSortField::GrantedAt => user_permissions_schema::granted_at,
+        UserPermissionSortField::ExpiresAt => user_permissions_schema::expires_at,
+    });
+
+    let found_permission_entries = user_permission_query
+        .then_order_by(user_permissions_schema::user_permission_id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<JoinedUserPermission>(database_connection)?;
+
+    Ok(JoinedUserPermissionList {
         entries: found_permission_entries,
-    };
-
-    Ok(joined_list)
+        total: total,
+        next: page.next(total),
+    })
 }
 
 pub(crate) fn get_current_user_permission(
@@ -391,11 +926,7 @@ pub(crate) fn get_current_user_permission(
     database_connection: &MysqlConnection,
 ) -> Result<PermissionList, Error> {
     if let Some(user_id) = requesting_user {
-        let permissions = permissions_schema::table
-            .inner_join(user_permissions_schema::table)
-            .select((permissions_schema::id, permissions_schema::permission_name))
-            .filter(user_permissions_schema::user_id.eq(user_id))
-            .load::<Permission>(database_connection)?;
+        let permissions = get_effective_permissions(user_id, database_connection)?;
 
         Ok(PermissionList { permissions })
     } else {
@@ -403,12 +934,56 @@ pub(crate) fn get_current_user_permission(
     }
 }
 
+/// Every permission the user has, whether granted directly or through a role
+///
+/// Direct grants that have not started yet or have expired are left out.
+pub(crate) fn get_effective_permissions(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<RawPermission>, Error> {
+    let now = Local::now().naive_local();
+
+    let mut permissions = permissions_schema::table
+        .inner_join(user_permissions_schema::table)
+        .select((permissions_schema::id, permissions_schema::permission_name))
+        .filter(user_permissions_schema::user_id.eq(user_id))
+        .filter(
+            user_permissions_schema::granted_at
+                .is_null()
+                .or(user_permissions_schema::granted_at.le(now)),
+        )
+        .filter(
+            user_permissions_schema::expires_at
+                .is_null()
+                .or(user_permissions_schema::expires_at.gt(now)),
+        )
+        .load::<RawPermission>(database_connection)?;
+
+    let role_permissions = permissions_schema::table
+        .inner_join(role_permissions_schema::table)
+        .inner_join(
+            user_roles_schema::table
+                .on(user_roles_schema::role_id.eq(role_permissions_schema::role_id)),
+        )
+        .select((permissions_schema::id, permissions_schema::permission_name))
+        .filter(user_roles_schema::user_id.eq(user_id))
+        .load::<RawPermission>(database_connection)?;
+
+    for permission in role_permissions {
+        if !permissions.iter().any(|p| p.id == permission.id) {
+            permissions.push(permission);
+        }
+    }
+
+    Ok(permissions)
+}
+
 pub(crate) fn get_user_permission(
-    permission_id: u64,
+    user_permission_id: u64,
     database_connection: &MysqlConnection,
 ) -> Result<UserPermission, Error> {
     let mut found_user_permissions = user_permissions_schema::table
-        .filter(user_permissions_schema::permission_id.eq(permission_id))
+        .filter(user_permissions_schema::user_permission_id.eq(user_permission_id))
         .load::<UserPermission>(database_connection)?;
 
     match found_user_permissions.pop() {
@@ -419,38 +994,103 @@ pub(crate) fn get_user_permission(
 
 pub(crate) fn check_user_permission(
     user_id: u64,
-    permission_name: String,
+    permission: Permission,
     database_connection: &MysqlConnection,
 ) -> Result<bool, Error> {
+    let now = Local::now().naive_local();
+
     let found_user_permissions = user_permissions_schema::table
         .inner_join(permissions_schema::table)
-        .select((
-            user_permissions_schema::user_id,
-            permissions_schema::permission_name,
-        ))
+        .select(permissions_schema::id)
         .filter(user_permissions_schema::user_id.eq(user_id))
-        .filter(permissions_schema::permission_name.eq(permission_name))
+        .filter(permissions_schema::permission_name.eq(permission.name()))
+        .filter(
+            user_permissions_schema::granted_at
+                .is_null()
+                .or(user_permissions_schema::granted_at.le(now)),
+        )
+        .filter(
+            user_permissions_schema::expires_at
+                .is_null()
+                .or(user_permissions_schema::expires_at.gt(now)),
+        )
+        .load::<u64>(database_connection)?;
+
+    if !found_user_permissions.is_empty() {
+        return Ok(true);
+    }
+
+    let found_role_permissions = role_permissions_schema::table
+        .inner_join(permissions_schema::table)
+        .inner_join(
+            user_roles_schema::table
+                .on(user_roles_schema::role_id.eq(role_permissions_schema::role_id)),
+        )
+        .select(permissions_schema::id)
+        .filter(user_roles_schema::user_id.eq(user_id))
+        .filter(permissions_schema::permission_name.eq(permission.name()))
+        .load::<u64>(database_connection)?;
+
+    Ok(!found_role_permissions.is_empty())
+}
+
+/// Whether a grant applies at `now`, the same way `check_user_permission` decides
+fn grant_is_active(user_permission: &UserPermission, now: NaiveDateTime) -> bool {
+    let started = user_permission.granted_at.map_or(true, |t| t <= now);
+    let not_expired = user_permission.expires_at.map_or(true, |t| t > now);
+
+    started && not_expired
+}
+
+/// Gives a grant that has expired or not started yet new times
+fn renew_user_permission(
+    user_permission_id: u64,
+    granted_at: Option<NaiveDateTime>,
+    expires_at: Option<NaiveDateTime>,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    diesel::update(user_permissions_schema::table)
+        .filter(user_permissions_schema::user_permission_id.eq(user_permission_id))
+        .set((
+            user_permissions_schema::granted_at.eq(granted_at),
+            user_permissions_schema::expires_at.eq(expires_at),
+        ))
         .execute(database_connection)?;
 
-    if found_user_permissions != 0 {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+    Ok(())
 }
 
 pub(crate) fn create_user_permission(
-    user_permission: NewUserPermission,
+    mut user_permission: NewUserPermission,
     database_connection: &MysqlConnection,
 ) -> Result<UserPermission, Error> {
+    if user_permission.granted_at.is_none() {
+        user_permission.granted_at = Some(Local::now().naive_local());
+    }
+
     //find if permission currently exists, should not duplicate (user_id, permission_id) pairs
-    let found_user_permissions = user_permissions_schema::table
+    let mut found_user_permissions = user_permissions_schema::table
         .filter(user_permissions_schema::user_id.eq(user_permission.user_id))
         .filter(user_permissions_schema::permission_id.eq(user_permission.permission_id))
-        .execute(database_connection)?;
+        .load::<UserPermission>(database_connection)?;
 
-    if found_user_permissions != 0 {
-        return Err(Error::new(ErrorKind::Database));
+    if let Some(found_user_permission) = found_user_permissions.pop() {
+        if grant_is_active(&found_user_permission, Local::now().naive_local()) {
+            return Err(Error::new(ErrorKind::PermissionAlreadyGranted));
+        }
+
+        // An expired grant is given again by renewing it in place
+        renew_user_permission(
+            found_user_permission.user_permission_id,
+            user_permission.granted_at,
+            user_permission.expires_at,
+            database_connection,
+        )?;
+
+        return get_user_permission(
+            found_user_permission.user_permission_id,
+            database_connection,
+        );
     }
 
     //permission most definitely does not exist at this point
@@ -473,13 +1113,120 @@ pub(crate) fn create_user_permission(
     }
 }
 
+/// Grants or revokes every pair of user and permission in one transaction
+///
+/// Pairs that are already in the requested state are skipped rather than failing,
+/// so the same request can safely be sent again. Every user whose permissions changed
+/// gets an audit log entry of its own.
+pub(crate) fn bulk_user_permissions(
+    bulk_user_permission: BulkUserPermission,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<BulkUserPermissionSummary, Error> {
+    let now = Local::now().naive_local();
+
+    let pending: Vec<_> = bulk_user_permission
+        .user_ids
+        .iter()
+        .map(|&user_id| {
+            let pending = begin_change(
+                requesting_user,
+                "/user_permission/bulk".to_owned(),
+                "users",
+                Some(user_id),
+                "update",
+                database_connection,
+            );
+
+            (user_id, pending)
+        })
+        .collect();
+
+    let summary = database_connection.transaction::<_, Error, _>(|| {
+        let mut results = Vec::new();
+
+        for &user_id in &bulk_user_permission.user_ids {
+            for &permission_id in &bulk_user_permission.permission_ids {
+                let existing = user_permissions_schema::table
+                    .filter(user_permissions_schema::user_id.eq(user_id))
+                    .filter(user_permissions_schema::permission_id.eq(permission_id));
+
+                let outcome = match bulk_user_permission.action {
+                    BulkAction::Grant => {
+                        let granted_at = bulk_user_permission.granted_at.or(Some(now));
+                        let existing = existing.load::<UserPermission>(database_connection)?;
+
+                        if existing.iter().any(|grant| grant_is_active(grant, now)) {
+                            BulkOutcome::AlreadyGranted
+                        } else if let Some(grant) = existing.first() {
+                            // Expired grants are renewed, like a new semester's lab access
+                            renew_user_permission(
+                                grant.user_permission_id,
+                                granted_at,
+                                bulk_user_permission.expires_at,
+                                database_connection,
+                            )?;
+
+                            BulkOutcome::Renewed
+                        } else {
+                            diesel::insert_into(user_permissions_schema::table)
+                                .values(NewUserPermission {
+                                    permission_id: permission_id,
+                                    user_id: user_id,
+                                    granted_at: granted_at,
+                                    expires_at: bulk_user_permission.expires_at,
+                                })
+                                .execute(database_connection)?;
+
+                            BulkOutcome::Granted
+                        }
+                    }
+                    BulkAction::Revoke => {
+                        if diesel::delete(existing).execute(database_connection)? != 0 {
+                            BulkOutcome::Revoked
+                        } else {
+                            BulkOutcome::NotGranted
+                        }
+                    }
+                };
+
+                results.push(BulkUserPermissionResult {
+                    user_id: user_id,
+                    permission_id: permission_id,
+                    outcome: outcome,
+                });
+            }
+        }
+
+        Ok(BulkUserPermissionSummary { results })
+    });
+
+    for (user_id, pending) in pending {
+        let changed = match &summary {
+            Ok(summary) => summary.results.iter().any(|r| {
+                r.user_id == user_id
+                    && (r.outcome == BulkOutcome::Granted
+                        || r.outcome == BulkOutcome::Renewed
+                        || r.outcome == BulkOutcome::Revoked)
+            }),
+            Err(_) => true,
+        };
+
+        if changed {
+            finish_change(pending, summary.is_ok(), None, database_connection);
+        }
+    }
+
+    summary
+}
+
 pub(crate) fn update_user_permission(
     id: u64,
     user_permission: PartialUserPermission,
     database_connection: &MysqlConnection,
 ) -> Result<(), Error> {
     diesel::update(user_permissions_schema::table)
-        .filter(user_permissions_schema::permission_id.eq(id))
+        .filter(user_permissions_schema::user_permission_id.eq(id))
         .set(&user_permission)
         .execute(database_connection)?;
 
@@ -491,7 +1238,7 @@ pub(crate) fn delete_user_permission(
     database_connection: &MysqlConnection,
 ) -> Result<(), Error> {
     diesel::delete(
-        user_permissions_schema::table.filter(user_permissions_schema::permission_id.eq(id)),
+        user_permissions_schema::table.filter(user_permissions_schema::user_permission_id.eq(id)),
     )
     .execute(database_connection)?;
 
@@ -506,6 +1253,446 @@ pub(crate) fn get_permissions(
             permissions_schema::id,
             permissions_schema::permission_name,
         ))
-        .load::<Permission>(database_connection)?;
+        .load::<RawPermission>(database_connection)?;
     Ok(PermissionList { permissions })
 }
+
+/// Makes sure the `permissions` table has a row for every `Permission`
+///
+/// Missing permissions are inserted. Rows that do not match any `Permission` are
+/// left alone, but are logged since nothing will ever check for them.
+pub fn reconcile_permissions(database_connection: &MysqlConnection) -> Result<(), Error> {
+    let existing_names = permissions_schema::table
+        .select(permissions_schema::permission_name)
+        .load::<String>(database_connection)?;
+
+    let missing: Vec<NewPermission> = Permission::ALL
+        .iter()
+        .filter(|permission| !existing_names.iter().any(|name| name == permission.name()))
+        .map(|permission| NewPermission {
+            permission_name: permission.name().to_owned(),
+        })
+        .collect();
+
+    for permission in &missing {
+        info!("Adding missing permission {}", permission.permission_name);
+    }
+
+    if !missing.is_empty() {
+        diesel::insert_into(permissions_schema::table)
+            .values(&missing)
+            .execute(database_connection)?;
+    }
+
+    for name in &existing_names {
+        if Permission::from_name(name).is_none() {
+            warn!("Permission {} is not used by the server", name);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn handle_role(
+    request: RoleRequest,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<RoleResponse, Error> {
+    match request {
+        RoleRequest::GetRoles(page) => {
+            check_to_run(requesting_user, Permission::GetRoles)?;
+            get_roles(page, database_connection).map(|r| RoleResponse::ManyRoles(r))
+        }
+        RoleRequest::GetRole(id) => {
+            check_to_run(requesting_user, Permission::GetRoles)?;
+            get_role(id, database_connection).map(|r| RoleResponse::OneRole(r))
+        }
+        RoleRequest::CreateRole(role) => {
+            check_to_run(requesting_user, Permission::CreateRoles)?;
+            create_role(role, database_connection).map(|r| RoleResponse::OneRole(r))
+        }
+        RoleRequest::UpdateRole(id, role) => {
+            check_to_run(requesting_user, Permission::UpdateRoles)?;
+            update_role(id, role, database_connection).map(|_| RoleResponse::NoResponse)
+        }
+        RoleRequest::DeleteRole(id) => {
+            check_to_run(requesting_user, Permission::DeleteRoles)?;
+            delete_role(id, database_connection).map(|_| RoleResponse::NoResponse)
+        }
+    }
+}
+
+fn condense_role_join(joined: Vec<JoinedRole>) -> Vec<Role> {
+    let mut condensed: Vec<Role> = Vec::new();
+
+    for join in joined {
+        let mut permission = if let Some(permission) = &join.permission {
+            vec![permission.clone()]
+        } else {
+            Vec::new()
+        };
+
+        if let Some(role) = condensed.iter_mut().find(|r| r.id == join.role.id) {
+            role.permissions.append(&mut permission);
+        } else {
+            condensed.push(Role {
+                id: join.role.id,
+                role_name: join.role.role_name,
+                permissions: permission,
+            });
+        }
+    }
+
+    condensed
+}
+
+pub(crate) fn get_roles(
+    page: Page<RoleSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<RoleList, Error> {
+    let total = roles_schema::table
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: RoleSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    // The page is found before joining the permissions, since each one is another row
+    let ids_query = roles_schema::table
+        .select(roles_schema::id)
+        .into_boxed::<Mysql>();
+
+    let ids_query = crate::sort_query!(ids_query, sort, {
+        RoleSortField::Id => roles_schema::id,
+        RoleSortField::RoleName => roles_schema::role_name,
+    });
+
+    let role_ids = ids_query
+        .then_order_by(roles_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<u64>(database_connection)?;
+
+    let joined_roles = roles_schema::table
+        .left_join(role_permissions_schema::table.left_join(permissions_schema::table))
+        .select((
+            (roles_schema::id, roles_schema::role_name),
+            (permissions_schema::id, permissions_schema::permission_name).nullable(),
+        ))
+        .filter(roles_schema::id.eq_any(role_ids.clone()))
+        .load::<JoinedRole>(database_connection)?;
+
+    let mut roles = condense_role_join(joined_roles);
+
+    // The join does not keep the order of the page
+    roles.sort_by_key(|r| role_ids.iter().position(|id| *id == r.id));
+
+    Ok(RoleList {
+        roles: roles,
+        total: total,
+        next: page.next(total),
+    })
+}
+
+pub(crate) fn get_role(id: u64, database_connection: &MysqlConnection) -> Result<Role, Error> {
+    let joined_roles = roles_schema::table
+        .left_join(role_permissions_schema::table.left_join(permissions_schema::table))
+        .select((
+            (roles_schema::id, roles_schema::role_name),
+            (permissions_schema::id, permissions_schema::permission_name).nullable(),
+        ))
+        .filter(roles_schema::id.eq(id))
+        .load::<JoinedRole>(database_connection)?;
+
+    match condense_role_join(joined_roles).pop() {
+        Some(role) => Ok(role),
+        None => Err(Error::new(ErrorKind::NotFound)),
+    }
+}
+
+pub(crate) fn create_role(
+    role: NewRole,
+    database_connection: &MysqlConnection,
+) -> Result<Role, Error> {
+    let role_id = database_connection.transaction::<_, Error, _>(|| {
+        diesel::insert_into(roles_schema::table)
+            .values(NewRawRole {
+                role_name: role.role_name,
+            })
+            .execute(database_connection)?;
+
+        no_arg_sql_function!(last_insert_id, Unsigned<sql_types::Bigint>);
+
+        let mut inserted_roles = roles_schema::table
+            .filter(roles_schema::id.eq(last_insert_id))
+            .load::<RawRole>(database_connection)?;
+
+        let inserted_role = match inserted_roles.pop() {
+            Some(inserted_role) => inserted_role,
+            None => return Err(Error::new(ErrorKind::Database)),
+        };
+
+        set_role_permissions(inserted_role.id, role.permissions, database_connection)?;
+
+        Ok(inserted_role.id)
+    })?;
+
+    get_role(role_id, database_connection)
+}
+
+fn set_role_permissions(
+    role_id: u64,
+    permission_ids: Vec<u64>,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    diesel::delete(role_permissions_schema::table.filter(role_permissions_schema::role_id.eq(role_id)))
+        .execute(database_connection)?;
+
+    let new_role_permissions: Vec<_> = permission_ids
+        .into_iter()
+        .map(|permission_id| RolePermission {
+            role_id: role_id,
+            permission_id: permission_id,
+        })
+        .collect();
+
+    diesel::insert_into(role_permissions_schema::table)
+        .values(new_role_permissions)
+        .execute(database_connection)?;
+
+    Ok(())
+}
+
+pub(crate) fn update_role(
+    id: u64,
+    role: PartialRole,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    database_connection.transaction::<_, Error, _>(|| {
+        if let Some(role_name) = role.role_name {
+            diesel::update(roles_schema::table)
+                .filter(roles_schema::id.eq(id))
+                .set(roles_schema::role_name.eq(role_name))
+                .execute(database_connection)?;
+        }
+
+        if let Some(permissions) = role.permissions {
+            set_role_permissions(id, permissions, database_connection)?;
+        }
+
+        Ok(())
+    })
+}
+
+pub(crate) fn delete_role(id: u64, database_connection: &MysqlConnection) -> Result<(), Error> {
+    diesel::delete(roles_schema::table.filter(roles_schema::id.eq(id)))
+        .execute(database_connection)?;
+
+    Ok(())
+}
+
+pub fn handle_user_role(
+    request: UserRoleRequest,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<UserRoleResponse, Error> {
+    match request {
+        UserRoleRequest::SearchUserRoles(user_role, page) => {
+            check_to_run(requesting_user, Permission::GetUserRoles)?;
+            search_user_roles(user_role, page, database_connection)
+                .map(|u| UserRoleResponse::ManyUserRoles(u))
+        }
+        UserRoleRequest::CreateUserRole(user_role) => {
+            check_to_run(requesting_user, Permission::CreateUserRoles)?;
+            create_user_role(user_role, database_connection)
+                .map(|u| UserRoleResponse::OneUserRole(u))
+        }
+        UserRoleRequest::DeleteUserRole(user_id, role_id) => {
+            check_to_run(requesting_user, Permission::DeleteUserRoles)?;
+            delete_user_role(user_id, role_id, database_connection)
+                .map(|_| UserRoleResponse::NoResponse)
+        }
+    }
+}
+
+pub(crate) fn search_user_roles(
+    user_role_search: SearchUserRole,
+    page: Page<UserRoleSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<UserRoleList, Error> {
+    let total = filter_user_roles(&user_role_search)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: UserRoleSortField::UserId,
+        order: SortOrder::Ascending,
+    });
+
+    let user_role_query = filter_user_roles(&user_role_search);
+
+    let user_role_query = crate::sort_query!(user_role_query, sort, {
+        UserRoleSortField::UserId => user_roles_schema::user_id,
+        UserRoleSortField::RoleId => user_roles_schema::role_id,
+    });
+
+    let found_user_roles = user_role_query
+        .then_order_by(user_roles_schema::user_id.asc())
+        .then_order_by(user_roles_schema::role_id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<UserRole>(database_connection)?;
+
+    Ok(UserRoleList {
+        entries: found_user_roles,
+        total: total,
+        next: page.next(total),
+    })
+}
+
+/// Builds a query for the user roles that match the search
+fn filter_user_roles<'a>(
+    user_role_search: &'a SearchUserRole,
+) -> user_roles_schema::BoxedQuery<'a, Mysql> {
+    user_role_search.filter(user_roles_schema::table.into_boxed::<Mysql>())
+}
+
+/// Every role of one user
+pub(crate) fn get_user_roles(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<UserRole>, Error> {
+    let found_user_roles = user_roles_schema::table
+        .filter(user_roles_schema::user_id.eq(user_id))
+        .order(user_roles_schema::role_id.asc())
+        .load::<UserRole>(database_connection)?;
+
+    Ok(found_user_roles)
+}
+
+pub(crate) fn create_user_role(
+    user_role: UserRole,
+    database_connection: &MysqlConnection,
+) -> Result<UserRole, Error> {
+    //should not duplicate (user_id, role_id) pairs
+    let found_user_roles = user_roles_schema::table
+        .filter(user_roles_schema::user_id.eq(user_role.user_id))
+        .filter(user_roles_schema::role_id.eq(user_role.role_id))
+        .load::<UserRole>(database_connection)?;
+
+    if !found_user_roles.is_empty() {
+        return Err(Error::new(ErrorKind::Database));
+    }
+
+    diesel::insert_into(user_roles_schema::table)
+        .values(&user_role)
+        .execute(database_connection)?;
+
+    Ok(user_role)
+}
+
+pub(crate) fn delete_user_role(
+    user_id: u64,
+    role_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    diesel::delete(
+        user_roles_schema::table
+            .filter(user_roles_schema::user_id.eq(user_id))
+            .filter(user_roles_schema::role_id.eq(role_id)),
+    )
+    .execute(database_connection)?;
+
+    Ok(())
+}
+
+#[test]
+fn unknown_names_ignores_case_and_found_names() {
+    let names = vec![
+        "GetUsers".to_owned(),
+        "getchemical".to_owned(),
+        "FlyToTheMoon".to_owned(),
+    ];
+
+    assert_eq!(
+        unknown_names(&names, &["GetUsers", "GetChemical"]),
+        vec!["FlyToTheMoon"]
+    );
+    assert!(unknown_names(&names[..2], &["GetUsers", "GetChemical"]).is_empty());
+}
+
+#[test]
+fn email_domain_allowed_matches_whole_domain() {
+    let domains = vec!["rowan.edu".to_owned(), "students.rowan.edu".to_owned()];
+
+    assert!(email_domain_allowed("prof@rowan.edu", &domains));
+    assert!(email_domain_allowed("student@Students.Rowan.edu", &domains));
+    assert!(!email_domain_allowed("someone@notrowan.edu", &domains));
+    assert!(!email_domain_allowed("someone@lab.rowan.edu", &domains));
+    assert!(!email_domain_allowed("rowan.edu", &domains));
+    assert!(!email_domain_allowed("someone@rowan.edu", &[]));
+}
+
+#[test]
+fn only_current_grants_are_active() {
+    let now = NaiveDateTime::parse_from_str("2019-11-01 09:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+    let grant = |granted_at: Option<i64>, expires_at: Option<i64>| UserPermission {
+        user_permission_id: 1,
+        permission_id: 2,
+        user_id: 3,
+        granted_at: granted_at.map(|days| now + Duration::days(days)),
+        expires_at: expires_at.map(|days| now + Duration::days(days)),
+    };
+
+    assert!(grant_is_active(&grant(None, None), now));
+    assert!(grant_is_active(&grant(Some(-10), Some(10)), now));
+    assert!(!grant_is_active(&grant(Some(-10), Some(-1)), now));
+    assert!(!grant_is_active(&grant(Some(1), None), now));
+    assert!(!grant_is_active(&grant(None, Some(0)), now));
+}
+
+#[test]
+fn impersonating_never_adds_permissions() {
+    let impersonator = Principal {
+        user_id: Some(1),
+        permissions: vec![Permission::ImpersonateUsers, Permission::GetUsers]
+            .into_iter()
+            .collect(),
+        impersonator_id: None,
+        credential: Some(super::models::Credential::IdToken),
+    };
+    let target = Principal {
+        user_id: Some(2),
+        permissions: vec![Permission::GetUsers, Permission::CreateUserPermission]
+            .into_iter()
+            .collect(),
+        impersonator_id: None,
+        credential: None,
+    };
+
+    let principal = impersonated(&impersonator, target);
+
+    assert_eq!(principal.user_id, Some(2));
+    assert_eq!(principal.impersonator_id, Some(1));
+    assert_eq!(principal.credential, None);
+    assert_eq!(
+        principal.permissions,
+        vec![Permission::GetUsers].into_iter().collect()
+    );
+}
+
+#[test]
+fn account_changes_are_blocked_while_impersonating() {
+    assert!(!allowed_while_impersonating("POST", "/user_role/"));
+    assert!(!allowed_while_impersonating("GET", "/audit_log"));
+    assert!(!allowed_while_impersonating("POST", "/users/"));
+    assert!(!allowed_while_impersonating("POST", "/users/import"));
+    assert!(!allowed_while_impersonating("POST", "/users/4/merge/9"));
+    assert!(!allowed_while_impersonating("POST", "/users/4/reactivate"));
+    assert!(!allowed_while_impersonating("DELETE", "/users/4"));
+    assert!(allowed_while_impersonating("GET", "/users/4"));
+    assert!(allowed_while_impersonating("PUT", "/users/current"));
+    assert!(allowed_while_impersonating("GET", "/test_sessions/2"));
+}
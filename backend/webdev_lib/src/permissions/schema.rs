@@ -12,10 +12,44 @@ table! {
         user_permission_id -> Unsigned<Bigint>,
         permission_id -> Unsigned<Bigint>,
         user_id -> Unsigned<Bigint>,
+        granted_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    roles (id) {
+        id -> Unsigned<Bigint>,
+        role_name -> Varchar,
+    }
+}
+
+table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Unsigned<Bigint>,
+        permission_id -> Unsigned<Bigint>,
+    }
+}
+
+table! {
+    user_roles (user_id, role_id) {
+        user_id -> Unsigned<Bigint>,
+        role_id -> Unsigned<Bigint>,
     }
 }
 
 joinable!(user_permissions -> permissions (permission_id));
 joinable!(user_permissions -> users (user_id));
+joinable!(role_permissions -> roles (role_id));
+joinable!(role_permissions -> permissions (permission_id));
+joinable!(user_roles -> roles (role_id));
+joinable!(user_roles -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(permissions, user_permissions, users,);
+allow_tables_to_appear_in_same_query!(
+    permissions,
+    user_permissions,
+    users,
+    roles,
+    role_permissions,
+    user_roles,
+);
@@ -1,7 +1,22 @@
+use diesel::expression::{
+    AppearsOnTable, BoxableExpression, Expression, NonAggregate, SelectableExpression,
+};
+use diesel::mysql::Mysql;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::result::QueryResult;
+use diesel::sql_types::{Bool, Double, Text};
+use diesel::BoolExpressionMethods;
+
+use url::form_urlencoded;
+
+use crate::errors::{Error, ErrorKind};
+
 #[derive(Debug, PartialEq)]
 pub enum SearchParseError {
     Kind(String),
     Term(String),
+    Page(String),
+    Sort(String),
 }
 
 impl std::fmt::Display for SearchParseError {
@@ -9,14 +24,223 @@ impl std::fmt::Display for SearchParseError {
         match self {
             SearchParseError::Kind(s) => write!(f, "Invalid search kind: {}", s),
             SearchParseError::Term(s) => write!(f, "Invalid search term: {}", s),
+            SearchParseError::Page(s) => write!(f, "Invalid page: {}", s),
+            SearchParseError::Sort(s) => write!(f, "Invalid sort: {}", s),
         }
     }
 }
 
 impl std::error::Error for SearchParseError {}
 
+/// A filter on a table, ready to be given to a boxed query
+pub type BoxedFilter<QS> = Box<dyn BoxableExpression<QS, Mysql, SqlType = Bool>>;
+
+/// A single comparison against a field, which a search is made of
+///
+/// Each module turns these into filters on its own columns, usually with `column_filter!`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Comparison<T> {
+    Partial(T),
+    Exact(T),
+    Not(T),
+    GreaterThan(T),
+    LessThan(T),
+    Between(T, T),
+    In(Vec<T>),
+    IsNull,
+    IsNotNull,
+}
+
+/// Parses one `kind,term` search
+///
+/// `some` and `none` are only kinds when `nullable` is set.
+fn parse_comparison<T: std::str::FromStr>(
+    query: &str,
+    nullable: bool,
+) -> Result<Comparison<T>, SearchParseError> {
+    let mut query_iter = query.splitn(2, ',');
+
+    let kind = query_iter.next().map(|s| s.trim()).unwrap_or("");
+    let term = query_iter.next().map(|s| s.trim());
+
+    let parse_term = |s: &str| {
+        s.trim()
+            .parse()
+            .map_err(|_| SearchParseError::Term(s.trim().to_owned()))
+    };
+
+    // Kinds with one term ignore anything after a second comma, as they always have
+    let parse_first_term = |s: &str| parse_term(s.split(',').next().unwrap_or(""));
+
+    let parse_terms = |s: &str| {
+        s.split(',')
+            .map(|t| parse_term(t))
+            .collect::<Result<Vec<T>, _>>()
+    };
+
+    match (kind, term) {
+        ("partial", Some(s)) => parse_first_term(s).map(Comparison::Partial),
+        ("exact", Some(s)) => parse_first_term(s).map(Comparison::Exact),
+        ("not", Some(s)) => parse_first_term(s).map(Comparison::Not),
+        ("gt", Some(s)) => parse_first_term(s).map(Comparison::GreaterThan),
+        ("lt", Some(s)) => parse_first_term(s).map(Comparison::LessThan),
+        ("between", Some(s)) => {
+            let mut terms = parse_terms(s)?;
+
+            if terms.len() == 2 {
+                let high = terms.remove(1);
+                let low = terms.remove(0);
+                Ok(Comparison::Between(low, high))
+            } else {
+                Err(SearchParseError::Term(s.to_owned()))
+            }
+        }
+        ("in", Some(s)) => parse_terms(s).map(Comparison::In),
+        ("some", None) if nullable => Ok(Comparison::IsNotNull),
+        ("none", None) if nullable => Ok(Comparison::IsNull),
+        ("some", Some(s)) | ("none", Some(s)) if nullable => {
+            Err(SearchParseError::Term(s.to_owned()))
+        }
+        ("partial", None)
+        | ("exact", None)
+        | ("not", None)
+        | ("gt", None)
+        | ("lt", None)
+        | ("between", None)
+        | ("in", None) => Err(SearchParseError::Term("".to_owned())),
+        (k, _) => Err(SearchParseError::Kind(k.to_owned())),
+    }
+}
+
+/// Splits a query into the searches that are joined with `OR`
+///
+/// Searches are separated by `|`, and `\|` stands for a `|` inside a term.
+fn split_searches(query: &str) -> Vec<String> {
+    let mut searches = vec![String::new()];
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                chars.next();
+                searches.last_mut().unwrap().push('|');
+            }
+            '|' => searches.push(String::new()),
+            c => searches.last_mut().unwrap().push(c),
+        }
+    }
+
+    searches
+}
+
+/// Joins filters with `OR`, or gives `None` when there are no filters
+fn any<QS: 'static, I: Iterator<Item = BoxedFilter<QS>>>(filters: I) -> Option<BoxedFilter<QS>> {
+    let mut joined: Option<BoxedFilter<QS>> = None;
+
+    for filter in filters {
+        joined = match joined {
+            Some(joined) => {
+                let joined: BoxedFilter<QS> = Box::new(joined.or(filter));
+                Some(joined)
+            }
+            None => Some(filter),
+        };
+    }
+
+    joined
+}
+
+/// Makes the comparison function that `Search::to_filter` needs for a column
+///
+/// `column_filter!(schema::column)` compares partial searches with `LIKE`, and
+/// `column_filter!(schema::column, exact)` compares them exactly, for columns that are
+/// not text.
+#[macro_export]
+macro_rules! column_filter {
+    (@build $column:path, $term:ident => $partial:expr) => {
+        |comparison| -> $crate::search::BoxedFilter<<$column as ::diesel::Column>::Table> {
+            use $crate::search::Comparison;
+
+            match comparison {
+                Comparison::Partial($term) => Box::new($partial),
+                Comparison::Exact(t) => Box::new(::diesel::ExpressionMethods::eq($column, t)),
+                Comparison::Not(t) => Box::new(::diesel::ExpressionMethods::ne($column, t)),
+                Comparison::GreaterThan(t) => {
+                    Box::new(::diesel::ExpressionMethods::gt($column, t))
+                }
+                Comparison::LessThan(t) => Box::new(::diesel::ExpressionMethods::lt($column, t)),
+                Comparison::Between(low, high) => Box::new(::diesel::BoolExpressionMethods::and(
+                    ::diesel::ExpressionMethods::ge($column, low),
+                    ::diesel::ExpressionMethods::le($column, high),
+                )),
+                Comparison::In(t) => Box::new(::diesel::ExpressionMethods::eq_any($column, t)),
+                Comparison::IsNull => Box::new(::diesel::ExpressionMethods::is_null($column)),
+                Comparison::IsNotNull => {
+                    Box::new(::diesel::ExpressionMethods::is_not_null($column))
+                }
+            }
+        }
+    };
+    ($column:path, exact) => {
+        $crate::column_filter!(@build $column, t => ::diesel::ExpressionMethods::eq($column, t))
+    };
+    ($column:path) => {
+        $crate::column_filter!(@build $column, t => ::diesel::TextExpressionMethods::like(
+            $column,
+            format!("%{}%", t)
+        ))
+    };
+}
+
+/// How relevant a row is to a free text search, from MySQL's `MATCH ... AGAINST`
+///
+/// The columns must be the same as the ones in one of the table's `FULLTEXT` indexes. Rows
+/// that do not match at all have a relevance of 0.
+#[derive(Debug, Clone)]
+pub struct MatchAgainst<C> {
+    columns: C,
+    text: String,
+}
+
+pub fn match_against<C>(columns: C, text: &str) -> MatchAgainst<C> {
+    MatchAgainst {
+        columns: columns,
+        text: text.to_owned(),
+    }
+}
+
+impl<C: Expression> Expression for MatchAgainst<C> {
+    type SqlType = Double;
+}
+
+impl<C: QueryFragment<Mysql>> QueryFragment<Mysql> for MatchAgainst<C> {
+    fn walk_ast(&self, mut out: AstPass<Mysql>) -> QueryResult<()> {
+        out.push_sql("MATCH (");
+        self.columns.walk_ast(out.reborrow())?;
+        out.push_sql(") AGAINST (");
+        out.push_bind_param::<Text, _>(&self.text)?;
+        out.push_sql(" IN NATURAL LANGUAGE MODE)");
+        Ok(())
+    }
+}
+
+impl<C> QueryId for MatchAgainst<C> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<C, QS> AppearsOnTable<QS> for MatchAgainst<C> where C: AppearsOnTable<QS> {}
+
+impl<C, QS> SelectableExpression<QS> for MatchAgainst<C> where C: SelectableExpression<QS> {}
+
+impl<C: NonAggregate> NonAggregate for MatchAgainst<C> {}
+
 /// Search for a field that cannot be null
 ///
+/// Searches are written as `kind,term` in the url, like `partial,acid` or `between,1,10`.
+/// Several searches can be joined with `|`, and any of them can match.
+///
 /// Use a `NullableSearch<T>` when a field could be null instead of `Search<Option<T>>`
 #[derive(Debug, PartialEq)]
 pub enum Search<T> {
@@ -26,34 +250,81 @@ pub enum Search<T> {
     /// Field fully matches
     Exact(T),
 
+    /// Field does not fully match
+    Not(T),
+
+    /// Field is greater than the term
+    GreaterThan(T),
+
+    /// Field is less than the term
+    LessThan(T),
+
+    /// Field is between the two terms, including them
+    Between(T, T),
+
+    /// Field fully matches one of the terms
+    In(Vec<T>),
+
+    /// Any of the searches match
+    Or(Vec<Search<T>>),
+
     /// Do not search by this field
     NoSearch,
 }
 
 impl<T: std::str::FromStr> Search<T> {
     pub fn from_query(query: &str) -> Result<Search<T>, SearchParseError> {
-        let mut query_iter = query.split(',');
+        let mut searches = Vec::new();
+
+        for term in split_searches(query) {
+            let search = match parse_comparison(&term, false)? {
+                Comparison::Partial(t) => Search::Partial(t),
+                Comparison::Exact(t) => Search::Exact(t),
+                Comparison::Not(t) => Search::Not(t),
+                Comparison::GreaterThan(t) => Search::GreaterThan(t),
+                Comparison::LessThan(t) => Search::LessThan(t),
+                Comparison::Between(low, high) => Search::Between(low, high),
+                Comparison::In(t) => Search::In(t),
+                Comparison::IsNull | Comparison::IsNotNull => {
+                    return Err(SearchParseError::Kind(term.trim().to_owned()))
+                }
+            };
+
+            searches.push(search);
+        }
 
-        let kind = query_iter.next().map(|s| s.trim());
-        let term = query_iter.next().map(|s| s.trim());
+        if searches.len() == 1 {
+            Ok(searches.remove(0))
+        } else {
+            Ok(Search::Or(searches))
+        }
+    }
+}
+
+impl<T: Clone> Search<T> {
+    /// Turns the search into a diesel filter, using `compare` for each comparison in it
+    ///
+    /// `compare` is usually made with `column_filter!`. The comparisons of an `Or` are
+    /// joined with `OR`. There is no filter for `NoSearch`.
+    pub fn to_filter<QS, F>(&self, compare: F) -> Option<BoxedFilter<QS>>
+    where
+        QS: 'static,
+        F: Fn(Comparison<T>) -> BoxedFilter<QS>,
+    {
+        any(self.comparisons().into_iter().map(compare))
+    }
 
-        match (kind, term) {
-            (Some("partial"), Some(s)) => s
-                .trim()
-                .parse()
-                .map(|p| Search::Partial(p))
-                .map_err(|_| SearchParseError::Term(s.to_owned())),
-            (Some("exact"), Some(s)) => s
-                .trim()
-                .parse()
-                .map(|p| Search::Exact(p))
-                .map_err(|_| SearchParseError::Term(s.to_owned())),
-            (Some("partial"), None) => Err(SearchParseError::Term("".to_owned())),
-            (Some("exact"), None) => Err(SearchParseError::Term("".to_owned())),
-            (Some(k), Some(_)) => Err(SearchParseError::Kind(k.to_owned())),
-            (Some(k), None) => Err(SearchParseError::Kind(k.to_owned())),
-            (None, Some(_)) => Err(SearchParseError::Kind("".to_owned())),
-            (None, None) => Err(SearchParseError::Kind("".to_owned())),
+    fn comparisons(&self) -> Vec<Comparison<T>> {
+        match self {
+            Search::Partial(t) => vec![Comparison::Partial(t.clone())],
+            Search::Exact(t) => vec![Comparison::Exact(t.clone())],
+            Search::Not(t) => vec![Comparison::Not(t.clone())],
+            Search::GreaterThan(t) => vec![Comparison::GreaterThan(t.clone())],
+            Search::LessThan(t) => vec![Comparison::LessThan(t.clone())],
+            Search::Between(low, high) => vec![Comparison::Between(low.clone(), high.clone())],
+            Search::In(t) => vec![Comparison::In(t.clone())],
+            Search::Or(searches) => searches.iter().flat_map(|s| s.comparisons()).collect(),
+            Search::NoSearch => Vec::new(),
         }
     }
 }
@@ -106,6 +377,76 @@ fn parse_search_empty_string_fails() {
     assert_eq!(s, Err(SearchParseError::Kind("".to_owned())));
 }
 
+#[test]
+fn parse_search_comparisons_work() {
+    assert_eq!(
+        Search::from_query("not, acid"),
+        Ok(Search::Not("acid".to_owned()))
+    );
+    assert_eq!(Search::from_query("gt,5"), Ok(Search::GreaterThan(5)));
+    assert_eq!(Search::from_query("lt,5"), Ok(Search::LessThan(5)));
+}
+
+#[test]
+fn parse_search_between_works() {
+    let s = Search::from_query("between, 1, 10");
+    assert_eq!(s, Ok(Search::Between(1, 10)));
+}
+
+#[test]
+fn parse_search_between_needs_two_terms() {
+    let s: Result<Search<u64>, _> = Search::from_query("between,1,2,3");
+    assert_eq!(s, Err(SearchParseError::Term("1,2,3".to_owned())));
+}
+
+#[test]
+fn parse_search_in_works() {
+    let s = Search::from_query("in,1, 2,3");
+    assert_eq!(s, Ok(Search::In(vec![1, 2, 3])));
+}
+
+#[test]
+fn parse_search_in_with_bad_term_fails() {
+    let s: Result<Search<u64>, _> = Search::from_query("in,1,two");
+    assert_eq!(s, Err(SearchParseError::Term("two".to_owned())));
+}
+
+#[test]
+fn parse_search_or_works() {
+    let s = Search::from_query("exact,acid|partial,base");
+    assert_eq!(
+        s,
+        Ok(Search::Or(vec![
+            Search::Exact("acid".to_owned()),
+            Search::Partial("base".to_owned())
+        ]))
+    );
+}
+
+#[test]
+fn parse_search_escaped_bar_is_part_of_the_term() {
+    let s = Search::from_query("exact,a\\|b|partial,c");
+    assert_eq!(
+        s,
+        Ok(Search::Or(vec![
+            Search::Exact("a|b".to_owned()),
+            Search::Partial("c".to_owned())
+        ]))
+    );
+}
+
+#[test]
+fn parse_search_ignores_terms_after_a_second_comma() {
+    let s = Search::from_query("partial,a,b");
+    assert_eq!(s, Ok(Search::Partial("a".to_owned())));
+}
+
+#[test]
+fn parse_search_or_with_bad_search_fails() {
+    let s: Result<Search<String>, _> = Search::from_query("exact,acid|none");
+    assert_eq!(s, Err(SearchParseError::Kind("none".to_owned())));
+}
+
 /// Search fo a field that can be null
 ///
 /// This could be done as a `Search<Option>`, but then the
@@ -130,6 +471,24 @@ pub enum NullableSearch<T> {
     /// Field is not null and exactly matches
     Exact(T),
 
+    /// Field is null or does not exactly match
+    Not(T),
+
+    /// Field is not null and greater than the term
+    GreaterThan(T),
+
+    /// Field is not null and less than the term
+    LessThan(T),
+
+    /// Field is not null and between the two terms, including them
+    Between(T, T),
+
+    /// Field is not null and exactly matches one of the terms
+    In(Vec<T>),
+
+    /// Any of the searches match
+    Or(Vec<NullableSearch<T>>),
+
     /// Field is not null
     /// (`Some` matches Rust terminology better than `NonNull` or similar)
     Some,
@@ -144,37 +503,58 @@ pub enum NullableSearch<T> {
 
 impl<T: std::str::FromStr> NullableSearch<T> {
     pub fn from_query(query: &str) -> Result<NullableSearch<T>, SearchParseError> {
-        let mut query_iter = query.split(',');
-
-        let kind = query_iter.next().map(|s| s.trim());
-        let term = query_iter.next().map(|s| s.trim());
-
-        match (kind, term) {
-            (Some("partial"), Some(s)) => s
-                .trim()
-                .parse()
-                .map(|p| NullableSearch::Partial(p))
-                .map_err(|_| SearchParseError::Term(s.to_owned())),
-            (Some("exact"), Some(s)) => s
-                .trim()
-                .parse()
-                .map(|p| NullableSearch::Exact(p))
-                .map_err(|_| SearchParseError::Term(s.to_owned())),
-
-            (Some("some"), None) => Ok(NullableSearch::Some),
-            (Some("none"), None) => Ok(NullableSearch::None),
-
-            (Some("partial"), None) => Err(SearchParseError::Term("".to_owned())),
-            (Some("exact"), None) => Err(SearchParseError::Term("".to_owned())),
+        let mut searches = Vec::new();
+
+        for term in split_searches(query) {
+            let search = match parse_comparison(&term, true)? {
+                Comparison::Partial(t) => NullableSearch::Partial(t),
+                Comparison::Exact(t) => NullableSearch::Exact(t),
+                Comparison::Not(t) => NullableSearch::Not(t),
+                Comparison::GreaterThan(t) => NullableSearch::GreaterThan(t),
+                Comparison::LessThan(t) => NullableSearch::LessThan(t),
+                Comparison::Between(low, high) => NullableSearch::Between(low, high),
+                Comparison::In(t) => NullableSearch::In(t),
+                Comparison::IsNotNull => NullableSearch::Some,
+                Comparison::IsNull => NullableSearch::None,
+            };
+
+            searches.push(search);
+        }
 
-            (Some("some"), Some(s)) => Err(SearchParseError::Term(s.to_owned())),
-            (Some("none"), Some(s)) => Err(SearchParseError::Term(s.to_owned())),
+        if searches.len() == 1 {
+            Ok(searches.remove(0))
+        } else {
+            Ok(NullableSearch::Or(searches))
+        }
+    }
+}
 
-            (Some(k), Some(_)) => Err(SearchParseError::Kind(k.to_owned())),
-            (Some(k), None) => Err(SearchParseError::Kind(k.to_owned())),
+impl<T: Clone> NullableSearch<T> {
+    /// Turns the search into a diesel filter, like `Search::to_filter`
+    pub fn to_filter<QS, F>(&self, compare: F) -> Option<BoxedFilter<QS>>
+    where
+        QS: 'static,
+        F: Fn(Comparison<T>) -> BoxedFilter<QS>,
+    {
+        any(self.comparisons().into_iter().map(compare))
+    }
 
-            (None, Some(_)) => Err(SearchParseError::Kind("".to_owned())),
-            (None, None) => Err(SearchParseError::Kind("".to_owned())),
+    fn comparisons(&self) -> Vec<Comparison<T>> {
+        match self {
+            NullableSearch::Partial(t) => vec![Comparison::Partial(t.clone())],
+            NullableSearch::Exact(t) => vec![Comparison::Exact(t.clone())],
+            // `NOT` is never true for null, so null has to be asked for separately
+            NullableSearch::Not(t) => vec![Comparison::Not(t.clone()), Comparison::IsNull],
+            NullableSearch::GreaterThan(t) => vec![Comparison::GreaterThan(t.clone())],
+            NullableSearch::LessThan(t) => vec![Comparison::LessThan(t.clone())],
+            NullableSearch::Between(low, high) => {
+                vec![Comparison::Between(low.clone(), high.clone())]
+            }
+            NullableSearch::In(t) => vec![Comparison::In(t.clone())],
+            NullableSearch::Or(searches) => searches.iter().flat_map(|s| s.comparisons()).collect(),
+            NullableSearch::Some => vec![Comparison::IsNotNull],
+            NullableSearch::None => vec![Comparison::IsNull],
+            NullableSearch::NoSearch => Vec::new(),
         }
     }
 }
@@ -250,3 +630,352 @@ fn parse_nullable_search_empty_string_fails() {
     let s: Result<NullableSearch<String>, _> = NullableSearch::from_query("");
     assert_eq!(s, Err(SearchParseError::Kind("".to_owned())));
 }
+
+#[test]
+fn parse_nullable_search_or_works() {
+    let s = NullableSearch::from_query("none|between,1,10");
+    assert_eq!(
+        s,
+        Ok(NullableSearch::Or(vec![
+            NullableSearch::None,
+            NullableSearch::Between(1, 10)
+        ]))
+    );
+}
+
+#[test]
+fn nullable_search_not_includes_null() {
+    let s = NullableSearch::Not(5);
+    assert_eq!(
+        s.comparisons(),
+        vec![Comparison::Not(5), Comparison::IsNull]
+    );
+}
+
+#[test]
+fn or_search_flattens_comparisons() {
+    let s = Search::Or(vec![Search::Exact(1), Search::In(vec![2, 3])]);
+    assert_eq!(
+        s.comparisons(),
+        vec![Comparison::Exact(1), Comparison::In(vec![2, 3])]
+    );
+}
+
+/// Url queries that `Page` and `Shape` read, which any request can be given
+pub const RESERVED_QUERIES: &[&str] = &["limit", "offset", "sort", "expand", "fields"];
+
+/// Gives every url query to `parse`, except for the reserved ones
+///
+/// Requests read their own queries with this, usually through the `from_query` that
+/// `Searchable` derives, so a new reserved query only needs adding to `RESERVED_QUERIES`.
+pub fn parse_queries<F>(query_string: &str, mut parse: F) -> Result<(), Error>
+where
+    F: FnMut(&str, &str) -> Result<(), Error>,
+{
+    for (field, query) in form_urlencoded::parse(query_string.as_bytes()) {
+        if !RESERVED_QUERIES.contains(&field.as_ref()) {
+            parse(&field, &query)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails on any url query a request does not know, for use with `parse_queries`
+pub fn unknown_query(_field: &str, _query: &str) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Url))
+}
+
+/// The limit of a page that has every entry, which is also used when `limit` is not given
+///
+/// Lists were not paged before, so clients that do not ask for a page still get everything.
+const NO_LIMIT: u64 = i64::max_value() as u64;
+
+/// Largest `limit` that can be asked for
+pub const MAX_LIMIT: u64 = 1000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+/// Which field to sort a list by, and in which direction
+///
+/// Parsed from `field,asc` or `field,desc`. The direction defaults to ascending when
+/// it is left out. `F` is the list's own set of fields that it can be sorted by.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sort<F> {
+    pub field: F,
+    pub order: SortOrder,
+}
+
+impl<F: std::str::FromStr> Sort<F> {
+    pub fn from_query(query: &str) -> Result<Sort<F>, SearchParseError> {
+        let mut query_iter = query.split(',');
+
+        let field = query_iter.next().map(|s| s.trim()).unwrap_or("");
+        let order = query_iter.next().map(|s| s.trim());
+
+        let field = field
+            .parse()
+            .map_err(|_| SearchParseError::Sort(field.to_owned()))?;
+
+        let order = match order {
+            None | Some("asc") => SortOrder::Ascending,
+            Some("desc") => SortOrder::Descending,
+            Some(o) => return Err(SearchParseError::Sort(o.to_owned())),
+        };
+
+        match query_iter.next() {
+            Some(s) => Err(SearchParseError::Sort(s.to_owned())),
+            None => Ok(Sort { field, order }),
+        }
+    }
+}
+
+/// Orders a boxed query by the column that a list's sort field stands for
+///
+/// `sort_query!(query, sort, { UserSortField::Id => users_schema::id, ... })` orders
+/// `query` by the column given for `sort.field`, in `sort.order`. Every field of the
+/// list needs a column, so a new field cannot be forgotten.
+#[macro_export]
+macro_rules! sort_query {
+    ($query:expr, $sort:expr, { $($field:path => $column:expr),+ $(,)? }) => {{
+        let sort: $crate::search::Sort<_> = $sort;
+
+        match sort.field {
+            $(
+                $field => match sort.order {
+                    $crate::search::SortOrder::Ascending => ::diesel::QueryDsl::order(
+                        $query,
+                        ::diesel::ExpressionMethods::asc($column),
+                    ),
+                    $crate::search::SortOrder::Descending => ::diesel::QueryDsl::order(
+                        $query,
+                        ::diesel::ExpressionMethods::desc($column),
+                    ),
+                },
+            )+
+        }
+    }};
+}
+
+/// The part of a list to return, and the order to return it in
+///
+/// Every list takes `limit`, `offset` and `sort` in its url queries, next to its
+/// searches. The rest of the queries are kept so that the link to the next page
+/// searches for the same thing.
+#[derive(Debug, PartialEq)]
+pub struct Page<F> {
+    pub limit: u64,
+    pub offset: u64,
+    pub sort: Option<Sort<F>>,
+    queries: Vec<(String, String)>,
+}
+
+impl<F: std::str::FromStr> Page<F> {
+    /// Reads the page from a raw query string, ignoring everything but the page fields
+    pub fn from_query(query_string: &str) -> Result<Page<F>, SearchParseError> {
+        let mut page = Page {
+            limit: NO_LIMIT,
+            offset: 0,
+            sort: None,
+            queries: Vec::new(),
+        };
+
+        for (field, query) in form_urlencoded::parse(query_string.as_bytes()) {
+            match field.as_ref() {
+                "limit" => {
+                    page.limit = match query.trim().parse() {
+                        Ok(limit) if limit > 0 && limit <= MAX_LIMIT => limit,
+                        _ => return Err(SearchParseError::Page(query.to_string())),
+                    }
+                }
+                "offset" => {
+                    page.offset = query
+                        .trim()
+                        .parse()
+                        .map_err(|_| SearchParseError::Page(query.to_string()))?
+                }
+                "sort" => {
+                    page.sort = Some(Sort::from_query(query.as_ref())?);
+                    page.queries.push((field.into_owned(), query.into_owned()));
+                }
+                _ => page.queries.push((field.into_owned(), query.into_owned())),
+            }
+        }
+
+        Ok(page)
+    }
+}
+
+impl<F> Page<F> {
+    /// A page with every entry, for lists that are used internally
+    pub fn all() -> Page<F> {
+        Page {
+            limit: NO_LIMIT,
+            offset: 0,
+            sort: None,
+            queries: Vec::new(),
+        }
+    }
+
+    /// The same page without a limit, for when every entry is needed
+    pub fn unlimited(self) -> Page<F> {
+        Page {
+            limit: NO_LIMIT,
+            ..self
+        }
+    }
+
+    /// A link to the next page, if there are entries after this one
+    ///
+    /// The link only has the url queries, so it is relative to the list's url.
+    pub fn next(&self, total: u64) -> Option<String> {
+        let next_offset = self.offset.saturating_add(self.limit);
+
+        if next_offset >= total {
+            return None;
+        }
+
+        let queries = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(self.queries.iter())
+            .append_pair("limit", &self.limit.to_string())
+            .append_pair("offset", &next_offset.to_string())
+            .finish();
+
+        Some(format!("?{}", queries))
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TestSortField {
+    Name,
+}
+
+#[cfg(test)]
+impl std::str::FromStr for TestSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TestSortField, String> {
+        match s {
+            "name" => Ok(TestSortField::Name),
+            _ => Err(s.to_owned()),
+        }
+    }
+}
+
+#[test]
+fn parse_sort_defaults_to_ascending() {
+    let s = Sort::from_query(" name ");
+    assert_eq!(
+        s,
+        Ok(Sort {
+            field: TestSortField::Name,
+            order: SortOrder::Ascending
+        })
+    );
+}
+
+#[test]
+fn parse_sort_descending_works() {
+    let s = Sort::from_query("name, desc");
+    assert_eq!(
+        s,
+        Ok(Sort {
+            field: TestSortField::Name,
+            order: SortOrder::Descending
+        })
+    );
+}
+
+#[test]
+fn parse_sort_unknown_field_fails() {
+    let s: Result<Sort<TestSortField>, _> = Sort::from_query("email,asc");
+    assert_eq!(s, Err(SearchParseError::Sort("email".to_owned())));
+}
+
+#[test]
+fn parse_sort_unknown_order_fails() {
+    let s: Result<Sort<TestSortField>, _> = Sort::from_query("name,up");
+    assert_eq!(s, Err(SearchParseError::Sort("up".to_owned())));
+}
+
+#[test]
+fn parse_queries_skips_reserved_queries() {
+    let mut fields = Vec::new();
+
+    let query_string = "name=a&limit=5&sort=name&expand=user&fields=id&q=b";
+
+    let parsed = parse_queries(query_string, |f, q| {
+        fields.push((f.to_owned(), q.to_owned()));
+        Ok(())
+    });
+
+    assert!(parsed.is_ok());
+    assert_eq!(
+        fields,
+        vec![
+            ("name".to_owned(), "a".to_owned()),
+            ("q".to_owned(), "b".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn parse_queries_stops_at_an_unknown_query() {
+    let mut parsed = Vec::new();
+
+    let result = parse_queries("fields=id&name=a&q=b", |f, q| {
+        parsed.push(f.to_owned());
+        unknown_query(f, q)
+    });
+
+    assert!(result.is_err());
+    assert_eq!(parsed, vec!["name".to_owned()]);
+}
+
+#[test]
+fn parse_page_defaults_work() {
+    let page: Page<TestSortField> = Page::from_query("name=partial,a").unwrap();
+    assert_eq!(page.limit, NO_LIMIT);
+    assert_eq!(page.offset, 0);
+    assert_eq!(page.sort, None);
+    assert_eq!(page.next(1000), None);
+}
+
+#[test]
+fn parse_page_limit_over_max_fails() {
+    let page: Result<Page<TestSortField>, _> = Page::from_query("limit=5000");
+    assert_eq!(page, Err(SearchParseError::Page("5000".to_owned())));
+}
+
+#[test]
+fn parse_page_zero_limit_fails() {
+    let page: Result<Page<TestSortField>, _> = Page::from_query("limit=0");
+    assert_eq!(page, Err(SearchParseError::Page("0".to_owned())));
+}
+
+#[test]
+fn page_next_keeps_other_queries() {
+    let page: Page<TestSortField> =
+        Page::from_query("name=partial,a&limit=10&offset=10&sort=name,desc").unwrap();
+    assert_eq!(
+        page.next(25),
+        Some("?name=partial%2Ca&sort=name%2Cdesc&limit=10&offset=20".to_owned())
+    );
+}
+
+#[test]
+fn page_next_is_none_on_last_page() {
+    let page: Page<TestSortField> = Page::from_query("limit=10&offset=20").unwrap();
+    assert_eq!(page.next(25), None);
+}
+
+#[test]
+fn unlimited_page_has_no_next() {
+    let page: Page<TestSortField> = Page::from_query("offset=5").unwrap().unlimited();
+    assert_eq!(page.next(1000), None);
+}
@@ -0,0 +1,138 @@
+use diesel::Queryable;
+
+use rouille::router;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use chrono::NaiveDateTime;
+
+use log::warn;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::search::Page;
+
+use super::schema::service_accounts;
+
+/// A service account, without its key hash
+#[derive(Queryable, Serialize, Deserialize, Debug)]
+pub struct ServiceAccount {
+    pub id: u64,
+    pub user_id: u64,
+    pub name: String,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "service_accounts"]
+pub struct NewRawServiceAccount {
+    pub user_id: u64,
+    pub name: String,
+    pub key_hash: String,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewServiceAccount {
+    pub name: String,
+    pub permissions: Vec<u64>,
+}
+
+/// The key handed back when a service account is created
+///
+/// Only a hash of the key is stored, so this is the only time it is ever seen.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceAccountKey {
+    pub id: u64,
+    pub user_id: u64,
+    pub name: String,
+    pub key: String,
+}
+
+/// A page of service accounts
+///
+/// `total` counts every service account, and `next` links to the next page.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceAccountList {
+    pub service_accounts: Vec<ServiceAccount>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+/// Fields that a list of service accounts can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ServiceAccountSortField {
+    Id,
+    UserId,
+    Name,
+    Created,
+}
+
+impl std::str::FromStr for ServiceAccountSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ServiceAccountSortField, String> {
+        match s {
+            "id" => Ok(ServiceAccountSortField::Id),
+            "user_id" => Ok(ServiceAccountSortField::UserId),
+            "name" => Ok(ServiceAccountSortField::Name),
+            "created" => Ok(ServiceAccountSortField::Created),
+            _ => Err(format!("Cannot sort service accounts by {}", s)),
+        }
+    }
+}
+
+pub enum ServiceAccountRequest {
+    GetServiceAccounts(Page<ServiceAccountSortField>), //list every service account
+    CreateServiceAccount(NewServiceAccount),    //create an account and hand back its key
+    DeleteServiceAccount(u64),                  //revoke the key of the account with the given id
+}
+
+impl ServiceAccountRequest {
+    pub fn from_rouille(request: &rouille::Request) -> Result<ServiceAccountRequest, Error> {
+        router!(request,
+            (GET) (/) => {
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(ServiceAccountRequest::GetServiceAccounts(page))
+            },
+
+            (POST) (/) => {
+                let request_body = request.data()
+                    .ok_or(Error::new(ErrorKind::Body))?;
+                let new_service_account: NewServiceAccount =
+                    serde_json::from_reader(request_body)?;
+
+                Ok(ServiceAccountRequest::CreateServiceAccount(new_service_account))
+            },
+
+            (DELETE) (/{id: u64}) => {
+                Ok(ServiceAccountRequest::DeleteServiceAccount(id))
+            },
+
+            _ => {
+                warn!("Could not create a service account request for the given rouille request");
+                Err(Error::new(ErrorKind::NotFound))
+            }
+        ) //end router
+    }
+}
+
+pub enum ServiceAccountResponse {
+    OneServiceAccountKey(ServiceAccountKey),
+    ManyServiceAccounts(ServiceAccountList),
+    NoResponse,
+}
+
+impl ServiceAccountResponse {
+    pub fn to_rouille(self) -> rouille::Response {
+        match self {
+            ServiceAccountResponse::OneServiceAccountKey(key) => rouille::Response::json(&key),
+            ServiceAccountResponse::ManyServiceAccounts(service_accounts) => {
+                rouille::Response::json(&service_accounts)
+            }
+            ServiceAccountResponse::NoResponse => rouille::Response::empty_204(),
+        }
+    }
+}
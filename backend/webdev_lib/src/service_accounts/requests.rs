@@ -0,0 +1,210 @@
+use diesel;
+use diesel::mysql::types::Unsigned;
+use diesel::mysql::Mysql;
+use diesel::mysql::MysqlConnection;
+use diesel::sql_types;
+use diesel::Connection;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::RunQueryDsl;
+
+use chrono::offset::Local;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use log::trace;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::permissions::models::{Permission, Principal};
+use crate::permissions::requests::check_to_run;
+
+use crate::search::{Page, Sort, SortOrder};
+
+use crate::sessions::requests::hash_token;
+
+use crate::users::models::NewUser;
+use crate::users::requests::create_user;
+
+use super::models::{
+    NewRawServiceAccount, NewServiceAccount, ServiceAccount, ServiceAccountKey, ServiceAccountList,
+    ServiceAccountRequest, ServiceAccountResponse, ServiceAccountSortField,
+};
+
+use super::schema::service_accounts as service_accounts_schema;
+
+const API_KEY_LENGTH: usize = 48;
+
+pub fn handle_service_account(
+    request: ServiceAccountRequest,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<ServiceAccountResponse, Error> {
+    match request {
+        ServiceAccountRequest::GetServiceAccounts(page) => {
+            check_to_run(requesting_user, Permission::GetServiceAccounts)?;
+            get_service_accounts(page, database_connection)
+                .map(|s| ServiceAccountResponse::ManyServiceAccounts(s))
+        }
+        ServiceAccountRequest::CreateServiceAccount(service_account) => {
+            check_to_run(requesting_user, Permission::CreateServiceAccounts)?;
+
+            // Creating an account grants its permissions, so it has the same requirement
+            if !service_account.permissions.is_empty() {
+                check_to_run(requesting_user, Permission::CreateUserPermission)?;
+            }
+
+            create_service_account(service_account, database_connection)
+                .map(|k| ServiceAccountResponse::OneServiceAccountKey(k))
+        }
+        ServiceAccountRequest::DeleteServiceAccount(id) => {
+            check_to_run(requesting_user, Permission::DeleteServiceAccounts)?;
+            delete_service_account(id, database_connection)
+                .map(|_| ServiceAccountResponse::NoResponse)
+        }
+    }
+}
+
+/// Finds the user behind a service account's API key
+pub fn validate_api_key(
+    api_key: &str,
+    database_connection: &MysqlConnection,
+) -> Result<u64, Error> {
+    let mut found_users = service_accounts_schema::table
+        .select(service_accounts_schema::user_id)
+        .filter(service_accounts_schema::key_hash.eq(hash_token(api_key)))
+        .load::<u64>(database_connection)?;
+
+    match found_users.pop() {
+        Some(user_id) => Ok(user_id),
+        None => Err(Error::new(ErrorKind::InvalidApiKey)),
+    }
+}
+
+pub(crate) fn get_service_accounts(
+    page: Page<ServiceAccountSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<ServiceAccountList, Error> {
+    let total = service_accounts_schema::table
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: ServiceAccountSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let service_accounts_query = service_accounts_schema::table
+        .select((
+            service_accounts_schema::id,
+            service_accounts_schema::user_id,
+            service_accounts_schema::name,
+            service_accounts_schema::created,
+        ))
+        .into_boxed::<Mysql>();
+
+    let service_accounts_query = crate::sort_query!(service_accounts_query, sort, {
+        ServiceAccountSortField::Id => service_accounts_schema::id,
+        ServiceAccountSortField::UserId => service_accounts_schema::user_id,
+        ServiceAccountSortField::Name => service_accounts_schema::name,
+        ServiceAccountSortField::Created => service_accounts_schema::created,
+    });
+
+    let service_accounts = service_accounts_query
+        .then_order_by(service_accounts_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<ServiceAccount>(database_connection)?;
+
+    Ok(ServiceAccountList {
+        service_accounts: service_accounts,
+        total: total,
+        next: page.next(total),
+    })
+}
+
+/// Creates a service account along with the user that holds its permissions
+///
+/// The user is given an `.invalid` email address, so it can never be logged into
+/// through the auth provider.
+pub(crate) fn create_service_account(
+    service_account: NewServiceAccount,
+    database_connection: &MysqlConnection,
+) -> Result<ServiceAccountKey, Error> {
+    database_connection.transaction::<_, Error, _>(|| {
+        let user = create_user(
+            NewUser {
+                first_name: service_account.name.clone(),
+                last_name: "Service Account".to_owned(),
+                banner_id: None,
+                email: format!("{}@service-account.invalid", service_account.name),
+                department: None,
+                class_year: None,
+                affiliation: None,
+                phone: None,
+                emergency_contact: None,
+                permissions: service_account.permissions,
+            },
+            database_connection,
+        )?;
+
+        let api_key: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(API_KEY_LENGTH)
+            .collect();
+
+        let new_raw_service_account = NewRawServiceAccount {
+            user_id: user.id,
+            name: service_account.name,
+            key_hash: hash_token(&api_key),
+            created: Local::now().naive_local(),
+        };
+
+        diesel::insert_into(service_accounts_schema::table)
+            .values(&new_raw_service_account)
+            .execute(database_connection)?;
+
+        no_arg_sql_function!(last_insert_id, Unsigned<sql_types::Bigint>);
+
+        let mut inserted_ids = service_accounts_schema::table
+            .select(service_accounts_schema::id)
+            .filter(service_accounts_schema::id.eq(last_insert_id))
+            .load::<u64>(database_connection)?;
+
+        trace!(
+            "Created service account {} for user {}",
+            new_raw_service_account.name,
+            user.id
+        );
+
+        match inserted_ids.pop() {
+            Some(id) => Ok(ServiceAccountKey {
+                id: id,
+                user_id: user.id,
+                name: new_raw_service_account.name,
+                key: api_key,
+            }),
+            None => Err(Error::new(ErrorKind::Database)),
+        }
+    })
+}
+
+/// Revokes a service account's key
+///
+/// The user behind the account is kept so that anything it created still has an owner.
+pub(crate) fn delete_service_account(
+    id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    let deleted = diesel::delete(
+        service_accounts_schema::table.filter(service_accounts_schema::id.eq(id)),
+    )
+    .execute(database_connection)?;
+
+    if deleted == 0 {
+        Err(Error::new(ErrorKind::NotFound))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+use crate::users::schema::users;
+
+table! {
+    service_accounts (id) {
+        id -> Unsigned<Bigint>,
+        user_id -> Unsigned<Bigint>,
+        name -> Varchar,
+        key_hash -> Varchar,
+        created -> Timestamp,
+    }
+}
+
+joinable!(service_accounts -> users (user_id));
+allow_tables_to_appear_in_same_query!(service_accounts, users);
@@ -0,0 +1,89 @@
+use diesel::Queryable;
+
+use rouille::router;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use chrono::NaiveDateTime;
+
+use log::warn;
+
+use crate::errors::{Error, ErrorKind};
+
+use super::schema::sessions;
+
+#[derive(Queryable, Debug)]
+pub struct RawSession {
+    pub id: u64,
+    pub user_id: u64,
+    pub token_hash: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "sessions"]
+pub struct NewRawSession {
+    pub user_id: u64,
+    pub token_hash: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+/// The token handed back to the client when a session is created
+///
+/// Only a hash of the token is stored, so this is the only time it is ever seen.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionToken {
+    pub user_id: u64,
+    pub token: String,
+    pub expires: NaiveDateTime,
+}
+
+pub enum SessionRequest {
+    CreateSession,            //exchange the verified id token for a session
+    DeleteSession(String),    //log out of the session with the given token
+    RevokeUserSessions(u64),  //log the user out everywhere
+}
+
+impl SessionRequest {
+    pub fn from_rouille(request: &rouille::Request) -> Result<SessionRequest, Error> {
+        router!(request,
+            (POST) (/) => {
+                Ok(SessionRequest::CreateSession)
+            },
+
+            (DELETE) (/) => {
+                if let Some(session_token) = request.header("session_token") {
+                    Ok(SessionRequest::DeleteSession(session_token.to_string()))
+                } else {
+                    Err(Error::new(ErrorKind::InvalidSession))
+                }
+            },
+
+            (DELETE) (/user/{user_id: u64}) => {
+                Ok(SessionRequest::RevokeUserSessions(user_id))
+            },
+
+            _ => {
+                warn!("Could not create a session request for the given rouille request");
+                Err(Error::new(ErrorKind::NotFound))
+            }
+        ) //end router
+    }
+}
+
+pub enum SessionResponse {
+    OneSession(SessionToken),
+    NoResponse,
+}
+
+impl SessionResponse {
+    pub fn to_rouille(self) -> rouille::Response {
+        match self {
+            SessionResponse::OneSession(session) => rouille::Response::json(&session),
+            SessionResponse::NoResponse => rouille::Response::empty_204(),
+        }
+    }
+}
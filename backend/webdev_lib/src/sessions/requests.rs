@@ -0,0 +1,168 @@
+use std::env;
+
+use diesel;
+use diesel::mysql::MysqlConnection;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::RunQueryDsl;
+
+use chrono::offset::Local;
+use chrono::Duration;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use sha2::{Digest, Sha256};
+
+use log::trace;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::permissions::models::{Credential, Permission, Principal};
+use crate::permissions::requests::check_to_run;
+
+use super::models::{NewRawSession, SessionRequest, SessionResponse, SessionToken};
+
+use super::schema::sessions as sessions_schema;
+
+/// How long a session lasts if `SESSION_LIFETIME_HOURS` is not set
+const DEFAULT_SESSION_LIFETIME_HOURS: i64 = 12;
+
+const SESSION_TOKEN_LENGTH: usize = 48;
+
+pub fn handle_session(
+    request: SessionRequest,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<SessionResponse, Error> {
+    match request {
+        // Only a fresh id token can start a session, so sessions and API keys
+        // cannot be used to keep making new ones
+        SessionRequest::CreateSession => {
+            match (requesting_user.user_id, requesting_user.credential) {
+                (Some(user_id), Some(Credential::IdToken)) => {
+                    create_session(user_id, database_connection)
+                        .map(|s| SessionResponse::OneSession(s))
+                }
+                _ => Err(Error::new(ErrorKind::PermissionDenied)),
+            }
+        }
+        SessionRequest::DeleteSession(session_token) => {
+            delete_session(&session_token, database_connection)
+                .map(|_| SessionResponse::NoResponse)
+        }
+        SessionRequest::RevokeUserSessions(user_id) => {
+            // Anyone can log themselves out everywhere
+            if requesting_user.user_id != Some(user_id) {
+                check_to_run(requesting_user, Permission::RevokeSessions)?;
+            }
+
+            revoke_user_sessions(user_id, database_connection)
+                .map(|_| SessionResponse::NoResponse)
+        }
+    }
+}
+
+pub(crate) fn hash_token(session_token: &str) -> String {
+    format!("{:x}", Sha256::digest(session_token.as_bytes()))
+}
+
+fn session_lifetime() -> Duration {
+    let hours = env::var("SESSION_LIFETIME_HOURS")
+        .ok()
+        .and_then(|h| h.parse().ok())
+        .unwrap_or(DEFAULT_SESSION_LIFETIME_HOURS);
+
+    Duration::hours(hours)
+}
+
+/// Finds the user that owns a session
+///
+/// This only touches the sessions table, so it is much cheaper than
+/// verifying an id token with the auth provider.
+pub fn validate_session(
+    session_token: &str,
+    database_connection: &MysqlConnection,
+) -> Result<u64, Error> {
+    let mut found_users = sessions_schema::table
+        .select(sessions_schema::user_id)
+        .filter(sessions_schema::token_hash.eq(hash_token(session_token)))
+        .filter(sessions_schema::expires.gt(Local::now().naive_local()))
+        .load::<u64>(database_connection)?;
+
+    match found_users.pop() {
+        Some(user_id) => Ok(user_id),
+        None => Err(Error::new(ErrorKind::InvalidSession)),
+    }
+}
+
+pub(crate) fn create_session(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<SessionToken, Error> {
+    let now = Local::now().naive_local();
+
+    // Clean up old sessions while we are here
+    diesel::delete(
+        sessions_schema::table
+            .filter(sessions_schema::user_id.eq(user_id))
+            .filter(sessions_schema::expires.le(now)),
+    )
+    .execute(database_connection)?;
+
+    let session_token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_TOKEN_LENGTH)
+        .collect();
+
+    let new_raw_session = NewRawSession {
+        user_id: user_id,
+        token_hash: hash_token(&session_token),
+        created: now,
+        expires: now + session_lifetime(),
+    };
+
+    let expires = new_raw_session.expires;
+
+    diesel::insert_into(sessions_schema::table)
+        .values(new_raw_session)
+        .execute(database_connection)?;
+
+    trace!("Created session for user {} expiring at {}", user_id, expires);
+
+    Ok(SessionToken {
+        user_id: user_id,
+        token: session_token,
+        expires: expires,
+    })
+}
+
+pub(crate) fn delete_session(
+    session_token: &str,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    diesel::delete(
+        sessions_schema::table.filter(sessions_schema::token_hash.eq(hash_token(session_token))),
+    )
+    .execute(database_connection)?;
+
+    Ok(())
+}
+
+pub(crate) fn revoke_user_sessions(
+    user_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    diesel::delete(sessions_schema::table.filter(sessions_schema::user_id.eq(user_id)))
+        .execute(database_connection)?;
+
+    Ok(())
+}
+
+#[test]
+fn hash_token_is_hex_sha256() {
+    assert_eq!(
+        hash_token("hello"),
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+}
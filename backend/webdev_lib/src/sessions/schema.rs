@@ -0,0 +1,14 @@
+use crate::users::schema::users;
+
+table! {
+    sessions (id) {
+        id -> Unsigned<Bigint>,
+        user_id -> Unsigned<Bigint>,
+        token_hash -> Varchar,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
+joinable!(sessions -> users (user_id));
+allow_tables_to_appear_in_same_query!(sessions, users);
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use diesel::mysql::MysqlConnection;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use url::form_urlencoded;
+
+use log::warn;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::permissions::models::{Permission, Principal};
+use crate::permissions::requests::check_to_run;
+
+use crate::chemicals::requests::get_chemicals;
+use crate::tests::question_categories::requests::get_raw_question_categories;
+use crate::users::requests::get_users;
+
+/// The kinds of objects that can be inlined into a response
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Related {
+    User,
+    Chemical,
+    QuestionCategory,
+}
+
+/// Every name that can be expanded, and what its `{name}_id` field refers to
+const EXPANSIONS: &[(&str, Related)] = &[
+    ("actor", Related::User),
+    ("category", Related::QuestionCategory),
+    ("chemical", Related::Chemical),
+    ("creator", Related::User),
+    ("custodian", Related::User),
+    ("purchaser", Related::User),
+    ("question_category", Related::QuestionCategory),
+    ("taker", Related::User),
+    ("user", Related::User),
+];
+
+impl Related {
+    /// The permission needed to see the objects
+    fn permission(self) -> Permission {
+        match self {
+            Related::User => Permission::GetUsers,
+            Related::Chemical => Permission::GetChemical,
+            Related::QuestionCategory => Permission::GetQuestionCategories,
+        }
+    }
+
+    /// Loads the objects with the ids in one query, keyed by their id
+    fn load(
+        self,
+        ids: Vec<u64>,
+        database_connection: &MysqlConnection,
+    ) -> Result<HashMap<u64, Value>, Error> {
+        fn to_json<T: Serialize>(entities: Vec<T>) -> Vec<Value> {
+            entities
+                .into_iter()
+                .filter_map(|e| serde_json::to_value(e).ok())
+                .collect()
+        }
+
+        let objects = match self {
+            Related::User => to_json(get_users(ids, database_connection)?),
+            Related::Chemical => to_json(get_chemicals(ids, database_connection)?),
+            Related::QuestionCategory => {
+                to_json(get_raw_question_categories(ids, database_connection)?)
+            }
+        };
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|o| o.get("id").and_then(Value::as_u64).map(|id| (id, o)))
+            .collect())
+    }
+}
+
+/// How a response should be changed by the `expand` and `fields` url queries
+///
+/// `expand=purchaser,chemical` adds a `purchaser` object next to every `purchaser_id`
+/// in the response, and a `chemical` next to every `chemical_id`. `fields=id,name`
+/// keeps only those fields of each entry of a list, or of a single entity.
+#[derive(Debug, PartialEq)]
+pub struct Shape {
+    expand: Vec<(&'static str, Related)>,
+    fields: Option<Vec<String>>,
+}
+
+impl Shape {
+    /// Reads the shape from a raw query string, ignoring every other query
+    ///
+    /// Returns `None` when neither is given, so the response can be sent as is.
+    pub fn from_query(query_string: &str) -> Result<Option<Shape>, Error> {
+        let mut expand = Vec::new();
+        let mut fields: Option<Vec<String>> = None;
+
+        for (field, query) in form_urlencoded::parse(query_string.as_bytes()) {
+            match field.as_ref() {
+                "expand" => {
+                    for name in query.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                        let expansion = EXPANSIONS
+                            .iter()
+                            .find(|(expansion, _)| *expansion == name)
+                            .ok_or_else(|| Error::new(ErrorKind::Url))?;
+
+                        if !expand.contains(expansion) {
+                            expand.push(*expansion);
+                        }
+                    }
+                }
+                "fields" => fields.get_or_insert_with(Vec::new).extend(
+                    query
+                        .split(',')
+                        .map(|f| f.trim().to_owned())
+                        .filter(|f| !f.is_empty()),
+                ),
+                _ => {}
+            }
+        }
+
+        if expand.is_empty() && fields.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(Shape {
+                expand: expand,
+                fields: fields,
+            }))
+        }
+    }
+
+    /// Expands and trims a successful JSON response
+    ///
+    /// Anything else, like errors, images and CSV files, is sent as is. Expanding
+    /// needs the permission to get the expanded objects.
+    pub fn apply(
+        &self,
+        mut response: rouille::Response,
+        requesting_user: &Principal,
+        database_connection: &MysqlConnection,
+    ) -> rouille::Response {
+        let is_json = response.headers.iter().any(|(header, value)| {
+            header.eq_ignore_ascii_case("Content-Type") && value.starts_with("application/json")
+        });
+
+        if !response.is_success() || !is_json {
+            return response;
+        }
+
+        let data = std::mem::replace(&mut response.data, rouille::ResponseBody::empty());
+        let (mut reader, _) = data.into_reader_and_size();
+
+        let mut body = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut body) {
+            warn!("Could not read response to expand it: {}", e);
+            return rouille::Response::from(Error::with_source(ErrorKind::Io, Box::new(e)));
+        }
+
+        let mut value = match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Could not parse response to expand it: {}", e);
+                response.data = rouille::ResponseBody::from_data(body);
+                return response;
+            }
+        };
+
+        for &(name, related) in &self.expand {
+            if let Err(e) = expand(
+                &mut value,
+                name,
+                related,
+                requesting_user,
+                database_connection,
+            ) {
+                return rouille::Response::from(e);
+            }
+        }
+
+        if let Some(fields) = &self.fields {
+            trim(&mut value, fields);
+        }
+
+        response.data = match serde_json::to_vec(&value) {
+            Ok(shaped) => rouille::ResponseBody::from_data(shaped),
+            Err(e) => {
+                warn!("Could not write expanded response: {}", e);
+                rouille::ResponseBody::from_data(body)
+            }
+        };
+
+        response
+    }
+}
+
+/// Adds `name` next to every `{name}_id` in the value
+///
+/// Ids that no longer exist are expanded to null.
+fn expand(
+    value: &mut Value,
+    name: &str,
+    related: Related,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    check_to_run(requesting_user, related.permission())?;
+
+    let id_field = format!("{}_id", name);
+
+    let mut ids = Vec::new();
+    collect_ids(value, &id_field, &mut ids);
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    ids.sort();
+    ids.dedup();
+
+    let objects = related.load(ids, database_connection)?;
+
+    insert_objects(value, &id_field, name, &objects);
+
+    Ok(())
+}
+
+fn collect_ids(value: &Value, id_field: &str, ids: &mut Vec<u64>) {
+    match value {
+        Value::Object(object) => {
+            if let Some(id) = object.get(id_field).and_then(Value::as_u64) {
+                ids.push(id);
+            }
+
+            for field in object.values() {
+                collect_ids(field, id_field, ids);
+            }
+        }
+        Value::Array(array) => {
+            for entry in array {
+                collect_ids(entry, id_field, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn insert_objects(value: &mut Value, id_field: &str, name: &str, objects: &HashMap<u64, Value>) {
+    match value {
+        Value::Object(object) => {
+            for field in object.values_mut() {
+                insert_objects(field, id_field, name, objects);
+            }
+
+            if let Some(id) = object.get(id_field).and_then(Value::as_u64) {
+                let related = objects.get(&id).cloned().unwrap_or(Value::Null);
+                object.insert(name.to_owned(), related);
+            }
+        }
+        Value::Array(array) => {
+            for entry in array {
+                insert_objects(entry, id_field, name, objects);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Keeps only `fields` of each entry of a list, or of a single entity
+fn trim(value: &mut Value, fields: &[String]) {
+    if let Value::Object(object) = value {
+        if !is_list(object) {
+            keep_fields(object, fields);
+            return;
+        }
+    }
+
+    match value {
+        Value::Object(list) => {
+            for entries in list.values_mut() {
+                if let Value::Array(entries) = entries {
+                    keep_fields_of_entries(entries, fields);
+                }
+            }
+        }
+        Value::Array(entries) => keep_fields_of_entries(entries, fields),
+        _ => {}
+    }
+}
+
+/// Whether the object is a list, which holds a single array of entries
+///
+/// Paged lists also have `total` and `next`, which are always kept.
+fn is_list(object: &Map<String, Value>) -> bool {
+    let mut entries = object
+        .iter()
+        .filter(|(field, _)| *field != "total" && *field != "next");
+
+    match (entries.next(), entries.next()) {
+        (Some((_, Value::Array(_))), None) => true,
+        _ => false,
+    }
+}
+
+fn keep_fields_of_entries(entries: &mut [Value], fields: &[String]) {
+    for entry in entries {
+        if let Value::Object(entry) = entry {
+            keep_fields(entry, fields);
+        }
+    }
+}
+
+fn keep_fields(object: &mut Map<String, Value>, fields: &[String]) {
+    let dropped: Vec<String> = object
+        .keys()
+        .filter(|field| !fields.contains(*field))
+        .cloned()
+        .collect();
+
+    for field in dropped {
+        object.remove(&field);
+    }
+}
+
+#[test]
+fn parse_shape_reads_expand_and_fields() {
+    let shape = Shape::from_query("expand=purchaser,%20chemical&fields=id,amount&limit=5");
+
+    assert_eq!(
+        shape.ok(),
+        Some(Some(Shape {
+            expand: vec![
+                ("purchaser", Related::User),
+                ("chemical", Related::Chemical)
+            ],
+            fields: Some(vec!["id".to_owned(), "amount".to_owned()]),
+        }))
+    );
+}
+
+#[test]
+fn parse_shape_without_queries_is_none() {
+    assert_eq!(Shape::from_query("name=partial,acid").ok(), Some(None));
+}
+
+#[test]
+fn parse_shape_rejects_unknown_expansion() {
+    assert!(Shape::from_query("expand=password").is_err());
+}
+
+#[test]
+fn expanded_objects_are_added_next_to_ids() {
+    let mut value = serde_json::json!({
+        "entries": [{"id": 1, "custodian_id": 4}, {"id": 2, "custodian_id": 9}],
+        "total": 2,
+        "next": null
+    });
+
+    let mut ids = Vec::new();
+    collect_ids(&value, "custodian_id", &mut ids);
+    assert_eq!(ids, vec![4, 9]);
+
+    let mut objects = HashMap::new();
+    objects.insert(4, serde_json::json!({"id": 4, "first_name": "Ada"}));
+
+    insert_objects(&mut value, "custodian_id", "custodian", &objects);
+
+    assert_eq!(
+        value["entries"],
+        serde_json::json!([
+            {"id": 1, "custodian_id": 4, "custodian": {"id": 4, "first_name": "Ada"}},
+            {"id": 2, "custodian_id": 9, "custodian": null}
+        ])
+    );
+}
+
+#[test]
+fn trim_keeps_fields_of_list_entries() {
+    let mut value = serde_json::json!({
+        "users": [{"id": 1, "first_name": "Ada", "permissions": []}],
+        "total": 1,
+        "next": null
+    });
+
+    trim(&mut value, &["id".to_owned(), "first_name".to_owned()]);
+
+    assert_eq!(
+        value,
+        serde_json::json!({
+            "users": [{"id": 1, "first_name": "Ada"}],
+            "total": 1,
+            "next": null
+        })
+    );
+}
+
+#[test]
+fn trim_keeps_fields_of_single_entity() {
+    let mut value = serde_json::json!({"id": 3, "role_name": "Admin", "permissions": []});
+
+    trim(&mut value, &["role_name".to_owned()]);
+
+    assert_eq!(value, serde_json::json!({"role_name": "Admin"}));
+}
@@ -8,6 +8,7 @@ use diesel::RunQueryDsl;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
+use crate::permissions::models::{Permission, Principal};
 use crate::permissions::requests::check_to_run;
 
 use crate::tests::question_categories::models::{
@@ -23,35 +24,27 @@ use crate::tests::questions::schema::questions as questions_schema;
 
 pub fn handle_question_category(
     request: QuestionCategoryRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<QuestionCategoryResponse, Error> {
     match request {
         QuestionCategoryRequest::GetQuestionCategories => {
-            check_to_run(requested_user, "GetQuestionCategories", database_connection)?;
+            check_to_run(requested_user, Permission::GetQuestionCategories)?;
             get_question_categories(database_connection)
                 .map(|u| QuestionCategoryResponse::ManyQuestionCategories(u))
         }
         QuestionCategoryRequest::GetQuestionCategory(id) => {
-            check_to_run(requested_user, "GetQuestionCategories", database_connection)?;
+            check_to_run(requested_user, Permission::GetQuestionCategories)?;
             get_question_category(id, database_connection)
                 .map(|u| QuestionCategoryResponse::OneQuestionCategory(u))
         }
         QuestionCategoryRequest::CreateQuestionCategory(question_category) => {
-            check_to_run(
-                requested_user,
-                "CreateQuestionCategories",
-                database_connection,
-            )?;
+            check_to_run(requested_user, Permission::CreateQuestionCategories)?;
             create_question_category(question_category, database_connection)
                 .map(|u| QuestionCategoryResponse::OneQuestionCategory(u))
         }
         QuestionCategoryRequest::DeleteQuestionCategory(id) => {
-            check_to_run(
-                requested_user,
-                "DeleteQuestionCategories",
-                database_connection,
-            )?;
+            check_to_run(requested_user, Permission::DeleteQuestionCategories)?;
             delete_question_category(id, database_connection)
                 .map(|_| QuestionCategoryResponse::NoResponse)
         }
@@ -152,6 +145,19 @@ pub(crate) fn get_question_categories(
     })
 }
 
+/// Gets the titles of every question category with one of the ids, without their questions
+pub(crate) fn get_raw_question_categories(
+    ids: Vec<u64>,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<RawQuestionCategory>, Error> {
+    let question_categories = question_categories_schema::table
+        .filter(question_categories_schema::id.eq_any(ids))
+        .order(question_categories_schema::id.asc())
+        .load::<RawQuestionCategory>(database_connection)?;
+
+    Ok(question_categories)
+}
+
 pub(crate) fn create_question_category(
     question_category: NewQuestionCategory,
     database_connection: &MysqlConnection,
@@ -9,6 +9,8 @@ use log::warn;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
+use crate::search::{parse_queries, unknown_query, Page};
+
 use super::schema::questions;
 
 #[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
@@ -53,9 +55,32 @@ pub struct PartialQuestion {
     pub incorrect_answer_3: Option<String>,
 }
 
+/// Fields that a list of questions can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum QuestionSortField {
+    Id,
+    CategoryId,
+    Title,
+}
+
+impl std::str::FromStr for QuestionSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QuestionSortField, String> {
+        match s {
+            "id" => Ok(QuestionSortField::Id),
+            "category_id" => Ok(QuestionSortField::CategoryId),
+            "title" => Ok(QuestionSortField::Title),
+            _ => Err(format!("Cannot sort questions by {}", s)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QuestionList {
     pub questions: Vec<Question>,
+    pub total: u64,
+    pub next: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -85,7 +110,7 @@ pub struct ResponseQuestionList {
 }
 
 pub enum QuestionRequest {
-    GetQuestions,
+    GetQuestions(Option<String>, Page<QuestionSortField>),
     UpdateQuestion(u64, PartialQuestion),
     CreateQuestion(NewRawQuestion),
     DeleteQuestion(u64),
@@ -95,7 +120,19 @@ impl QuestionRequest {
     pub fn from_rouille(request: &rouille::Request) -> Result<QuestionRequest, Error> {
         router!(request,
             (GET) (/) => {
-                Ok(QuestionRequest::GetQuestions)
+                let mut text = None;
+
+                parse_queries(request.raw_query_string(), |field, query| match field {
+                    "q" => {
+                        text = Some(query.to_owned());
+                        Ok(())
+                    }
+                    _ => unknown_query(field, query),
+                })?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(QuestionRequest::GetQuestions(text, page))
             },
 
             (POST) (/) => {
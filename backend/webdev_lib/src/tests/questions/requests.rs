@@ -1,4 +1,5 @@
 use diesel;
+use diesel::mysql::Mysql;
 use diesel::mysql::MysqlConnection;
 use diesel::ExpressionMethods;
 use diesel::QueryDsl;
@@ -7,47 +8,134 @@ use diesel::RunQueryDsl;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
+use crate::permissions::models::{Permission, Principal};
 use crate::permissions::requests::check_to_run;
 
+use crate::search::{match_against, MatchAgainst, Page, Sort, SortOrder};
+
 use crate::tests::questions::models::{
-    NewRawQuestion, Question, QuestionList, QuestionRequest, QuestionResponse, PartialQuestion
+    NewRawQuestion, Question, QuestionList, QuestionRequest, QuestionResponse, QuestionSortField,
+    PartialQuestion
 };
 use crate::tests::questions::schema::questions as questions_schema;
 
 pub fn handle_question(
     request: QuestionRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<QuestionResponse, Error> {
     match request {
-        QuestionRequest::GetQuestions => {
-            check_to_run(requested_user, "GetQuestions", database_connection)?;
-            get_questions(database_connection).map(|u| QuestionResponse::ManyQuestions(u))
+        QuestionRequest::GetQuestions(text, page) => {
+            check_to_run(requested_user, Permission::GetQuestions)?;
+            get_questions(text, page, database_connection)
+                .map(|u| QuestionResponse::ManyQuestions(u))
         }
         QuestionRequest::CreateQuestion(question) => {
-            check_to_run(requested_user, "CreateQuestions", database_connection)?;
+            check_to_run(requested_user, Permission::CreateQuestions)?;
             create_question(question, database_connection).map(|u| QuestionResponse::OneQuestion(u))
         }
         QuestionRequest::DeleteQuestion(id) => {
-            check_to_run(requested_user, "DeleteQuestions", database_connection)?;
+            check_to_run(requested_user, Permission::DeleteQuestions)?;
             delete_question(id, database_connection).map(|_| QuestionResponse::NoResponse)
         }
         QuestionRequest::UpdateQuestion(id, question) => {
-            check_to_run(requested_user, "UpdateQuestions", database_connection)?;
+            check_to_run(requested_user, Permission::UpdateQuestions)?;
             update_question(id, question, database_connection)
                 .map(|_| QuestionResponse::NoResponse)
         }
     }
 }
 
-pub(crate) fn get_questions(database_connection: &MysqlConnection) -> Result<QuestionList, Error> {
-    let found_questions = questions_schema::table.load::<Question>(database_connection)?;
+/// Lists questions, with an optional free text search on their title and answers
+///
+/// Free text searches are ranked by relevance, unless they are sorted some other way.
+pub(crate) fn get_questions(
+    text: Option<String>,
+    page: Page<QuestionSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<QuestionList, Error> {
+    let total = filter_questions(&text)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let rank_by_relevance = page.sort.is_none();
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: QuestionSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let questions_query = filter_questions(&text);
+
+    let questions_query = crate::sort_query!(questions_query, sort, {
+        QuestionSortField::Id => questions_schema::id,
+        QuestionSortField::CategoryId => questions_schema::category_id,
+        QuestionSortField::Title => questions_schema::title,
+    });
+
+    let questions_query = match &text {
+        Some(text) if rank_by_relevance => questions_query.order(question_relevance(text).desc()),
+        _ => questions_query,
+    };
+
+    let found_questions = questions_query
+        .then_order_by(questions_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<Question>(database_connection)?;
 
     Ok(QuestionList {
         questions: found_questions,
+        total: total,
+        next: page.next(total),
     })
 }
 
+/// Builds a query for the questions that match the free text search, if there is one
+fn filter_questions(text: &Option<String>) -> questions_schema::BoxedQuery<'static, Mysql> {
+    let questions_query = questions_schema::table.into_boxed();
+
+    match text {
+        Some(text) => questions_query.filter(question_relevance(text).gt(0.0)),
+        None => questions_query,
+    }
+}
+
+/// How relevant a question is to a free text search
+///
+/// The columns are the ones in the `question_text` index.
+fn question_relevance(
+    text: &str,
+) -> MatchAgainst<(
+    questions_schema::title,
+    questions_schema::correct_answer,
+    questions_schema::incorrect_answer_1,
+    questions_schema::incorrect_answer_2,
+    questions_schema::incorrect_answer_3,
+)> {
+    match_against(
+        (
+            questions_schema::title,
+            questions_schema::correct_answer,
+            questions_schema::incorrect_answer_1,
+            questions_schema::incorrect_answer_2,
+            questions_schema::incorrect_answer_3,
+        ),
+        text,
+    )
+}
+
+pub(crate) fn get_question(id: u64, database_connection: &MysqlConnection) -> Result<Question, Error> {
+    let mut found_questions = questions_schema::table
+        .filter(questions_schema::id.eq(id))
+        .load::<Question>(database_connection)?;
+
+    match found_questions.pop() {
+        Some(question) => Ok(question),
+        None => Err(Error::new(ErrorKind::NotFound)),
+    }
+}
+
 pub(crate) fn create_question(
     question: NewRawQuestion,
     database_connection: &MysqlConnection,
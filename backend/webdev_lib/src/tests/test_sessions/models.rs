@@ -15,6 +15,10 @@ use log::warn;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
+use crate::search::{NullableSearch, Page, Search};
+
+use webdev_search_derive::Searchable;
+
 use crate::tests::questions::models::AnonymousQuestionList;
 use crate::tests::questions::models::ResponseQuestionList;
 
@@ -108,14 +112,38 @@ pub struct PartialTestSession {
     pub submissions_enabled: Option<bool>,
 }
 
+/// Fields that a list of test sessions can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TestSessionSortField {
+    Id,
+    TestId,
+    Name,
+}
+
+impl std::str::FromStr for TestSessionSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TestSessionSortField, String> {
+        match s {
+            "id" => Ok(TestSessionSortField::Id),
+            "test_id" => Ok(TestSessionSortField::TestId),
+            "name" => Ok(TestSessionSortField::Name),
+            _ => Err(format!("Cannot sort test sessions by {}", s)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TestSessionList {
     pub test_sessions: Vec<TestSession>,
+    pub total: u64,
+    pub next: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TestSessionRegistration {
     pub id: u64,
+    pub test_session_id: u64,
     pub taker_id: u64,
     pub registered: DateTime<Local>,
     pub opened_test: Option<DateTime<Local>>,
@@ -123,8 +151,58 @@ pub struct TestSessionRegistration {
     pub score: Option<f32>,
 }
 
+/// Searches for registrations across every test session
+///
+/// Times are written like `2019-11-01T09:00:00`.
+#[derive(Debug, Searchable)]
+#[search(table = "test_session_registrations")]
+pub struct SearchTestSessionRegistration {
+    pub test_session_id: Search<u64>,
+    pub taker_id: Search<u64>,
+    pub registered: Search<NaiveDateTime>,
+    pub opened_test: NullableSearch<NaiveDateTime>,
+    pub submitted_test: NullableSearch<NaiveDateTime>,
+    pub score: NullableSearch<f32>,
+}
+
+/// Fields that a list of registrations can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TestSessionRegistrationSortField {
+    Id,
+    TestSessionId,
+    TakerId,
+    Registered,
+    Score,
+}
+
+impl std::str::FromStr for TestSessionRegistrationSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TestSessionRegistrationSortField, String> {
+        match s {
+            "id" => Ok(TestSessionRegistrationSortField::Id),
+            "test_session_id" => Ok(TestSessionRegistrationSortField::TestSessionId),
+            "taker_id" => Ok(TestSessionRegistrationSortField::TakerId),
+            "registered" => Ok(TestSessionRegistrationSortField::Registered),
+            "score" => Ok(TestSessionRegistrationSortField::Score),
+            _ => Err(format!("Cannot sort registrations by {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TestSessionRegistrationList {
+    pub registrations: Vec<TestSessionRegistration>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
 pub enum TestSessionRequest {
-    GetTestSessions(Option<u64>),
+    GetTestSessions(Option<u64>, Page<TestSessionSortField>),
+    SearchRegistrations(
+        SearchTestSessionRegistration,
+        Page<TestSessionRegistrationSortField>,
+    ),
     GetTestSession(u64),
     CreateTestSession(NewTestSession),
     UpdateTestSession(u64, PartialTestSession),
@@ -150,7 +228,19 @@ impl TestSessionRequest {
                     }
                 });
 
-                Ok(TestSessionRequest::GetTestSessions(test_id))
+                Ok(TestSessionRequest::GetTestSessions(
+                    test_id,
+                    Page::from_query(request.raw_query_string())?
+                ))
+            },
+
+            (GET) (/registrations) => {
+                let search =
+                    SearchTestSessionRegistration::from_query(request.raw_query_string())?;
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(TestSessionRequest::SearchRegistrations(search, page))
             },
 
             (GET) (/{id: u64}) => {
@@ -216,6 +306,7 @@ impl TestSessionRequest {
 pub enum TestSessionResponse {
     OneTestSession(TestSession),
     ManyTestSessions(TestSessionList),
+    ManyRegistrations(TestSessionRegistrationList),
     AnonymousQuestions(AnonymousQuestionList),
     TestSessionRegistration(TestSessionRegistration),
     Image(Vec<u8>),
@@ -231,6 +322,9 @@ impl TestSessionResponse {
             TestSessionResponse::ManyTestSessions(test_sessions) => {
                 rouille::Response::json(&test_sessions)
             }
+            TestSessionResponse::ManyRegistrations(registrations) => {
+                rouille::Response::json(&registrations)
+            }
             TestSessionResponse::TestSessionRegistration(registration) => {
                 rouille::Response::json(&registration)
             }
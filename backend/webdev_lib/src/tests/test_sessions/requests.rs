@@ -3,6 +3,7 @@ use std::io::Cursor;
 
 use crate::diesel::NullableExpressionMethods;
 use diesel;
+use diesel::mysql::Mysql;
 use diesel::mysql::MysqlConnection;
 use diesel::BoolExpressionMethods;
 use diesel::ExpressionMethods;
@@ -11,6 +12,7 @@ use diesel::RunQueryDsl;
 
 use chrono::offset::Local;
 use chrono::offset::TimeZone;
+use chrono::NaiveDateTime;
 
 use reqwest;
 use image::png::PNGDecoder;
@@ -32,13 +34,19 @@ use log::trace;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
-use crate::permissions::requests::check_to_run;
+use crate::audit::requests::{begin_change, finish_change};
+
+use crate::permissions::models::{OwnedResource, Permission, Principal};
+use crate::permissions::requests::{check_to_run, check_to_run_scoped};
+
+use crate::search::{Page, Sort, SortOrder};
 
 use crate::tests::test_sessions::models::{
     JoinedTestSession, NewRawTestSession, NewRawTestSessionRegistration, NewTestSession,
     PartialRawTestSessionRegistration, PartialTestSession, RawTestSession,
-    RawTestSessionRegistration, TestSession, TestSessionList, TestSessionRegistration,
-    TestSessionRequest, TestSessionResponse,
+    RawTestSessionRegistration, SearchTestSessionRegistration, TestSession, TestSessionList,
+    TestSessionRegistration, TestSessionRegistrationList, TestSessionRegistrationSortField,
+    TestSessionRequest, TestSessionResponse, TestSessionSortField,
 };
 
 use crate::tests::questions::models::AnonymousQuestion;
@@ -61,59 +69,157 @@ static FONT_DATA: &[u8] = include_bytes!("../../FiraSans-Regular.ttf");
 
 pub fn handle_test_session(
     request: TestSessionRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<TestSessionResponse, Error> {
     match request {
         TestSessionRequest::Register(test_session_id) => {
-            register(test_session_id, requested_user, database_connection)
-                .map(|_| TestSessionResponse::NoResponse)
+            let pending = begin_change(
+                requested_user,
+                format!("/test_sessions/{}/register", test_session_id),
+                "test_session_registrations",
+                None,
+                "create",
+                database_connection,
+            );
+
+            let registered = register(test_session_id, requested_user.user_id, database_connection);
+
+            let registration_id = match registered {
+                Ok(_) => find_registration_id(
+                    test_session_id,
+                    requested_user.user_id,
+                    database_connection,
+                ),
+                Err(_) => None,
+            };
+            finish_change(
+                pending,
+                registered.is_ok(),
+                registration_id,
+                database_connection,
+            );
+
+            registered.map(|_| TestSessionResponse::NoResponse)
         }
         TestSessionRequest::Unregister(test_session_id, option_user_id) => {
-            if option_user_id.is_some() {
-                check_to_run(requested_user, "UpdateTestSessions", database_connection)?;
+            if let Some(user_id) = option_user_id {
+                // Only look up the registration if the user could be relying on owning it
+                if !requested_user.has_permission(Permission::UpdateTestSessions) {
+                    let registration_ids = test_session_registrations_schema::table
+                        .select(test_session_registrations_schema::id)
+                        .filter(
+                            test_session_registrations_schema::test_session_id
+                                .eq(test_session_id),
+                        )
+                        .filter(test_session_registrations_schema::taker_id.eq(user_id))
+                        .load::<u64>(database_connection)?;
+
+                    match registration_ids.first() {
+                        Some(registration_id) => check_to_run_scoped(
+                            requested_user,
+                            Permission::UpdateTestSessions,
+                            Permission::DeleteOwnTestSessionRegistrations,
+                            OwnedResource::TestSessionRegistration(*registration_id),
+                            database_connection,
+                        )?,
+                        None => return Err(Error::new(ErrorKind::PermissionDenied)),
+                    }
+                }
             }
-            unregister(test_session_id, option_user_id.or(requested_user), database_connection)
-                .map(|_| TestSessionResponse::NoResponse)
+
+            let taker_id = option_user_id.or(requested_user.user_id);
+
+            let request_path = match option_user_id {
+                Some(user_id) => {
+                    format!("/test_sessions/{}/unregister/{}", test_session_id, user_id)
+                }
+                None => format!("/test_sessions/{}/unregister", test_session_id),
+            };
+            let pending = begin_change(
+                requested_user,
+                request_path,
+                "test_session_registrations",
+                find_registration_id(test_session_id, taker_id, database_connection),
+                "delete",
+                database_connection,
+            );
+
+            let unregistered = unregister(test_session_id, taker_id, database_connection);
+
+            finish_change(pending, unregistered.is_ok(), None, database_connection);
+
+            unregistered.map(|_| TestSessionResponse::NoResponse)
         }
         TestSessionRequest::Open(test_session_id) => {
-            open(test_session_id, requested_user, database_connection)
-                .map(|u| TestSessionResponse::AnonymousQuestions(u))
+            let pending = begin_change(
+                requested_user,
+                format!("/test_sessions/{}/open", test_session_id),
+                "test_session_registrations",
+                find_registration_id(test_session_id, requested_user.user_id, database_connection),
+                "update",
+                database_connection,
+            );
+
+            let opened = open(test_session_id, requested_user.user_id, database_connection);
+
+            finish_change(pending, opened.is_ok(), None, database_connection);
+
+            opened.map(|u| TestSessionResponse::AnonymousQuestions(u))
+        }
+        TestSessionRequest::Submit(test_session_id, respose_questions) => {
+            let pending = begin_change(
+                requested_user,
+                format!("/test_sessions/{}/submit", test_session_id),
+                "test_session_registrations",
+                find_registration_id(test_session_id, requested_user.user_id, database_connection),
+                "update",
+                database_connection,
+            );
+
+            let submitted = submit(
+                test_session_id,
+                respose_questions,
+                requested_user.user_id,
+                database_connection,
+            );
+
+            finish_change(pending, submitted.is_ok(), None, database_connection);
+
+            submitted.map(|u| TestSessionResponse::TestSessionRegistration(u))
         }
-        TestSessionRequest::Submit(test_session_id, respose_questions) => submit(
-            test_session_id,
-            respose_questions,
-            requested_user,
-            database_connection,
-        )
-        .map(|u| TestSessionResponse::TestSessionRegistration(u)),
-        TestSessionRequest::GetTestSessions(test_id) => {
-            check_to_run(requested_user, "GetTestSessions", database_connection)?;
-            get_test_sessions(test_id, database_connection)
+        TestSessionRequest::GetTestSessions(test_id, page) => {
+            check_to_run(requested_user, Permission::GetTestSessions)?;
+            get_test_sessions(test_id, page, database_connection)
                 .map(|u| TestSessionResponse::ManyTestSessions(u))
         }
+        TestSessionRequest::SearchRegistrations(registration, page) => {
+            check_to_run(requested_user, Permission::GetTestSessions)?;
+            search_registrations(registration, page, database_connection)
+                .map(|r| TestSessionResponse::ManyRegistrations(r))
+        }
         TestSessionRequest::GetTestSession(id) => {
-            check_to_run(requested_user, "GetTestSessions", database_connection)?;
+            check_to_run(requested_user, Permission::GetTestSessions)?;
             get_test_session(id, database_connection)
                 .map(|u| TestSessionResponse::OneTestSession(u))
         }
         TestSessionRequest::Certificate(id) => {
-            //check_to_run(requested_user, "GetTestSessions", database_connection)?;
+            //check_to_run(requested_user, Permission::GetTestSessions)?;
             generate_certificate(id, database_connection)
                 .map(|u| TestSessionResponse::Image(u))
         }
         TestSessionRequest::CreateTestSession(test_session) => {
-            check_to_run(requested_user, "CreateTestSessions", database_connection)?;
+            check_to_run(requested_user, Permission::CreateTestSessions)?;
             create_test_session(test_session, database_connection)
                 .map(|u| TestSessionResponse::OneTestSession(u))
         }
         TestSessionRequest::UpdateTestSession(id, test_session) => {
-            check_to_run(requested_user, "UpdateTestSessions", database_connection)?;
+            check_to_run(requested_user, Permission::UpdateTestSessions)?;
             update_test_session(id, test_session, database_connection)
                 .map(|_| TestSessionResponse::NoResponse)
         }
         TestSessionRequest::DeleteTestSession(id) => {
-            check_to_run(requested_user, "DeleteTestSessions", database_connection)?;
+            check_to_run(requested_user, Permission::DeleteTestSessions)?;
             delete_test_session(id, database_connection).map(|_| TestSessionResponse::NoResponse)
         }
     }
@@ -152,7 +258,7 @@ pub(crate) fn generate_certificate(
             scale,
             rusttype::point(3015.0, 3480.0)
         ).chain(font.layout(
-            &format!("{:09}", user.banner_id),
+            &user.banner_id.map(|b| format!("{:09}", b)).unwrap_or_default(),
             scale,
             rusttype::point(3015.0, 3834.0)
         )).chain(font.layout(
@@ -209,7 +315,8 @@ pub(crate) fn register(
     if test_session.registrations_enabled {
         if let Some(user_id) = requested_user {
 
-            let TestSessionList { test_sessions } = get_test_sessions(None, &database_connection)?;
+            let test_sessions =
+                get_test_sessions(None, Page::all(), &database_connection)?.test_sessions;
 
             let test_session = match test_sessions.iter().cloned().find(|s| s.id == test_session_id) {
                 Some(test_session) => test_session,
@@ -483,47 +590,7 @@ pub(crate) fn submit(
                     .load::<RawTestSessionRegistration>(database_connection)?;
 
                 if let Some(inserted_test_session_registration) = registrations.pop() {
-                    let registered = match Local.from_local_datetime(&inserted_test_session_registration.registered).earliest() {
-                        Some(registered) => registered,
-                        None => {
-                            error!(
-                                "Could not create a datetime from the database! {:?}",
-                                inserted_test_session_registration.registered
-                            );
-
-                            return Err(Error::new(ErrorKind::Database));
-                        }
-                    };
-
-                    let opened_test = inserted_test_session_registration.opened_test
-                        .map(|t| match Local.from_local_datetime(&t).earliest() {
-                            Some(opened) => Ok(opened),
-                            None => {
-                                error!("Could not create a datatime from the database! {:?}", t);
-
-                                Err(Error::new(ErrorKind::Database))
-                            }
-                        })
-                    .transpose()?;
-
-                    let submitted_test = inserted_test_session_registration.submitted_test
-                        .map(|t| match Local.from_local_datetime(&t).earliest() {
-                            Some(submitted_test) => Ok(submitted_test),
-                            None => {
-                                error!("Could not create a datatime from the database! {:?}", t);
-
-                                Err(Error::new(ErrorKind::Database))
-                            }
-                        })
-                    .transpose()?;
-                    Ok(TestSessionRegistration {
-                        id: inserted_test_session_registration.id,
-                        taker_id: inserted_test_session_registration.taker_id,
-                        registered: registered,
-                        opened_test: opened_test,
-                        submitted_test: submitted_test,
-                        score: inserted_test_session_registration.score,
-                    })
+                    registration_from_raw(inserted_test_session_registration)
                 } else {
                     Err(Error::new(ErrorKind::Database))
                 }
@@ -538,64 +605,68 @@ pub(crate) fn submit(
     }
 }
 
-pub(crate) fn condense_join(joined: Vec<JoinedTestSession>) -> Result<Vec<TestSession>, Error> {
-    let mut condensed: Vec<TestSession> = Vec::new();
+pub(crate) fn get_registration(
+    registration_id: u64,
+    database_connection: &MysqlConnection,
+) -> Result<TestSessionRegistration, Error> {
+    let mut registrations = test_session_registrations_schema::table
+        .filter(test_session_registrations_schema::id.eq(registration_id))
+        .load::<RawTestSessionRegistration>(database_connection)?;
 
-    for join in joined {
-        let mut registration = if let Some(RawTestSessionRegistration {
-            id: test_session_registration_id,
-            test_session_id: _,
-            taker_id,
-            registered,
-            opened_test,
-            submitted_test,
-            score,
-        }) = join.test_session_registration
-        {
-            let registered = match Local.from_local_datetime(&registered).earliest() {
-                Some(registered) => registered,
-                None => {
-                    error!(
-                        "Could not create a datetime from the database! {:?}",
-                        registered
-                    );
-
-                    return Err(Error::new(ErrorKind::Database));
-                }
-            };
+    match registrations.pop() {
+        Some(registration) => registration_from_raw(registration),
+        None => Err(Error::new(ErrorKind::NotFound)),
+    }
+}
+
+/// The registration of a user for a test session, if they are registered
+fn find_registration_id(
+    test_session_id: u64,
+    taker_id: Option<u64>,
+    database_connection: &MysqlConnection,
+) -> Option<u64> {
+    let taker_id = taker_id?;
+
+    test_session_registrations_schema::table
+        .select(test_session_registrations_schema::id)
+        .filter(test_session_registrations_schema::test_session_id.eq(test_session_id))
+        .filter(test_session_registrations_schema::taker_id.eq(taker_id))
+        .order(test_session_registrations_schema::id.desc())
+        .first::<u64>(database_connection)
+        .ok()
+}
 
-            let opened_test = opened_test
-                .map(|t| match Local.from_local_datetime(&t).earliest() {
-                    Some(opened) => Ok(opened),
-                    None => {
-                        error!("Could not create a datatime from the database! {:?}", t);
+/// Turns a registration from the database into one with local times
+fn registration_from_raw(
+    registration: RawTestSessionRegistration,
+) -> Result<TestSessionRegistration, Error> {
+    let to_local = |t: NaiveDateTime| match Local.from_local_datetime(&t).earliest() {
+        Some(t) => Ok(t),
+        None => {
+            error!("Could not create a datetime from the database! {:?}", t);
 
-                        Err(Error::new(ErrorKind::Database))
-                    }
-                })
-                .transpose()?;
+            Err(Error::new(ErrorKind::Database))
+        }
+    };
 
-            let submitted_test = submitted_test
-                .map(|t| match Local.from_local_datetime(&t).earliest() {
-                    Some(submitted_test) => Ok(submitted_test),
-                    None => {
-                        error!("Could not create a datatime from the database! {:?}", t);
+    Ok(TestSessionRegistration {
+        id: registration.id,
+        test_session_id: registration.test_session_id,
+        taker_id: registration.taker_id,
+        registered: to_local(registration.registered)?,
+        opened_test: registration.opened_test.map(to_local).transpose()?,
+        submitted_test: registration.submitted_test.map(to_local).transpose()?,
+        score: registration.score,
+    })
+}
 
-                        Err(Error::new(ErrorKind::Database))
-                    }
-                })
-                .transpose()?;
-
-            vec![TestSessionRegistration {
-                id: test_session_registration_id,
-                taker_id: taker_id,
-                registered: registered,
-                opened_test: opened_test,
-                submitted_test: submitted_test,
-                score: score,
-            }]
-        } else {
-            Vec::new()
+pub(crate) fn condense_join(joined: Vec<JoinedTestSession>) -> Result<Vec<TestSession>, Error> {
+    let mut condensed: Vec<TestSession> = Vec::new();
+
+    for join in joined {
+        let mut registration = match join.test_session_registration {
+            Some(registration) => vec![registration_from_raw(registration)?],
+            None => Vec::new(),
         };
 
         if let Some(test_session) = condensed.iter_mut().find(|t| t.id == join.test_session.id) {
@@ -620,9 +691,34 @@ pub(crate) fn condense_join(joined: Vec<JoinedTestSession>) -> Result<Vec<TestSe
 
 pub(crate) fn get_test_sessions(
     test_id: Option<u64>,
+    page: Page<TestSessionSortField>,
     database_connection: &MysqlConnection,
 ) -> Result<TestSessionList, Error> {
-    let mut query = test_sessions_schema::table
+    let total = filter_test_sessions(test_id)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: TestSessionSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    // The page is found before joining the registrations, since each one is another row
+    let ids_query = filter_test_sessions(test_id).select(test_sessions_schema::id);
+
+    let ids_query = crate::sort_query!(ids_query, sort, {
+        TestSessionSortField::Id => test_sessions_schema::id,
+        TestSessionSortField::TestId => test_sessions_schema::test_id,
+        TestSessionSortField::Name => test_sessions_schema::name,
+    });
+
+    let test_session_ids = ids_query
+        .then_order_by(test_sessions_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<u64>(database_connection)?;
+
+    let joined_test_sessions = test_sessions_schema::table
         .left_join(test_session_registrations_schema::table)
         .select((
             (
@@ -645,21 +741,80 @@ pub(crate) fn get_test_sessions(
             )
                 .nullable(),
         ))
-        .into_boxed();
+        .filter(test_sessions_schema::id.eq_any(test_session_ids.clone()))
+        .load::<JoinedTestSession>(database_connection)?;
+
+    let mut test_sessions = condense_join(joined_test_sessions)?;
+
+    // The join does not keep the order of the page
+    test_sessions.sort_by_key(|s| test_session_ids.iter().position(|id| *id == s.id));
+
+    Ok(TestSessionList {
+        test_sessions: test_sessions,
+        total: total,
+        next: page.next(total),
+    })
+}
+
+/// Builds a query for the sessions of a test, or every session if there is no test
+fn filter_test_sessions<'a>(test_id: Option<u64>) -> test_sessions_schema::BoxedQuery<'a, Mysql> {
+    let mut query = test_sessions_schema::table.into_boxed();
 
     if let Some(test_id) = test_id {
         query = query.filter(test_sessions_schema::test_id.eq(test_id));
     };
 
-    let joined_test_sessions = query.load::<JoinedTestSession>(database_connection)?;
-
-    let test_sessions = condense_join(joined_test_sessions)?;
+    query
+}
 
-    Ok(TestSessionList {
-        test_sessions: test_sessions,
+/// Searches the registrations of every test session
+pub(crate) fn search_registrations(
+    registration_search: SearchTestSessionRegistration,
+    page: Page<TestSessionRegistrationSortField>,
+    database_connection: &MysqlConnection,
+) -> Result<TestSessionRegistrationList, Error> {
+    let total = filter_registrations(&registration_search)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: TestSessionRegistrationSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let registrations_query = filter_registrations(&registration_search);
+
+    let registrations_query = crate::sort_query!(registrations_query, sort, {
+        TestSessionRegistrationSortField::Id => test_session_registrations_schema::id,
+        TestSessionRegistrationSortField::TestSessionId => test_session_registrations_schema::test_session_id,
+        TestSessionRegistrationSortField::TakerId => test_session_registrations_schema::taker_id,
+        TestSessionRegistrationSortField::Registered => test_session_registrations_schema::registered,
+        TestSessionRegistrationSortField::Score => test_session_registrations_schema::score,
+    });
+
+    let registrations = registrations_query
+        .then_order_by(test_session_registrations_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<RawTestSessionRegistration>(database_connection)?
+        .into_iter()
+        .map(registration_from_raw)
+        .collect::<Result<Vec<TestSessionRegistration>, Error>>()?;
+
+    Ok(TestSessionRegistrationList {
+        registrations: registrations,
+        total: total,
+        next: page.next(total),
     })
 }
 
+/// Builds a query for the registrations that match the search
+fn filter_registrations<'a>(
+    registration_search: &'a SearchTestSessionRegistration,
+) -> test_session_registrations_schema::BoxedQuery<'a, Mysql> {
+    registration_search.filter(test_session_registrations_schema::table.into_boxed())
+}
+
 pub(crate) fn get_test_session(
     id: u64,
     database_connection: &MysqlConnection,
@@ -7,7 +7,8 @@ use diesel::RunQueryDsl;
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
-use crate::permissions::requests::check_to_run;
+use crate::permissions::models::{OwnedResource, Permission, Principal};
+use crate::permissions::requests::{check_to_run, check_to_run_scoped};
 
 use crate::tests::tests::models::{
     JoinedTest, NewRawTest, NewTest, RawTest, RawTestQuestionCategory, Test, TestList,
@@ -18,24 +19,31 @@ use crate::tests::tests::schema::tests as tests_schema;
 
 pub fn handle_test(
     request: TestRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<TestResponse, Error> {
     match request {
         TestRequest::GetTests => {
-            check_to_run(requested_user, "GetTests", database_connection)?;
+            check_to_run(requested_user, Permission::GetTests)?;
             get_tests(database_connection).map(|u| TestResponse::ManyTests(u))
         }
         TestRequest::GetTest(id) => {
-            check_to_run(requested_user, "GetTests", database_connection)?;
+            check_to_run(requested_user, Permission::GetTests)?;
             get_test(id, database_connection).map(|u| TestResponse::OneTest(u))
         }
         TestRequest::CreateTest(test) => {
-            check_to_run(requested_user, "CreateTests", database_connection)?;
-            create_test(test, requested_user, database_connection).map(|u| TestResponse::OneTest(u))
+            check_to_run(requested_user, Permission::CreateTests)?;
+            create_test(test, requested_user.user_id, database_connection)
+                .map(|u| TestResponse::OneTest(u))
         }
         TestRequest::DeleteTest(id) => {
-            check_to_run(requested_user, "DeleteTests", database_connection)?;
+            check_to_run_scoped(
+                requested_user,
+                Permission::DeleteTests,
+                Permission::DeleteOwnTests,
+                OwnedResource::Test(id),
+                database_connection,
+            )?;
             delete_test(id, database_connection).map(|_| TestResponse::NoResponse)
         }
     }
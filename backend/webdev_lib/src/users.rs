@@ -1,3 +1,5 @@
+pub mod export;
+pub mod import;
 pub mod models;
 pub mod requests;
 pub mod schema;
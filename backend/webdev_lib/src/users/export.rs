@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use diesel::mysql::MysqlConnection;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::RunQueryDsl;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::permissions::schema::roles as roles_schema;
+use crate::permissions::schema::user_roles as user_roles_schema;
+
+use super::import::{CsvUser, LIST_SEPARATOR};
+use super::models::UserList;
+
+/// Writes users out with the same columns `import_users` reads
+///
+/// Permissions and roles are only the ones given to the user directly, so an exported roster can
+/// be edited and imported again.
+pub fn export_users_csv(
+    user_list: UserList,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<u8>, Error> {
+    let user_ids: Vec<u64> = user_list.users.iter().map(|u| u.id).collect();
+
+    let mut roles: HashMap<u64, Vec<String>> = HashMap::new();
+
+    for (user_id, role_name) in user_roles_schema::table
+        .inner_join(roles_schema::table)
+        .select((user_roles_schema::user_id, roles_schema::role_name))
+        .filter(user_roles_schema::user_id.eq_any(user_ids))
+        .load::<(u64, String)>(database_connection)?
+    {
+        roles
+            .entry(user_id)
+            .or_insert_with(Vec::new)
+            .push(role_name);
+    }
+
+    let separator = LIST_SEPARATOR.to_string();
+    let mut csv_writer = csv::Writer::from_writer(Vec::new());
+
+    for user in user_list.users {
+        let permissions: Vec<String> = user
+            .permissions
+            .into_iter()
+            .map(|p| p.permission_name)
+            .collect();
+
+        let csv_user = CsvUser {
+            banner_id: user.banner_id,
+            last_name: user.last_name,
+            first_name: user.first_name,
+            email: user.email,
+            class_year: user.class_year,
+            department: user.department,
+            affiliation: user.affiliation.map(|a| a.name().to_owned()),
+            phone: user.phone,
+            emergency_contact: user.emergency_contact,
+            permissions: Some(permissions.join(&separator)),
+            roles: roles.remove(&user.id).map(|r| r.join(&separator)),
+        };
+
+        csv_writer.serialize(csv_user)?;
+    }
+
+    csv_writer
+        .into_inner()
+        .map_err(|_| Error::new(ErrorKind::Io))
+}
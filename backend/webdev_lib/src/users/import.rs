@@ -0,0 +1,779 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Read;
+
+use diesel;
+use diesel::mysql::MysqlConnection;
+use diesel::Connection;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::RunQueryDsl;
+
+use chrono::offset::Local;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use log::info;
+
+use crate::errors::{Error, ErrorKind};
+
+use crate::audit::requests::{begin_change, finish_change};
+
+use crate::permissions::models::{NewUserPermission, Permission, Principal, UserRole};
+use crate::permissions::requests::{check_to_run, create_user_role};
+
+use crate::permissions::schema::permissions as permissions_schema;
+use crate::permissions::schema::roles as roles_schema;
+use crate::permissions::schema::user_permissions as user_permissions_schema;
+use crate::permissions::schema::user_roles as user_roles_schema;
+use crate::users::schema::users as users_schema;
+
+use super::models::{
+    Affiliation, ImportAction, ImportChange, ImportReport, ImportRow, NewUser, PartialUser, User,
+};
+use super::requests::{create_user, get_user, update_user};
+
+/// Separates the names in the permission and role columns
+pub const LIST_SEPARATOR: char = ';';
+
+/// The request path of audit log entries for imported users, even from the command line
+const IMPORT_PATH: &str = "/users/import";
+
+/// A row of a user import
+///
+/// Only the names and email are required. Permissions and roles are lists of names
+/// separated by semicolons, and are only ever added to a user, never taken away.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CsvUser {
+    #[serde(rename = "Banner ID", default)]
+    pub banner_id: Option<u32>,
+    #[serde(rename = "Last Name")]
+    pub last_name: String,
+    #[serde(rename = "First Name")]
+    pub first_name: String,
+    #[serde(rename = "Email")]
+    pub email: String,
+    #[serde(rename = "Year", default)]
+    pub class_year: Option<String>,
+    #[serde(rename = "Department", default)]
+    pub department: Option<String>,
+    #[serde(rename = "Affiliation", default)]
+    pub affiliation: Option<String>,
+    #[serde(rename = "Phone", default)]
+    pub phone: Option<String>,
+    #[serde(rename = "Emergency Contact", default)]
+    pub emergency_contact: Option<String>,
+    #[serde(rename = "Permissions", default)]
+    pub permissions: Option<String>,
+    #[serde(rename = "Roles", default)]
+    pub roles: Option<String>,
+}
+
+/// Creates or updates a user for every row of a CSV file
+///
+/// Rows are matched to existing users by banner id, then by email. Each row is imported on its
+/// own, so a bad row is reported without stopping the rest. Rows that share an email or banner id
+/// with another row fail before anything is looked up. With `dry_run`, nothing is written and the
+/// report shows what would have changed, including every field of the users it would create.
+pub fn import_users<R: Read>(
+    csv_data: R,
+    dry_run: bool,
+    importer: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<ImportReport, Error> {
+    let mut csv_reader = csv::Reader::from_reader(csv_data);
+
+    // Fail the whole import if the header cannot be read, since no row will make sense
+    csv_reader.headers()?;
+
+    // Count the header as the first row, like a spreadsheet would
+    let csv_rows: Vec<_> = csv_reader
+        .deserialize::<CsvUser>()
+        .enumerate()
+        .map(|(index, result)| (index + 2, result))
+        .collect();
+
+    let duplicates = duplicate_rows(
+        csv_rows
+            .iter()
+            .filter_map(|(row, result)| result.as_ref().ok().map(|csv_user| (*row, csv_user))),
+    );
+
+    let mut rows = Vec::new();
+
+    for (row, result) in csv_rows {
+        let import_row = match result {
+            Ok(csv_user) => {
+                let email = csv_user.email.clone();
+
+                let imported = match duplicates.get(&row) {
+                    Some(duplicate) => Err(Error::with_source(
+                        ErrorKind::Body,
+                        duplicate.clone().into(),
+                    )),
+                    None => import_user(csv_user, dry_run, importer, database_connection),
+                };
+
+                match imported {
+                    Ok((user_id, action, changes)) => ImportRow {
+                        row: row,
+                        email: Some(email),
+                        user_id: user_id,
+                        action: action,
+                        changes: changes,
+                        error: None,
+                    },
+                    Err(e) => failed_row(row, Some(email), e),
+                }
+            }
+            Err(e) => failed_row(row, None, Error::from(e)),
+        };
+
+        rows.push(import_row);
+    }
+
+    let report = ImportReport::from_rows(dry_run, rows);
+
+    info!(
+        "Imported users: {} created, {} updated, {} unchanged, {} failed{}",
+        report.created,
+        report.updated,
+        report.unchanged,
+        report.failed,
+        if dry_run { " (dry run)" } else { "" }
+    );
+
+    Ok(report)
+}
+
+/// Finds the rows that share an email or banner id with another row
+///
+/// Each of them would be matched to, or create, the same user, so none of them can be imported.
+/// Emails are compared without case, like the database does.
+fn duplicate_rows<'a>(
+    csv_users: impl Iterator<Item = (usize, &'a CsvUser)>,
+) -> HashMap<usize, String> {
+    let mut by_email: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut by_banner_id: HashMap<u32, Vec<usize>> = HashMap::new();
+
+    for (row, csv_user) in csv_users {
+        by_email
+            .entry(csv_user.email.trim().to_lowercase())
+            .or_insert_with(Vec::new)
+            .push(row);
+
+        if let Some(banner_id) = csv_user.banner_id {
+            by_banner_id
+                .entry(banner_id)
+                .or_insert_with(Vec::new)
+                .push(row);
+        }
+    }
+
+    let mut duplicates = HashMap::new();
+
+    let shared = by_email
+        .into_iter()
+        .map(|(email, rows)| (format!("Email {}", email), rows))
+        .chain(
+            by_banner_id
+                .into_iter()
+                .map(|(banner_id, rows)| (format!("Banner id {}", banner_id), rows)),
+        )
+        .filter(|(_, rows)| rows.len() > 1);
+
+    for (value, rows) in shared {
+        let row_list: Vec<String> = rows.iter().map(|r| r.to_string()).collect();
+
+        for row in rows {
+            duplicates
+                .entry(row)
+                .or_insert_with(|| format!("{} is on rows {}", value, row_list.join(", ")));
+        }
+    }
+
+    duplicates
+}
+
+fn failed_row(row: usize, email: Option<String>, error: Error) -> ImportRow {
+    ImportRow {
+        row: row,
+        email: email,
+        user_id: None,
+        action: ImportAction::Failed,
+        changes: Vec::new(),
+        error: Some(error.to_string_with_source()),
+    }
+}
+
+fn import_user(
+    csv_user: CsvUser,
+    dry_run: bool,
+    importer: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<(Option<u64>, ImportAction, Vec<ImportChange>), Error> {
+    let affiliation = match &csv_user.affiliation {
+        Some(affiliation) => Some(
+            affiliation
+                .parse::<Affiliation>()
+                .map_err(|e| Error::with_source(ErrorKind::Body, e.into()))?,
+        ),
+        None => None,
+    };
+
+    let permission_names = split_list(&csv_user.permissions);
+    let role_names = split_list(&csv_user.roles);
+
+    if !permission_names.is_empty() {
+        check_to_run(importer, Permission::CreateUserPermission)?;
+    }
+
+    if !role_names.is_empty() {
+        check_to_run(importer, Permission::CreateUserRoles)?;
+    }
+
+    let permission_ids = permission_names
+        .iter()
+        .map(|name| find_permission(name, database_connection))
+        .collect::<Result<Vec<u64>, Error>>()?;
+
+    let role_ids = role_names
+        .iter()
+        .map(|name| find_role(name, database_connection))
+        .collect::<Result<Vec<u64>, Error>>()?;
+
+    let existing_user =
+        match find_existing_user(csv_user.banner_id, &csv_user.email, database_connection)? {
+            Some(user_id) => Some(get_user(user_id, database_connection)?),
+            None => None,
+        };
+
+    match existing_user {
+        Some(user) => {
+            check_to_run(importer, Permission::UpdateUsers)?;
+
+            let (partial_user, mut changes) = user_changes(&user, &csv_user, affiliation);
+
+            let (new_permission_names, new_permission_ids): (Vec<String>, Vec<u64>) =
+                permission_names
+                    .into_iter()
+                    .zip(permission_ids)
+                    .filter(|(_, id)| !user.permissions.iter().any(|p| p.id == *id))
+                    .unzip();
+
+            let current_role_ids = user_roles_schema::table
+                .select(user_roles_schema::role_id)
+                .filter(user_roles_schema::user_id.eq(user.id))
+                .load::<u64>(database_connection)?;
+
+            let (new_role_names, new_role_ids): (Vec<String>, Vec<u64>) = role_names
+                .into_iter()
+                .zip(role_ids)
+                .filter(|(_, id)| !current_role_ids.contains(id))
+                .unzip();
+
+            let user_changed = !changes.is_empty();
+
+            if !new_permission_names.is_empty() {
+                changes.push(ImportChange {
+                    field: "permissions".to_owned(),
+                    before: None,
+                    after: Some(new_permission_names.join(&LIST_SEPARATOR.to_string())),
+                });
+            }
+
+            if !new_role_names.is_empty() {
+                changes.push(ImportChange {
+                    field: "roles".to_owned(),
+                    before: None,
+                    after: Some(new_role_names.join(&LIST_SEPARATOR.to_string())),
+                });
+            }
+
+            if changes.is_empty() {
+                return Ok((Some(user.id), ImportAction::Unchanged, changes));
+            }
+
+            if !dry_run {
+                let mut pending = vec![begin_change(
+                    importer,
+                    IMPORT_PATH.to_owned(),
+                    "users",
+                    Some(user.id),
+                    "update",
+                    database_connection,
+                )];
+
+                if !new_role_ids.is_empty() {
+                    pending.push(begin_change(
+                        importer,
+                        IMPORT_PATH.to_owned(),
+                        "user_role",
+                        Some(user.id),
+                        "update",
+                        database_connection,
+                    ));
+                }
+
+                let updated = database_connection.transaction::<_, Error, _>(|| {
+                    if user_changed {
+                        update_user(user.id, partial_user, database_connection)?;
+                    }
+
+                    grant(
+                        user.id,
+                        &new_permission_ids,
+                        &new_role_ids,
+                        database_connection,
+                    )
+                });
+
+                for pending in pending {
+                    finish_change(pending, updated.is_ok(), None, database_connection);
+                }
+
+                updated?;
+            }
+
+            Ok((Some(user.id), ImportAction::Updated, changes))
+        }
+
+        None => {
+            check_to_run(importer, Permission::CreateUsers)?;
+
+            let new_user = NewUser {
+                first_name: csv_user.first_name,
+                last_name: csv_user.last_name,
+                banner_id: csv_user.banner_id,
+                email: csv_user.email,
+                department: csv_user.department,
+                class_year: csv_user.class_year,
+                affiliation: affiliation,
+                phone: csv_user.phone,
+                emergency_contact: csv_user.emergency_contact,
+                permissions: Vec::new(),
+            };
+
+            let mut changes = new_user_changes(&new_user);
+
+            if !permission_names.is_empty() {
+                changes.push(ImportChange {
+                    field: "permissions".to_owned(),
+                    before: None,
+                    after: Some(permission_names.join(&LIST_SEPARATOR.to_string())),
+                });
+            }
+
+            if !role_names.is_empty() {
+                changes.push(ImportChange {
+                    field: "roles".to_owned(),
+                    before: None,
+                    after: Some(role_names.join(&LIST_SEPARATOR.to_string())),
+                });
+            }
+
+            if dry_run {
+                return Ok((None, ImportAction::Created, changes));
+            }
+
+            let mut pending = vec![begin_change(
+                importer,
+                IMPORT_PATH.to_owned(),
+                "users",
+                None,
+                "create",
+                database_connection,
+            )];
+
+            if !role_ids.is_empty() {
+                pending.push(begin_change(
+                    importer,
+                    IMPORT_PATH.to_owned(),
+                    "user_role",
+                    None,
+                    "create",
+                    database_connection,
+                ));
+            }
+
+            let created = database_connection.transaction::<_, Error, _>(|| {
+                let user_id = create_user(new_user, database_connection)?.id;
+                grant(user_id, &permission_ids, &role_ids, database_connection)?;
+                Ok(user_id)
+            });
+
+            let user_id = created.as_ref().ok().cloned();
+            for pending in pending {
+                finish_change(pending, created.is_ok(), user_id, database_connection);
+            }
+
+            let user_id = created?;
+
+            Ok((Some(user_id), ImportAction::Created, changes))
+        }
+    }
+}
+
+/// Finds the user a row refers to, by banner id and then by email
+fn find_existing_user(
+    banner_id: Option<u32>,
+    email: &str,
+    database_connection: &MysqlConnection,
+) -> Result<Option<u64>, Error> {
+    let by_banner_id = match banner_id {
+        Some(banner_id) => users_schema::table
+            .select(users_schema::id)
+            .filter(users_schema::banner_id.eq(banner_id))
+            .load::<u64>(database_connection)?
+            .pop(),
+        None => None,
+    };
+
+    let by_email = users_schema::table
+        .select(users_schema::id)
+        .filter(users_schema::email.eq(email))
+        .load::<u64>(database_connection)?
+        .pop();
+
+    match (by_banner_id, by_email) {
+        (Some(banner_user), Some(email_user)) if banner_user != email_user => {
+            Err(Error::with_source(
+                ErrorKind::Body,
+                format!(
+                    "Banner id {} belongs to user {}, but {} belongs to user {}",
+                    banner_id.unwrap_or(0),
+                    banner_user,
+                    email,
+                    email_user
+                )
+                .into(),
+            ))
+        }
+        (Some(user_id), _) | (None, Some(user_id)) => Ok(Some(user_id)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn find_permission(name: &str, database_connection: &MysqlConnection) -> Result<u64, Error> {
+    let permission = Permission::from_name(name).ok_or_else(|| {
+        Error::with_source(
+            ErrorKind::Body,
+            format!("Unknown permission {}", name).into(),
+        )
+    })?;
+
+    permissions_schema::table
+        .select(permissions_schema::id)
+        .filter(permissions_schema::permission_name.eq(permission.name()))
+        .load::<u64>(database_connection)?
+        .pop()
+        .ok_or(Error::new(ErrorKind::NotFound))
+}
+
+fn find_role(name: &str, database_connection: &MysqlConnection) -> Result<u64, Error> {
+    roles_schema::table
+        .select(roles_schema::id)
+        .filter(roles_schema::role_name.eq(name))
+        .load::<u64>(database_connection)?
+        .pop()
+        .ok_or_else(|| Error::with_source(ErrorKind::Body, format!("Unknown role {}", name).into()))
+}
+
+fn grant(
+    user_id: u64,
+    permission_ids: &[u64],
+    role_ids: &[u64],
+    database_connection: &MysqlConnection,
+) -> Result<(), Error> {
+    let new_user_permissions: Vec<_> = permission_ids
+        .iter()
+        .map(|permission_id| NewUserPermission {
+            permission_id: *permission_id,
+            user_id: user_id,
+            granted_at: Some(Local::now().naive_local()),
+            expires_at: None,
+        })
+        .collect();
+
+    if !new_user_permissions.is_empty() {
+        diesel::insert_into(user_permissions_schema::table)
+            .values(new_user_permissions)
+            .execute(database_connection)?;
+    }
+
+    for role_id in role_ids {
+        create_user_role(
+            UserRole {
+                user_id: user_id,
+                role_id: *role_id,
+            },
+            database_connection,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The fields of a row that differ from the user they refer to
+///
+/// Empty optional columns are left alone rather than clearing the user's field.
+fn user_changes(
+    user: &User,
+    csv_user: &CsvUser,
+    affiliation: Option<Affiliation>,
+) -> (PartialUser, Vec<ImportChange>) {
+    let mut changes = Vec::new();
+
+    let partial_user = PartialUser {
+        first_name: changed(
+            "first_name",
+            Some(&user.first_name),
+            Some(csv_user.first_name.clone()),
+            &mut changes,
+        ),
+        last_name: changed(
+            "last_name",
+            Some(&user.last_name),
+            Some(csv_user.last_name.clone()),
+            &mut changes,
+        ),
+        banner_id: changed(
+            "banner_id",
+            user.banner_id.as_ref(),
+            csv_user.banner_id,
+            &mut changes,
+        ),
+        email: changed(
+            "email",
+            Some(&user.email),
+            Some(csv_user.email.clone()),
+            &mut changes,
+        ),
+        department: changed(
+            "department",
+            user.department.as_ref(),
+            csv_user.department.clone(),
+            &mut changes,
+        ),
+        class_year: changed(
+            "class_year",
+            user.class_year.as_ref(),
+            csv_user.class_year.clone(),
+            &mut changes,
+        ),
+        affiliation: changed(
+            "affiliation",
+            user.affiliation.as_ref(),
+            affiliation,
+            &mut changes,
+        ),
+        phone: changed(
+            "phone",
+            user.phone.as_ref(),
+            csv_user.phone.clone(),
+            &mut changes,
+        ),
+        emergency_contact: changed(
+            "emergency_contact",
+            user.emergency_contact.as_ref(),
+            csv_user.emergency_contact.clone(),
+            &mut changes,
+        ),
+        preferred_name: None,
+    };
+
+    (partial_user, changes)
+}
+
+/// Every field a new user will be created with, as changes from nothing
+fn new_user_changes(new_user: &NewUser) -> Vec<ImportChange> {
+    let mut changes = Vec::new();
+
+    changed("first_name", None, Some(&new_user.first_name), &mut changes);
+    changed("last_name", None, Some(&new_user.last_name), &mut changes);
+    changed("banner_id", None, new_user.banner_id.as_ref(), &mut changes);
+    changed("email", None, Some(&new_user.email), &mut changes);
+    changed(
+        "department",
+        None,
+        new_user.department.as_ref(),
+        &mut changes,
+    );
+    changed(
+        "class_year",
+        None,
+        new_user.class_year.as_ref(),
+        &mut changes,
+    );
+    changed(
+        "affiliation",
+        None,
+        new_user.affiliation.as_ref(),
+        &mut changes,
+    );
+    changed("phone", None, new_user.phone.as_ref(), &mut changes);
+    changed(
+        "emergency_contact",
+        None,
+        new_user.emergency_contact.as_ref(),
+        &mut changes,
+    );
+
+    changes
+}
+
+fn changed<T: PartialEq + Display>(
+    field: &str,
+    before: Option<&T>,
+    after: Option<T>,
+    changes: &mut Vec<ImportChange>,
+) -> Option<T> {
+    match after {
+        Some(after) if before != Some(&after) => {
+            changes.push(ImportChange {
+                field: field.to_owned(),
+                before: before.map(|b| b.to_string()),
+                after: Some(after.to_string()),
+            });
+
+            Some(after)
+        }
+        _ => None,
+    }
+}
+
+fn split_list(list: &Option<String>) -> Vec<String> {
+    match list {
+        Some(list) => list
+            .split(LIST_SEPARATOR)
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_owned())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[test]
+fn split_list_ignores_blank_names() {
+    let list = Some(" GetUsers;;CreateUsers ; ".to_owned());
+    assert_eq!(split_list(&list), vec!["GetUsers", "CreateUsers"]);
+    assert!(split_list(&None).is_empty());
+}
+
+#[test]
+fn user_changes_only_includes_different_fields() {
+    let user = User {
+        id: 1,
+        first_name: "John".to_owned(),
+        last_name: "Smith".to_owned(),
+        banner_id: Some(916200000),
+        email: "smithj1@students.rowan.edu".to_owned(),
+        active: true,
+        deactivated_at: None,
+        department: Some("Chemistry".to_owned()),
+        class_year: None,
+        affiliation: None,
+        phone: Some("555-0100".to_owned()),
+        emergency_contact: None,
+        preferred_name: None,
+        permissions: Vec::new(),
+    };
+
+    let csv_user = CsvUser {
+        banner_id: Some(916200000),
+        last_name: "Smith".to_owned(),
+        first_name: "Johnny".to_owned(),
+        email: "smithj1@students.rowan.edu".to_owned(),
+        class_year: Some("2021".to_owned()),
+        department: Some("Chemistry".to_owned()),
+        affiliation: None,
+        phone: None,
+        emergency_contact: None,
+        permissions: None,
+        roles: None,
+    };
+
+    let (partial_user, changes) = user_changes(&user, &csv_user, Some(Affiliation::Student));
+
+    assert_eq!(partial_user.first_name, Some("Johnny".to_owned()));
+    assert_eq!(partial_user.last_name, None);
+    assert_eq!(partial_user.department, None);
+    assert_eq!(partial_user.class_year, Some("2021".to_owned()));
+    assert_eq!(partial_user.affiliation, Some(Affiliation::Student));
+    assert_eq!(partial_user.phone, None);
+
+    let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+    assert_eq!(fields, vec!["first_name", "class_year", "affiliation"]);
+}
+
+#[test]
+fn new_user_changes_lists_every_given_field() {
+    let new_user = NewUser {
+        first_name: "Jane".to_owned(),
+        last_name: "Doe".to_owned(),
+        banner_id: None,
+        email: "doej2@students.rowan.edu".to_owned(),
+        department: None,
+        class_year: Some("2022".to_owned()),
+        affiliation: Some(Affiliation::Student),
+        phone: None,
+        emergency_contact: None,
+        permissions: Vec::new(),
+    };
+
+    let changes = new_user_changes(&new_user);
+
+    let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+    assert_eq!(
+        fields,
+        vec![
+            "first_name",
+            "last_name",
+            "email",
+            "class_year",
+            "affiliation"
+        ]
+    );
+    assert!(changes.iter().all(|c| c.before.is_none()));
+    assert_eq!(
+        changes[2].after,
+        Some("doej2@students.rowan.edu".to_owned())
+    );
+}
+
+#[test]
+fn duplicate_rows_finds_shared_emails_and_banner_ids() {
+    let csv_user = |banner_id: Option<u32>, email: &str| CsvUser {
+        banner_id: banner_id,
+        last_name: "Smith".to_owned(),
+        first_name: "John".to_owned(),
+        email: email.to_owned(),
+        class_year: None,
+        department: None,
+        affiliation: None,
+        phone: None,
+        emergency_contact: None,
+        permissions: None,
+        roles: None,
+    };
+
+    let csv_users = vec![
+        (2, csv_user(Some(916200000), "smithj1@students.rowan.edu")),
+        (3, csv_user(None, "SmithJ1@students.rowan.edu")),
+        (4, csv_user(Some(916200000), "smithj2@students.rowan.edu")),
+        (5, csv_user(None, "doej2@students.rowan.edu")),
+    ];
+
+    let duplicates = duplicate_rows(csv_users.iter().map(|(row, u)| (*row, u)));
+
+    let mut rows: Vec<usize> = duplicates.keys().cloned().collect();
+    rows.sort();
+    assert_eq!(rows, vec![2, 3, 4]);
+    assert_eq!(
+        duplicates[&3],
+        "Email smithj1@students.rowan.edu is on rows 2, 3"
+    );
+    assert_eq!(duplicates[&4], "Banner id 916200000 is on rows 2, 4");
+}
@@ -1,27 +1,123 @@
+use std::io::{Read, Write};
+
+use diesel::deserialize::{self, FromSql};
+use diesel::mysql::Mysql;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Varchar;
 use diesel::Queryable;
 use rouille::router;
 use serde::Deserialize;
 use serde::Serialize;
-use url::form_urlencoded;
+
+use chrono::NaiveDateTime;
 
 use log::warn;
 
 use super::schema::users;
 
-use crate::permissions::models::Permission;
+use crate::permissions::models::RawPermission;
 
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
-use crate::search::Search;
+use crate::search::{parse_queries, unknown_query, NullableSearch, Page, Search};
+
+use webdev_search_derive::Searchable;
+
+/// How a user is connected to the university
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, AsExpression, FromSqlRow)]
+#[serde(rename_all = "snake_case")]
+#[sql_type = "Varchar"]
+pub enum Affiliation {
+    Student,
+    Faculty,
+    Staff,
+    Guest,
+}
+
+impl Affiliation {
+    pub const ALL: &'static [Affiliation] = &[
+        Affiliation::Student,
+        Affiliation::Faculty,
+        Affiliation::Staff,
+        Affiliation::Guest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Affiliation::Student => "student",
+            Affiliation::Faculty => "faculty",
+            Affiliation::Staff => "staff",
+            Affiliation::Guest => "guest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Affiliation> {
+        Affiliation::ALL
+            .iter()
+            .cloned()
+            .find(|a| a.name() == name.to_lowercase())
+    }
+}
+
+impl std::str::FromStr for Affiliation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Affiliation, String> {
+        Affiliation::from_name(s).ok_or_else(|| format!("Unknown affiliation: {}", s))
+    }
+}
+
+impl std::fmt::Display for Affiliation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl ToSql<Varchar, Mysql> for Affiliation {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        out.write_all(self.name().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Mysql> for Affiliation {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Affiliation> {
+        let name = <String as FromSql<Varchar, Mysql>>::from_sql(bytes)?;
+        Ok(name.parse()?)
+    }
+}
+
+#[test]
+fn affiliation_name_round_trips() {
+    for affiliation in Affiliation::ALL {
+        assert_eq!(
+            Affiliation::from_name(affiliation.name()),
+            Some(*affiliation)
+        );
+    }
+}
+
+#[test]
+fn affiliation_unknown_name_fails() {
+    assert_eq!(Affiliation::from_name("alumni"), None);
+}
 
 #[derive(Queryable, Debug)]
 pub struct RawUser {
     pub id: u64,
     pub first_name: String,
     pub last_name: String,
-    pub banner_id: u32,
+    pub banner_id: Option<u32>,
     pub email: String,
+    pub active: bool,
+    pub deactivated_at: Option<NaiveDateTime>,
+    pub department: Option<String>,
+    pub class_year: Option<String>,
+    pub affiliation: Option<Affiliation>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
+    pub preferred_name: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -29,14 +125,19 @@ pub struct RawUser {
 pub struct NewRawUser {
     pub first_name: String,
     pub last_name: String,
-    pub banner_id: u32,
+    pub banner_id: Option<u32>,
     pub email: String,
+    pub department: Option<String>,
+    pub class_year: Option<String>,
+    pub affiliation: Option<Affiliation>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
 }
 
 #[derive(Queryable, Debug)]
 pub struct JoinedUser {
     pub user: RawUser,
-    pub permission: Option<Permission>,
+    pub permission: Option<RawPermission>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,17 +145,30 @@ pub struct User {
     pub id: u64,
     pub first_name: String,
     pub last_name: String,
-    pub banner_id: u32,
+    pub banner_id: Option<u32>,
     pub email: String,
-    pub permissions: Vec<Permission>,
+    pub active: bool,
+    pub deactivated_at: Option<NaiveDateTime>,
+    pub department: Option<String>,
+    pub class_year: Option<String>,
+    pub affiliation: Option<Affiliation>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
+    pub preferred_name: Option<String>,
+    pub permissions: Vec<RawPermission>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewUser {
     pub first_name: String,
     pub last_name: String,
-    pub banner_id: u32,
+    pub banner_id: Option<u32>,
     pub email: String,
+    pub department: Option<String>,
+    pub class_year: Option<String>,
+    pub affiliation: Option<Affiliation>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
     pub permissions: Vec<u64>,
 }
 
@@ -65,69 +179,230 @@ pub struct PartialUser {
     pub last_name: Option<String>,
     pub banner_id: Option<u32>,
     pub email: Option<String>,
+    pub department: Option<String>,
+    pub class_year: Option<String>,
+    pub affiliation: Option<Affiliation>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
+    pub preferred_name: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Searchable)]
+#[search(table = "users")]
 pub struct SearchUser {
     pub first_name: Search<String>,
     pub last_name: Search<String>,
-    pub banner_id: Search<u32>,
+    pub banner_id: NullableSearch<u32>,
     pub email: Search<String>,
+    pub department: NullableSearch<String>,
+    pub class_year: NullableSearch<String>,
+    pub affiliation: NullableSearch<Affiliation>,
+    pub include_inactive: bool,
+    /// Overrides the `Accept` header when given
+    pub format: Option<UserFormat>,
+}
+
+/// The parts of a user that they can change themselves
+///
+/// Email and banner id identify the user, so only an administrator can change them.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartialCurrentUser {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub preferred_name: Option<String>,
+    pub phone: Option<String>,
+    pub emergency_contact: Option<String>,
+}
+
+impl From<PartialCurrentUser> for PartialUser {
+    fn from(current_user: PartialCurrentUser) -> PartialUser {
+        PartialUser {
+            first_name: current_user.first_name,
+            last_name: current_user.last_name,
+            banner_id: None,
+            email: None,
+            department: None,
+            class_year: None,
+            affiliation: None,
+            phone: current_user.phone,
+            emergency_contact: current_user.emergency_contact,
+            preferred_name: current_user.preferred_name,
+        }
+    }
+}
+
+/// How a list of users is sent back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserFormat {
+    Json,
+    Csv,
+}
+
+impl UserFormat {
+    /// Picks the format from the `Accept` header, defaulting to JSON
+    pub fn from_accept(accept: Option<&str>) -> UserFormat {
+        match accept {
+            Some(accept) if accept.contains("text/csv") => UserFormat::Csv,
+            _ => UserFormat::Json,
+        }
+    }
+}
+
+impl std::str::FromStr for UserFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<UserFormat, Error> {
+        match s {
+            "json" => Ok(UserFormat::Json),
+            "csv" => Ok(UserFormat::Csv),
+            _ => Err(Error::new(ErrorKind::Url)),
+        }
+    }
+}
+
+#[test]
+fn user_format_uses_accept_header() {
+    assert_eq!(UserFormat::from_accept(Some("text/csv")), UserFormat::Csv);
+    assert_eq!(UserFormat::from_accept(Some("application/json")), UserFormat::Json);
+    assert_eq!(UserFormat::from_accept(None), UserFormat::Json);
+}
+
+/// Fields that a list of users can be sorted by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UserSortField {
+    Id,
+    FirstName,
+    LastName,
+    BannerId,
+    Email,
+    Department,
+    ClassYear,
+}
+
+impl std::str::FromStr for UserSortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UserSortField, String> {
+        match s {
+            "id" => Ok(UserSortField::Id),
+            "first_name" => Ok(UserSortField::FirstName),
+            "last_name" => Ok(UserSortField::LastName),
+            "banner_id" => Ok(UserSortField::BannerId),
+            "email" => Ok(UserSortField::Email),
+            "department" => Ok(UserSortField::Department),
+            "class_year" => Ok(UserSortField::ClassYear),
+            _ => Err(format!("Cannot sort users by {}", s)),
+        }
+    }
 }
 
+/// A page of users
+///
+/// `total` counts every user that matched, and `next` links to the next page.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserList {
     pub users: Vec<User>,
+    pub total: u64,
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Created,
+    Updated,
+    Unchanged,
+    Failed,
+}
+
+/// A field that an import changed, or would change in a dry run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// What happened to a single row of an import
+///
+/// `row` counts the header as row 1, so it matches the row number in a spreadsheet.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportRow {
+    pub row: usize,
+    pub email: Option<String>,
+    pub user_id: Option<u64>,
+    pub action: ImportAction,
+    pub changes: Vec<ImportChange>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub rows: Vec<ImportRow>,
+}
+
+impl ImportReport {
+    pub fn from_rows(dry_run: bool, rows: Vec<ImportRow>) -> ImportReport {
+        let count = |action| rows.iter().filter(|r| r.action == action).count();
+
+        ImportReport {
+            dry_run: dry_run,
+            created: count(ImportAction::Created),
+            updated: count(ImportAction::Updated),
+            unchanged: count(ImportAction::Unchanged),
+            failed: count(ImportAction::Failed),
+            rows: rows,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum UserRequest {
-    SearchUsers(SearchUser),
+    SearchUsers(SearchUser, Page<UserSortField>, UserFormat),
     Current,
+    UpdateCurrent(PartialCurrentUser),
     GetUser(u64),
     CreateUser(NewUser),
     UpdateUser(u64, PartialUser),
     DeleteUser(u64),
+    ReactivateUser(u64),
+    ImportUsers(String, bool),
+    MergeUsers(u64, u64),
 }
 
 impl UserRequest {
     pub fn from_rouille(request: &rouille::Request) -> Result<UserRequest, Error> {
-        let url_queries = form_urlencoded::parse(request.raw_query_string().as_bytes());
-
         router!(request,
             (GET) (/) => {
 
-                let mut first_name_search = Search::NoSearch;
-                let mut last_name_search = Search::NoSearch;
-                let mut banner_id_search = Search::NoSearch;
-                let mut email_search = Search::NoSearch;
-
-                for (field, query) in url_queries {
-                    match field.as_ref() {
-                        "first_name" => first_name_search =
-                            Search::from_query(query.as_ref())?,
-                        "last_name" => last_name_search =
-                            Search::from_query(query.as_ref())?,
-                        "banner_id" => banner_id_search =
-                            Search::from_query(query.as_ref())?,
-                        "email" => email_search =
-                            Search::from_query(query.as_ref())?,
-                        _ => return Err(Error::new(ErrorKind::Url)),
-                    }
-                }
-
-                Ok(UserRequest::SearchUsers(SearchUser {
-                    first_name: first_name_search,
-                    last_name: last_name_search,
-                    banner_id: banner_id_search,
-                    email: email_search,
-                }))
+                let search = SearchUser::from_query(request.raw_query_string())?;
+                let format = search.format
+                    .unwrap_or_else(|| UserFormat::from_accept(request.header("Accept")));
+
+                let page = Page::from_query(request.raw_query_string())?;
+
+                Ok(UserRequest::SearchUsers(search, page, format))
             },
 
             (GET) (/current) => {
                 Ok(UserRequest::Current)
             },
 
+            (PUT) (/current) => {
+                let request_body = request.data()
+                    .ok_or(Error::new(ErrorKind::Body))?;
+                let update_current_user: PartialCurrentUser
+                    = serde_json::from_reader(request_body)?;
+
+                Ok(UserRequest::UpdateCurrent(update_current_user))
+            },
+
             (GET) (/{id: u64}) => {
                 Ok(UserRequest::GetUser(id))
             },
@@ -153,6 +428,34 @@ impl UserRequest {
                 Ok(UserRequest::DeleteUser(id))
             },
 
+            (POST) (/import) => {
+                let mut dry_run = false;
+
+                parse_queries(request.raw_query_string(), |field, query| match field {
+                    "dry_run" => {
+                        dry_run = query.parse::<bool>()?;
+                        Ok(())
+                    }
+                    _ => unknown_query(field, query),
+                })?;
+
+                let mut request_body = request.data()
+                    .ok_or(Error::new(ErrorKind::Body))?;
+                let mut csv_data = String::new();
+                request_body.read_to_string(&mut csv_data)
+                    .map_err(|e| Error::with_source(ErrorKind::Body, Box::new(e)))?;
+
+                Ok(UserRequest::ImportUsers(csv_data, dry_run))
+            },
+
+            (POST) (/{id: u64}/merge/{other: u64}) => {
+                Ok(UserRequest::MergeUsers(id, other))
+            },
+
+            (POST) (/{id: u64}/reactivate) => {
+                Ok(UserRequest::ReactivateUser(id))
+            },
+
             _ => {
                 warn!("Could not create a user request for the given rouille request");
                 Err(Error::new(ErrorKind::NotFound))
@@ -165,6 +468,8 @@ impl UserRequest {
 pub enum UserResponse {
     OneUser(User),
     ManyUsers(UserList),
+    ImportReport(ImportReport),
+    UsersCsv(Vec<u8>),
     NoResponse,
 }
 
@@ -173,6 +478,14 @@ impl UserResponse {
         match self {
             UserResponse::OneUser(user) => rouille::Response::json(&user),
             UserResponse::ManyUsers(users) => rouille::Response::json(&users),
+            UserResponse::ImportReport(report) => rouille::Response::json(&report),
+            UserResponse::UsersCsv(csv_data) => {
+                rouille::Response::from_data("text/csv; charset=utf-8", csv_data)
+                    .with_additional_header(
+                        "Content-Disposition",
+                        "attachment; filename=\"users.csv\"",
+                    )
+            }
             UserResponse::NoResponse => rouille::Response::empty_204(),
         }
     }
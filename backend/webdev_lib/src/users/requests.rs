@@ -1,66 +1,97 @@
 use crate::diesel::NullableExpressionMethods;
 use diesel;
+use diesel::mysql::Mysql;
 use diesel::mysql::MysqlConnection;
+use diesel::result::DatabaseErrorKind;
+use diesel::Connection;
 use diesel::ExpressionMethods;
 use diesel::QueryDsl;
 use diesel::RunQueryDsl;
-use diesel::TextExpressionMethods;
+
+use chrono::offset::Local;
+use chrono::NaiveDateTime;
 
 use log::trace;
-use log::warn;
 
 use crate::errors::Error;
 use crate::errors::ErrorKind;
 
-use crate::search::Search;
+use crate::search::{Page, Sort, SortOrder};
+
+use crate::audit::requests::{begin_change, finish_change};
 
 use crate::permissions::requests::check_to_run;
 
-use crate::permissions::models::NewUserPermission;
+use crate::permissions::models::{NewUserPermission, Permission, Principal, RawPermission};
+
+use crate::tests::test_sessions::models::RawTestSessionRegistration;
+
+use crate::users::export::export_users_csv;
+use crate::users::import::import_users;
 
 use crate::users::models::{
-    JoinedUser, NewRawUser, NewUser, PartialUser, RawUser, SearchUser, User, UserList, UserRequest,
-    UserResponse,
+    JoinedUser, NewRawUser, NewUser, PartialCurrentUser, PartialUser, RawUser, SearchUser, User,
+    UserFormat, UserList, UserRequest, UserResponse, UserSortField,
 };
 
+use crate::audit::schema::audit_log as audit_log_schema;
+use crate::chemicals::schema::chemical_inventory as chemical_inventory_schema;
 use crate::permissions::schema::permissions as permissions_schema;
 use crate::permissions::schema::user_permissions as user_permissions_schema;
+use crate::permissions::schema::user_roles as user_roles_schema;
+use crate::service_accounts::schema::service_accounts as service_accounts_schema;
+use crate::tests::test_sessions::schema::test_session_registrations as test_session_registrations_schema;
+use crate::tests::tests::schema::tests as tests_schema;
 use crate::users::schema::users as users_schema;
 
 pub fn handle_user(
     request: UserRequest,
-    requested_user: Option<u64>,
+    requested_user: &Principal,
     database_connection: &MysqlConnection,
 ) -> Result<UserResponse, Error> {
     match request {
-        UserRequest::SearchUsers(user) => {
-            match check_to_run(requested_user, "GetUsers", database_connection) {
-                Ok(()) => {
-                    search_users(user, database_connection).map(|u| UserResponse::ManyUsers(u))
-                }
+        UserRequest::SearchUsers(user, page, format) => {
+            match check_to_run(requested_user, Permission::GetUsers) {
+                Ok(()) => match format {
+                    UserFormat::Json => search_users(user, page, database_connection)
+                        .map(|u| UserResponse::ManyUsers(u)),
+                    UserFormat::Csv => {
+                        // Exports have every matching user, not just one page
+                        let users = search_users(user, page.unlimited(), database_connection)?;
+
+                        export_users_csv(users, database_connection)
+                            .map(|c| UserResponse::UsersCsv(c))
+                    }
+                },
                 Err(e) => Err(e),
             }
         }
 
-        UserRequest::Current => match requested_user {
+        UserRequest::Current => match requested_user.user_id {
             Some(id) => get_user(id, database_connection).map(|u| UserResponse::OneUser(u)),
             None => Ok(UserResponse::NoResponse),
         },
 
+        UserRequest::UpdateCurrent(user) => match requested_user.user_id {
+            Some(id) => update_user(id, PartialUser::from(user), database_connection)
+                .map(|_| UserResponse::NoResponse),
+            None => Err(Error::new(ErrorKind::PermissionDenied)),
+        },
+
         UserRequest::GetUser(id) => {
-            match check_to_run(requested_user, "GetUsers", database_connection) {
+            match check_to_run(requested_user, Permission::GetUsers) {
                 Ok(()) => get_user(id, database_connection).map(|u| UserResponse::OneUser(u)),
                 Err(e) => Err(e),
             }
         }
         UserRequest::CreateUser(user) => {
-            match check_to_run(requested_user, "CreateUsers", database_connection) {
+            match check_to_run(requested_user, Permission::CreateUsers) {
                 Ok(()) => create_user(user, database_connection).map(|u| UserResponse::OneUser(u)),
                 Err(e) => Err(e),
             }
         }
         UserRequest::UpdateUser(id, user) => {
-            match check_to_run(requested_user, "DeleteUsers", database_connection) {
+            match check_to_run(requested_user, Permission::UpdateUsers) {
                 Ok(()) => {
                     update_user(id, user, database_connection).map(|_| UserResponse::NoResponse)
                 }
@@ -68,8 +99,34 @@ pub fn handle_user(
             }
         }
         UserRequest::DeleteUser(id) => {
-            match check_to_run(requested_user, "GetUsers", database_connection) {
-                Ok(()) => delete_user(id, database_connection).map(|_| UserResponse::NoResponse),
+            match check_to_run(requested_user, Permission::DeleteUsers) {
+                Ok(()) => {
+                    deactivate_user(id, database_connection).map(|_| UserResponse::NoResponse)
+                }
+                Err(e) => Err(e),
+            }
+        }
+        UserRequest::ImportUsers(csv_data, dry_run) => {
+            match check_to_run(requested_user, Permission::CreateUsers) {
+                Ok(()) => {
+                    import_users(csv_data.as_bytes(), dry_run, requested_user, database_connection)
+                        .map(|r| UserResponse::ImportReport(r))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        UserRequest::MergeUsers(id, other) => {
+            match check_to_run(requested_user, Permission::MergeUsers) {
+                Ok(()) => merge_users(id, other, requested_user, database_connection)
+                    .map(|u| UserResponse::OneUser(u)),
+                Err(e) => Err(e),
+            }
+        }
+        UserRequest::ReactivateUser(id) => {
+            match check_to_run(requested_user, Permission::UpdateUsers) {
+                Ok(()) => {
+                    reactivate_user(id, database_connection).map(|_| UserResponse::NoResponse)
+                }
                 Err(e) => Err(e),
             }
         }
@@ -95,6 +152,14 @@ fn condense_join(joined: Vec<JoinedUser>) -> Vec<User> {
                 last_name: join.user.last_name,
                 banner_id: join.user.banner_id,
                 email: join.user.email,
+                active: join.user.active,
+                deactivated_at: join.user.deactivated_at,
+                department: join.user.department,
+                class_year: join.user.class_year,
+                affiliation: join.user.affiliation,
+                phone: join.user.phone,
+                emergency_contact: join.user.emergency_contact,
+                preferred_name: join.user.preferred_name,
                 permissions: permission,
             };
 
@@ -106,71 +171,107 @@ fn condense_join(joined: Vec<JoinedUser>) -> Vec<User> {
 
 pub(crate) fn search_users(
     user: SearchUser,
+    page: Page<UserSortField>,
     database_connection: &MysqlConnection,
 ) -> Result<UserList, Error> {
-    let mut users_query = users_schema::table
-        .left_join(user_permissions_schema::table.left_join(permissions_schema::table))
-        .select((
-            (
-                users_schema::id,
-                users_schema::first_name,
-                users_schema::last_name,
-                users_schema::banner_id,
-                users_schema::email,
-            ),
-            (permissions_schema::id, permissions_schema::permission_name).nullable(),
-        ))
-        .into_boxed();
-
-    match user.first_name {
-        Search::Partial(s) => {
-            users_query = users_query.filter(users_schema::first_name.like(format!("%{}%", s)))
-        }
-
-        Search::Exact(s) => users_query = users_query.filter(users_schema::first_name.eq(s)),
-
-        Search::NoSearch => {}
-    }
-
-    match user.last_name {
-        Search::Partial(s) => {
-            users_query = users_query.filter(users_schema::last_name.like(format!("%{}%", s)))
-        }
+    let total = filter_users(&user)
+        .count()
+        .get_result::<i64>(database_connection)? as u64;
+
+    let sort = page.sort.unwrap_or(Sort {
+        field: UserSortField::Id,
+        order: SortOrder::Ascending,
+    });
+
+    let users_query = filter_users(&user);
+
+    let users_query = crate::sort_query!(users_query, sort, {
+        UserSortField::Id => users_schema::id,
+        UserSortField::FirstName => users_schema::first_name,
+        UserSortField::LastName => users_schema::last_name,
+        UserSortField::BannerId => users_schema::banner_id,
+        UserSortField::Email => users_schema::email,
+        UserSortField::Department => users_schema::department,
+        UserSortField::ClassYear => users_schema::class_year,
+    });
+
+    let raw_users = users_query
+        .then_order_by(users_schema::id.asc())
+        .limit(page.limit as i64)
+        .offset(page.offset as i64)
+        .load::<RawUser>(database_connection)?;
 
-        Search::Exact(s) => users_query = users_query.filter(users_schema::last_name.eq(s)),
+    let users = load_permissions(raw_users, database_connection)?;
 
-        Search::NoSearch => {}
-    }
+    let user_list = UserList {
+        users: users,
+        total: total,
+        next: page.next(total),
+    };
 
-    match user.banner_id {
-        Search::Partial(s) => {
-            warn!("Trying to partial search by banner id. This is not currently supported, so performing exact search instead");
-            trace!("Partial search required the field to be a text field, but banner id is currently an integet");;
-            users_query = users_query.filter(users_schema::banner_id.eq(s))
-        }
+    Ok(user_list)
+}
 
-        Search::Exact(s) => users_query = users_query.filter(users_schema::banner_id.eq(s)),
+/// Loads the permissions of a page of users, keeping the users in order
+///
+/// Joining the permissions onto the users would return each user once for every
+/// permission they have, which breaks limits and offsets, so they are loaded on
+/// their own.
+fn load_permissions(
+    raw_users: Vec<RawUser>,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<User>, Error> {
+    let user_ids: Vec<u64> = raw_users.iter().map(|u| u.id).collect();
 
-        Search::NoSearch => {}
-    }
+    let user_permissions = user_permissions_schema::table
+        .inner_join(permissions_schema::table)
+        .select((
+            user_permissions_schema::user_id,
+            (permissions_schema::id, permissions_schema::permission_name),
+        ))
+        .filter(user_permissions_schema::user_id.eq_any(user_ids))
+        .load::<(u64, RawPermission)>(database_connection)?;
+
+    let users = raw_users
+        .into_iter()
+        .map(|user| {
+            let permissions = user_permissions
+                .iter()
+                .filter(|(user_id, _)| *user_id == user.id)
+                .map(|(_, permission)| permission.clone())
+                .collect();
+
+            User {
+                id: user.id,
+                first_name: user.first_name,
+                last_name: user.last_name,
+                banner_id: user.banner_id,
+                email: user.email,
+                active: user.active,
+                deactivated_at: user.deactivated_at,
+                department: user.department,
+                class_year: user.class_year,
+                affiliation: user.affiliation,
+                phone: user.phone,
+                emergency_contact: user.emergency_contact,
+                preferred_name: user.preferred_name,
+                permissions: permissions,
+            }
+        })
+        .collect();
 
-    match user.email {
-        Search::Partial(s) => {
-            users_query = users_query.filter(users_schema::email.like(format!("%{}%", s)))
-        }
+    Ok(users)
+}
 
-        Search::Exact(s) => users_query = users_query.filter(users_schema::email.eq(s)),
+/// Builds a query for the users that match the search
+fn filter_users<'a>(user: &'a SearchUser) -> users_schema::BoxedQuery<'a, Mysql> {
+    let mut users_query = user.filter(users_schema::table.into_boxed());
 
-        Search::NoSearch => {}
+    if !user.include_inactive {
+        users_query = users_query.filter(users_schema::active.eq(true));
     }
 
-    let joined_users = users_query.load::<JoinedUser>(database_connection)?;
-
-    let users = condense_join(joined_users);
-
-    let user_list = UserList { users };
-
-    Ok(user_list)
+    users_query
 }
 
 pub(crate) fn get_user(id: u64, database_connection: &MysqlConnection) -> Result<User, Error> {
@@ -183,6 +284,14 @@ pub(crate) fn get_user(id: u64, database_connection: &MysqlConnection) -> Result
                 users_schema::last_name,
                 users_schema::banner_id,
                 users_schema::email,
+                users_schema::active,
+                users_schema::deactivated_at,
+                users_schema::department,
+                users_schema::class_year,
+                users_schema::affiliation,
+                users_schema::phone,
+                users_schema::emergency_contact,
+                users_schema::preferred_name,
             ),
             (permissions_schema::id, permissions_schema::permission_name).nullable(),
         ))
@@ -197,6 +306,19 @@ pub(crate) fn get_user(id: u64, database_connection: &MysqlConnection) -> Result
     }
 }
 
+/// Gets every user with one of the ids, in order of their ids
+pub(crate) fn get_users(
+    ids: Vec<u64>,
+    database_connection: &MysqlConnection,
+) -> Result<Vec<User>, Error> {
+    let raw_users = users_schema::table
+        .filter(users_schema::id.eq_any(ids))
+        .order(users_schema::id.asc())
+        .load::<RawUser>(database_connection)?;
+
+    load_permissions(raw_users, database_connection)
+}
+
 pub(crate) fn create_user(
     user: NewUser,
     database_connection: &MysqlConnection,
@@ -206,11 +328,17 @@ pub(crate) fn create_user(
         last_name: user.last_name,
         banner_id: user.banner_id,
         email: user.email,
+        department: user.department,
+        class_year: user.class_year,
+        affiliation: user.affiliation,
+        phone: user.phone,
+        emergency_contact: user.emergency_contact,
     };
 
     diesel::insert_into(users_schema::table)
         .values(new_raw_user)
-        .execute(database_connection)?;
+        .execute(database_connection)
+        .map_err(duplicate_user_error)?;
 
     let mut inserted_users = users_schema::table
         .filter(diesel::dsl::sql("id = LAST_INSERT_ID()"))
@@ -223,6 +351,8 @@ pub(crate) fn create_user(
             .map(|permission_id| NewUserPermission {
                 permission_id: permission_id,
                 user_id: inserted_user.id,
+                granted_at: None,
+                expires_at: None,
             })
             .collect();
 
@@ -246,13 +376,283 @@ pub(crate) fn update_user(
     diesel::update(users_schema::table)
         .filter(users_schema::id.eq(id))
         .set(&user)
+        .execute(database_connection)
+        .map_err(duplicate_user_error)?;
+    Ok(())
+}
+
+/// Combines two accounts for the same person into `id`, then removes `other`
+///
+/// Everything that belonged to `other` is moved over, and any profile fields `id` is missing are
+/// filled in from `other`. Grants and roles that both users have are only kept once. The audit
+/// log gets an update of `id` and its roles, and the deletion of `other`.
+pub(crate) fn merge_users(
+    id: u64,
+    other: u64,
+    requesting_user: &Principal,
+    database_connection: &MysqlConnection,
+) -> Result<User, Error> {
+    if id == other {
+        return Err(Error::new(ErrorKind::Url));
+    }
+
+    let request_path = format!("/users/{}/merge/{}", id, other);
+    let pending = vec![
+        begin_change(
+            requesting_user,
+            request_path.clone(),
+            "users",
+            Some(id),
+            "update",
+            database_connection,
+        ),
+        begin_change(
+            requesting_user,
+            request_path.clone(),
+            "user_role",
+            Some(id),
+            "update",
+            database_connection,
+        ),
+        begin_change(
+            requesting_user,
+            request_path,
+            "users",
+            Some(other),
+            "delete",
+            database_connection,
+        ),
+    ];
+
+    let mut deleted_registrations = Vec::new();
+
+    let merged = database_connection.transaction::<_, Error, _>(|| {
+        let user = get_user(id, database_connection)?;
+        let other_user = get_user(other, database_connection)?;
+
+        let load_registrations = |taker_id: u64| {
+            test_session_registrations_schema::table
+                .filter(test_session_registrations_schema::taker_id.eq(taker_id))
+                .load::<RawTestSessionRegistration>(database_connection)
+        };
+
+        // A taker can only be registered once for each test session
+        let duplicate_ids =
+            duplicate_registrations(&load_registrations(id)?, &load_registrations(other)?);
+
+        for &registration_id in &duplicate_ids {
+            deleted_registrations.push(begin_change(
+                requesting_user,
+                format!("/users/{}/merge/{}", id, other),
+                "test_session_registrations",
+                Some(registration_id),
+                "delete",
+                database_connection,
+            ));
+        }
+
+        diesel::delete(
+            test_session_registrations_schema::table
+                .filter(test_session_registrations_schema::id.eq_any(duplicate_ids)),
+        )
+        .execute(database_connection)?;
+
+        diesel::update(
+            test_session_registrations_schema::table
+                .filter(test_session_registrations_schema::taker_id.eq(other)),
+        )
+        .set(test_session_registrations_schema::taker_id.eq(id))
+        .execute(database_connection)?;
+
+        diesel::update(
+            chemical_inventory_schema::table
+                .filter(chemical_inventory_schema::purchaser_id.eq(other)),
+        )
+        .set(chemical_inventory_schema::purchaser_id.eq(id))
+        .execute(database_connection)?;
+
+        diesel::update(
+            chemical_inventory_schema::table
+                .filter(chemical_inventory_schema::custodian_id.eq(other)),
+        )
+        .set(chemical_inventory_schema::custodian_id.eq(id))
+        .execute(database_connection)?;
+
+        diesel::update(tests_schema::table.filter(tests_schema::creator_id.eq(other)))
+            .set(tests_schema::creator_id.eq(id))
+            .execute(database_connection)?;
+
+        let permission_ids: Vec<u64> = user.permissions.iter().map(|p| p.id).collect();
+
+        diesel::delete(
+            user_permissions_schema::table
+                .filter(user_permissions_schema::user_id.eq(other))
+                .filter(user_permissions_schema::permission_id.eq_any(permission_ids)),
+        )
+        .execute(database_connection)?;
+
+        diesel::update(
+            user_permissions_schema::table.filter(user_permissions_schema::user_id.eq(other)),
+        )
+        .set(user_permissions_schema::user_id.eq(id))
+        .execute(database_connection)?;
+
+        let role_ids = user_roles_schema::table
+            .select(user_roles_schema::role_id)
+            .filter(user_roles_schema::user_id.eq(id))
+            .load::<u64>(database_connection)?;
+
+        diesel::delete(
+            user_roles_schema::table
+                .filter(user_roles_schema::user_id.eq(other))
+                .filter(user_roles_schema::role_id.eq_any(role_ids)),
+        )
+        .execute(database_connection)?;
+
+        diesel::update(user_roles_schema::table.filter(user_roles_schema::user_id.eq(other)))
+            .set(user_roles_schema::user_id.eq(id))
+            .execute(database_connection)?;
+
+        diesel::update(
+            service_accounts_schema::table.filter(service_accounts_schema::user_id.eq(other)),
+        )
+        .set(service_accounts_schema::user_id.eq(id))
+        .execute(database_connection)?;
+
+        diesel::update(audit_log_schema::table.filter(audit_log_schema::actor_id.eq(other)))
+            .set(audit_log_schema::actor_id.eq(id))
+            .execute(database_connection)?;
+
+        diesel::update(
+            audit_log_schema::table.filter(audit_log_schema::impersonator_id.eq(other)),
+        )
+        .set(audit_log_schema::impersonator_id.eq(id))
         .execute(database_connection)?;
+
+        // Sessions are removed along with the user, so they will need to log in again
+        diesel::delete(users_schema::table.filter(users_schema::id.eq(other)))
+            .execute(database_connection)?;
+
+        // `other` no longer holds its banner id, so it can be moved over without a conflict
+        diesel::update(users_schema::table.filter(users_schema::id.eq(id)))
+            .set((
+                users_schema::banner_id.eq(user.banner_id.or(other_user.banner_id)),
+                users_schema::department.eq(user.department.or(other_user.department)),
+                users_schema::class_year.eq(user.class_year.or(other_user.class_year)),
+                users_schema::affiliation.eq(user.affiliation.or(other_user.affiliation)),
+                users_schema::phone.eq(user.phone.or(other_user.phone)),
+                users_schema::emergency_contact
+                    .eq(user.emergency_contact.or(other_user.emergency_contact)),
+                users_schema::preferred_name
+                    .eq(user.preferred_name.or(other_user.preferred_name)),
+            ))
+            .execute(database_connection)?;
+
+        trace!("Merged user {} into user {}", other, id);
+
+        get_user(id, database_connection)
+    });
+
+    for pending in pending.into_iter().chain(deleted_registrations) {
+        finish_change(pending, merged.is_ok(), None, database_connection);
+    }
+
+    merged
+}
+
+/// Finds the registrations to delete when `merged`'s registrations move to `kept`'s taker
+///
+/// When both were registered for the same test session, the one with a submission is
+/// kept, and otherwise the kept user's own registration is.
+fn duplicate_registrations(
+    kept: &[RawTestSessionRegistration],
+    merged: &[RawTestSessionRegistration],
+) -> Vec<u64> {
+    merged
+        .iter()
+        .filter_map(|registration| {
+            let kept_registration = kept
+                .iter()
+                .find(|kept| kept.test_session_id == registration.test_session_id)?;
+
+            if registration.submitted_test.is_some() && kept_registration.submitted_test.is_none() {
+                Some(kept_registration.id)
+            } else {
+                Some(registration.id)
+            }
+        })
+        .collect()
+}
+
+/// Reports a clash with the unique email and banner id as a conflict, not a database failure
+fn duplicate_user_error(e: diesel::result::Error) -> Error {
+    match e {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            Error::with_source(ErrorKind::DuplicateUser, Box::new(e))
+        }
+        e => Error::from(e),
+    }
+}
+
+/// Deactivates a user instead of deleting them
+///
+/// Deleting a user would cascade to their test registrations and the chemicals they
+/// purchased, so they are kept for history and can no longer log in instead.
+pub(crate) fn deactivate_user(id: u64, database_connection: &MysqlConnection) -> Result<(), Error> {
+    get_user(id, database_connection)?;
+
+    diesel::update(users_schema::table)
+        .filter(users_schema::id.eq(id))
+        .filter(users_schema::active.eq(true))
+        .set((
+            users_schema::active.eq(false),
+            users_schema::deactivated_at.eq(Some(Local::now().naive_local())),
+        ))
+        .execute(database_connection)?;
+
     Ok(())
 }
 
-pub(crate) fn delete_user(id: u64, database_connection: &MysqlConnection) -> Result<(), Error> {
-    diesel::delete(users_schema::table.filter(users_schema::id.eq(id)))
+pub(crate) fn reactivate_user(id: u64, database_connection: &MysqlConnection) -> Result<(), Error> {
+    get_user(id, database_connection)?;
+
+    diesel::update(users_schema::table)
+        .filter(users_schema::id.eq(id))
+        .set((
+            users_schema::active.eq(true),
+            users_schema::deactivated_at.eq(None::<NaiveDateTime>),
+        ))
         .execute(database_connection)?;
 
     Ok(())
 }
+
+#[test]
+fn duplicate_registrations_keep_the_submitted_one() {
+    let now = Local::now().naive_local();
+    let registration = |id: u64, test_session_id: u64, submitted_test: Option<NaiveDateTime>| {
+        RawTestSessionRegistration {
+            id: id,
+            test_session_id: test_session_id,
+            taker_id: 0,
+            registered: now,
+            opened_test: None,
+            submitted_test: submitted_test,
+            score: None,
+        }
+    };
+
+    let kept = vec![
+        registration(1, 10, None),
+        registration(2, 20, Some(now)),
+        registration(3, 30, None),
+    ];
+    let merged = vec![
+        registration(4, 10, Some(now)),
+        registration(5, 20, None),
+        registration(6, 40, Some(now)),
+    ];
+
+    assert_eq!(duplicate_registrations(&kept, &merged), vec![1, 5]);
+    assert_eq!(duplicate_registrations(&kept, &[]), Vec::<u64>::new());
+}
@@ -3,7 +3,15 @@ table! {
         id -> Unsigned<Bigint>,
         first_name -> Varchar,
         last_name -> Varchar,
-        banner_id -> Unsigned<Integer>,
+        banner_id -> Nullable<Unsigned<Integer>>,
         email -> Varchar,
+        active -> Bool,
+        deactivated_at -> Nullable<Timestamp>,
+        department -> Nullable<Varchar>,
+        class_year -> Nullable<Varchar>,
+        affiliation -> Nullable<Varchar>,
+        phone -> Nullable<Varchar>,
+        emergency_contact -> Nullable<Varchar>,
+        preferred_name -> Nullable<Varchar>,
     }
 }
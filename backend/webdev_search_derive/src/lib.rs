@@ -0,0 +1,322 @@
+//! Derives the url parsing and diesel filters for `webdev_lib`'s search structs
+//!
+//! ```ignore
+//! #[derive(Debug, Searchable)]
+//! #[search(table = "super::schema::chemical")]
+//! pub struct SearchChemical {
+//!     pub name: Search<String>,
+//!     pub purpose: Search<String>,
+//! }
+//! ```
+//!
+//! Each `Search<T>` or `NullableSearch<T>` field is searched for by its name in the url, and
+//! filters the column with the same name in `table`. `String` fields are searched partially with
+//! `LIKE`, and other fields exactly. `bool` and `Option<T>` fields are other url queries that are
+//! parsed, but not filtered by, so the handler can decide what they mean.
+//!
+//! `from_query` reads the whole query string, skipping the queries in
+//! `webdev_lib::search::RESERVED_QUERIES` and failing on any it does not know.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+use proc_macro2::TokenStream as TokenStream2;
+
+use quote::quote;
+
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, Ident, Lit, Meta, NestedMeta,
+    Path, PathArguments, Type,
+};
+
+#[proc_macro_derive(Searchable, attributes(search))]
+pub fn derive_searchable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match searchable(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// What a field of a search struct holds
+enum FieldKind {
+    Search { nullable: bool, text: bool },
+    Flag,
+    Query { text: bool },
+}
+
+struct SearchField {
+    ident: Ident,
+    kind: FieldKind,
+}
+
+fn searchable(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let table = table_path(input)?;
+
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    input,
+                    "Searchable needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "Searchable can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut fields = Vec::new();
+
+    for field in named {
+        let ident = field.ident.clone().expect("named fields have idents");
+        let kind = field_kind(&field.ty).ok_or_else(|| {
+            syn::Error::new_spanned(
+                &field.ty,
+                "Searchable fields must be a Search, a NullableSearch, a bool or an Option",
+            )
+        })?;
+
+        fields.push(SearchField { ident, kind });
+    }
+
+    let defaults = fields.iter().map(|field| {
+        let ident = &field.ident;
+
+        match field.kind {
+            FieldKind::Search { nullable, .. } => {
+                let search = search_type(nullable);
+                quote!(#ident: #search::NoSearch)
+            }
+            FieldKind::Flag => quote!(#ident: false),
+            FieldKind::Query { .. } => quote!(#ident: None),
+        }
+    });
+
+    let parsers = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let query_name = ident.to_string();
+
+        match field.kind {
+            FieldKind::Search { nullable, .. } => {
+                let search = search_type(nullable);
+                quote!(#query_name => self.#ident = #search::from_query(query)?)
+            }
+            FieldKind::Flag => quote!(#query_name => self.#ident = query.parse::<bool>()?),
+            FieldKind::Query { text: true } => {
+                quote!(#query_name => self.#ident = Some(query.to_owned()))
+            }
+            FieldKind::Query { text: false } => {
+                quote!(#query_name => self.#ident = Some(query.parse()?))
+            }
+        }
+    });
+
+    let filters = fields.iter().filter_map(|field| {
+        let ident = &field.ident;
+
+        let column_filter = match field.kind {
+            FieldKind::Search { text: true, .. } => quote!(crate::column_filter!(#table::#ident)),
+            FieldKind::Search { text: false, .. } => {
+                quote!(crate::column_filter!(#table::#ident, exact))
+            }
+            FieldKind::Flag | FieldKind::Query { .. } => return None,
+        };
+
+        Some(quote! {
+            if let Some(filter) = self.#ident.to_filter(#column_filter) {
+                query = ::diesel::query_dsl::methods::FilterDsl::filter(query, filter);
+            }
+        })
+    });
+
+    Ok(quote! {
+        impl Default for #name {
+            fn default() -> #name {
+                #name {
+                    #(#defaults,)*
+                }
+            }
+        }
+
+        impl #name {
+            /// Reads every search from a raw query string
+            ///
+            /// The reserved queries are left for `Page` and `Shape`, and any other query
+            /// fails with `ErrorKind::Url`.
+            pub fn from_query(query_string: &str) -> Result<#name, crate::errors::Error> {
+                let mut search = #name::default();
+
+                crate::search::parse_queries(query_string, |field, query| {
+                    search.parse_query(field, query)
+                })?;
+
+                Ok(search)
+            }
+
+            /// Sets the search named `field` from its url query
+            ///
+            /// Fails with `ErrorKind::Url` when there is no search with that name.
+            pub fn parse_query(
+                &mut self,
+                field: &str,
+                query: &str,
+            ) -> Result<(), crate::errors::Error> {
+                match field {
+                    #(#parsers,)*
+                    _ => {
+                        return Err(crate::errors::Error::new(crate::errors::ErrorKind::Url))
+                    }
+                }
+
+                Ok(())
+            }
+
+            /// Filters a query on the table by each of the searches
+            pub fn filter<Q>(&self, mut query: Q) -> Q
+            where
+                Q: ::diesel::query_dsl::methods::FilterDsl<
+                    crate::search::BoxedFilter<#table::table>,
+                    Output = Q,
+                >,
+            {
+                #(#filters)*
+
+                query
+            }
+        }
+    })
+}
+
+/// Finds the `#[search(table = "...")]` attribute
+fn table_path(input: &DeriveInput) -> syn::Result<Path> {
+    for attr in &input.attrs {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => continue,
+        };
+
+        if list.ident != "search" {
+            continue;
+        }
+
+        let nested = list.nested.iter().next();
+
+        match nested {
+            Some(NestedMeta::Meta(Meta::NameValue(name_value))) if name_value.ident == "table" => {
+                if let Lit::Str(table) = &name_value.lit {
+                    return syn::parse_str(&table.value());
+                }
+
+                return Err(syn::Error::new_spanned(
+                    &name_value.lit,
+                    "table must be a string",
+                ));
+            }
+            Some(other) => {
+                return Err(syn::Error::new_spanned(other, "Unknown search attribute"));
+            }
+            None => {}
+        }
+    }
+
+    Err(syn::Error::new_spanned(
+        input,
+        "Searchable needs a #[search(table = \"...\")] attribute",
+    ))
+}
+
+fn search_type(nullable: bool) -> TokenStream2 {
+    if nullable {
+        quote!(crate::search::NullableSearch)
+    } else {
+        quote!(crate::search::Search)
+    }
+}
+
+fn field_kind(ty: &Type) -> Option<FieldKind> {
+    let segment = last_segment(ty)?;
+
+    let nullable = match segment.ident.to_string().as_ref() {
+        "Search" => Some(false),
+        "NullableSearch" => Some(true),
+        "Option" => None,
+        "bool" => return Some(FieldKind::Flag),
+        _ => return None,
+    };
+
+    let term = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => match arguments.args.iter().next()? {
+            GenericArgument::Type(term) => term,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let text = last_segment(term)
+        .map(|segment| segment.ident == "String")
+        .unwrap_or(false);
+
+    match nullable {
+        Some(nullable) => Some(FieldKind::Search { nullable, text }),
+        None => Some(FieldKind::Query { text }),
+    }
+}
+
+fn last_segment(ty: &Type) -> Option<&syn::PathSegment> {
+    match ty {
+        Type::Path(path) => path.path.segments.iter().last(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn error_for(input: &str) -> String {
+    let input = syn::parse_str(input).expect("the test input is valid rust");
+
+    match searchable(&input) {
+        Ok(_) => panic!("the derive should have failed"),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn search_table_is_required() {
+    let e = error_for("struct SearchThing { name: Search<String> }");
+    assert_eq!(e, "Searchable needs a #[search(table = \"...\")] attribute");
+}
+
+#[test]
+fn unsupported_field_types_fail() {
+    let e =
+        error_for("#[search(table = \"schema::thing\")] struct SearchThing { name: Vec<String> }");
+    assert_eq!(
+        e,
+        "Searchable fields must be a Search, a NullableSearch, a bool or an Option"
+    );
+}
+
+#[test]
+fn unknown_search_attributes_fail() {
+    let e = error_for("#[search(tabel = \"schema::thing\")] struct SearchThing {}");
+    assert_eq!(e, "Unknown search attribute");
+}
+
+#[test]
+fn supported_fields_derive() {
+    let input = syn::parse_str(
+        "#[search(table = \"schema::thing\")] struct SearchThing { \
+         name: Search<String>, size: NullableSearch<u64>, all: bool, q: Option<String> }",
+    )
+    .unwrap();
+
+    assert!(searchable(&input).is_ok());
+}